@@ -0,0 +1,98 @@
+use std::{convert::Infallible, thread};
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use queues_demo::cache::{Cache, DataGetter};
+use tokio::runtime::Runtime;
+
+const KEYS: usize = 1_000;
+const USAGE_ROUNDS: usize = 10_000;
+
+// Answers right away, so the benchmarks measure the cache and not the fetch
+#[derive(Debug, Default)]
+struct LenGetter;
+
+impl DataGetter for LenGetter {
+    type Key = String;
+    type BorrowedKey = str;
+    type Value = usize;
+    type Error = Infallible;
+    async fn get(&self, key: &str) -> Result<usize, Infallible> {
+        Ok(key.len())
+    }
+}
+
+type BenchCache = Cache<LenGetter, 30_000, 600_000>;
+
+fn keys() -> Vec<String> {
+    (0..KEYS).map(|i| format!("exploit{i}")).collect()
+}
+
+fn get(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let keys = keys();
+    let mut group = c.benchmark_group("cache_get");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    group.bench_function("hit", |b| {
+        let cache = BenchCache::default();
+        rt.block_on(async {
+            for key in &keys {
+                cache.get(key).await.unwrap();
+            }
+        });
+        b.to_async(&rt).iter(|| async {
+            for key in &keys {
+                cache.get(key).await.unwrap();
+            }
+        })
+    });
+    group.bench_function("miss_and_fetch", |b| {
+        b.to_async(&rt).iter_batched(
+            BenchCache::default,
+            |cache| async {
+                for key in &keys {
+                    cache.get(key).await.unwrap();
+                }
+                cache
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// Every thread adds and removes usages across the same keys, with one key they all contend on a
+// single entry
+fn usage_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache_usage");
+    for threads in [1, 4, 16] {
+        group.throughput(Throughput::Elements((threads * USAGE_ROUNDS) as u64));
+        for key_count in [1, 64] {
+            let cache = BenchCache::default();
+            let keys = &keys()[..key_count];
+            for key in keys {
+                cache.set(key.clone(), 0).unwrap();
+            }
+            let id = BenchmarkId::new(format!("{key_count}_keys"), threads);
+            group.bench_with_input(id, &threads, |b, &threads| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        for t in 0..threads {
+                            let cache = &cache;
+                            scope.spawn(move || {
+                                for i in 0..USAGE_ROUNDS {
+                                    let key = &keys[(t + i) % key_count];
+                                    cache.add_usage(key).unwrap();
+                                    cache.remove_usage(key).unwrap();
+                                }
+                            });
+                        }
+                    })
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, get, usage_contention);
+criterion_main!(benches);
@@ -0,0 +1,319 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use dlv_list::{Index, VecList};
+use futures::future::join_all;
+use queues_demo::{
+    queue::{GenericTaskQueue, GenericTaskQueueWithBackup},
+    utils::Timed,
+};
+use tokio::{runtime::Runtime, select, sync::Notify, time::sleep};
+
+const TASKS: usize = 10_000;
+const PRODUCERS: usize = 4;
+
+type Queue = GenericTaskQueue<u64, 30_000>;
+
+// The queue as it was before the lock-free rework: one mutex shared by everyone for pending
+#[derive(Debug, Default)]
+struct MutexQueue {
+    notify_incoming: Notify,
+    pending: Mutex<VecDeque<u64>>,
+    processing: Mutex<VecList<Timed<u64>>>,
+}
+
+impl MutexQueue {
+    fn push(&self, item: u64) {
+        self.pending.lock().expect("Mutex poisoned").push_back(item);
+        self.notify_incoming.notify_one();
+    }
+
+    async fn pop_with_timeout(&self, timeout: Duration) -> Option<Index<Timed<u64>>> {
+        let mut timeout = Box::pin(sleep(timeout));
+        loop {
+            if let Some(item) = self.pending.lock().expect("Mutex poisoned").pop_front() {
+                let id = self
+                    .processing
+                    .lock()
+                    .expect("Mutex poisoned")
+                    .push_back(Timed::new(item));
+                return Some(id);
+            }
+            select! {
+                _ = self.notify_incoming.notified() => {},
+                _ = &mut timeout => {
+                    return None;
+                },
+            }
+        }
+    }
+
+    fn submit_completed(&self, id: Index<Timed<u64>>) {
+        self.processing.lock().expect("Mutex poisoned").remove(id);
+    }
+}
+
+async fn run_mutex(workers: usize) {
+    let queue = Arc::new(MutexQueue::default());
+    let producers = (0..PRODUCERS).map(|p| {
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            for i in 0..TASKS / PRODUCERS {
+                queue.push((p * TASKS + i) as u64);
+            }
+        })
+    });
+    let consumers = (0..workers).map(|_| {
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            for _ in 0..TASKS / workers {
+                let id = queue
+                    .pop_with_timeout(Duration::from_secs(10))
+                    .await
+                    .expect("Queue drained early");
+                queue.submit_completed(id);
+            }
+        })
+    });
+    join_all(producers.chain(consumers)).await;
+}
+
+async fn run_queue(workers: usize) {
+    let queue = Arc::new(Queue::default());
+    let producers = (0..PRODUCERS).map(|p| {
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            for i in 0..TASKS / PRODUCERS {
+                queue.push((p * TASKS + i) as u64);
+            }
+        })
+    });
+    let consumers = (0..workers).map(|_| {
+        let queue = queue.clone();
+        tokio::spawn(async move {
+            for _ in 0..TASKS / workers {
+                let (_, id) = queue
+                    .pop_with_timeout(Duration::from_secs(10))
+                    .await
+                    .expect("Queue drained early");
+                queue.submit_completed(&id).unwrap();
+            }
+        })
+    });
+    join_all(producers.chain(consumers)).await;
+}
+
+fn contention(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("pending_contention");
+    for workers in [1, 10, 50] {
+        group.bench_with_input(
+            BenchmarkId::new("mutex_vecdeque", workers),
+            &workers,
+            |b, &w| b.to_async(&rt).iter(|| run_mutex(w)),
+        );
+        group.bench_with_input(BenchmarkId::new("segqueue", workers), &workers, |b, &w| {
+            b.to_async(&rt).iter(|| run_queue(w))
+        });
+    }
+    group.finish();
+}
+
+const LARGE_TASK_BYTES: usize = 1 << 20;
+
+fn large_tasks(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let payload = "x".repeat(LARGE_TASK_BYTES);
+    let mut group = c.benchmark_group("large_task_pop");
+    group.bench_function("shared_handle", |b| {
+        let queue = GenericTaskQueue::<String, 30_000>::default();
+        b.to_async(&rt).iter_batched(
+            || queue.push(payload.clone()),
+            |_| async {
+                let (task, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+                queue.submit_completed(&id).unwrap();
+                task
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    // What pop used to do: keep one copy in processing and hand out a clone
+    group.bench_function("clone_on_pop", |b| {
+        let queue = GenericTaskQueue::<String, 30_000>::default();
+        b.to_async(&rt).iter_batched(
+            || queue.push(payload.clone()),
+            |_| async {
+                let (task, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+                queue.submit_completed(&id).unwrap();
+                String::clone(&task)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+const BULK_TASKS: u64 = 10_000;
+
+fn bulk_push(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("bulk_push");
+    group.sample_size(10);
+    group.bench_function("push_loop", |b| {
+        b.to_async(&rt).iter_batched(
+            || {
+                let db = sled::Config::new().temporary(true).open().unwrap();
+                GenericTaskQueueWithBackup::<u64, 30_000>::new(db)
+            },
+            |queue| async move {
+                for i in 0..BULK_TASKS {
+                    queue.push(i).await;
+                }
+                queue
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.bench_function("push_many", |b| {
+        b.to_async(&rt).iter_batched(
+            || {
+                let db = sled::Config::new().temporary(true).open().unwrap();
+                GenericTaskQueueWithBackup::<u64, 30_000>::new(db)
+            },
+            |queue| async move {
+                queue.push_many((0..BULK_TASKS).collect()).await;
+                queue
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn push(c: &mut Criterion) {
+    let mut group = c.benchmark_group("push");
+    group.throughput(Throughput::Elements(TASKS as u64));
+    group.bench_function("single_producer", |b| {
+        b.iter_batched(
+            Queue::default,
+            |queue| {
+                for i in 0..TASKS as u64 {
+                    queue.push(i);
+                }
+                queue
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("concurrent_producers", |b| {
+        b.iter_batched(
+            Queue::default,
+            |queue| {
+                thread::scope(|scope| {
+                    for p in 0..PRODUCERS {
+                        let queue = &queue;
+                        scope.spawn(move || {
+                            for i in 0..TASKS / PRODUCERS {
+                                queue.push((p * TASKS + i) as u64);
+                            }
+                        });
+                    }
+                });
+                queue
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// Time from pushing one task per parked waiter until every waiter got its task
+fn wake_waiters(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("wake_waiters");
+    for waiters in [1, 10, 100] {
+        group.bench_with_input(BenchmarkId::from_parameter(waiters), &waiters, |b, &w| {
+            b.to_async(&rt).iter_custom(|iters| async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let queue = Arc::new(Queue::default());
+                    let handles: Vec<_> = (0..w)
+                        .map(|_| {
+                            let queue = queue.clone();
+                            tokio::spawn(async move {
+                                queue.pop_with_timeout(Duration::from_secs(10)).await
+                            })
+                        })
+                        .collect();
+                    // NOTE: outside the measurement, only lets the waiters park
+                    sleep(Duration::from_millis(1)).await;
+                    let start = Instant::now();
+                    for i in 0..w as u64 {
+                        queue.push(i);
+                    }
+                    for handle in join_all(handles).await {
+                        handle.unwrap().expect("Waiter timed out");
+                    }
+                    total += start.elapsed();
+                }
+                total
+            })
+        });
+    }
+    group.finish();
+}
+
+const PROCESSING: u64 = 100_000;
+
+// `Queue` with `PROCESSING` tasks popped with `execution_timeout`
+fn processing_queue(rt: &Runtime, execution_timeout: Duration) -> Queue {
+    let queue = Queue::default();
+    queue.push_many((0..PROCESSING).collect());
+    rt.block_on(async {
+        for _ in 0..PROCESSING {
+            queue
+                .pop_with_execution_timeout(Duration::ZERO, execution_timeout)
+                .await
+                .unwrap();
+        }
+    });
+    queue
+}
+
+// A zero execution timeout expires tasks as soon as they are popped, so no sleeping is needed
+fn process_timeouts(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("process_timeouts");
+    group.sample_size(10);
+    group.bench_function("none_expired", |b| {
+        let queue = processing_queue(&rt, Duration::from_secs(3600));
+        b.iter(|| queue.process_timeouts())
+    });
+    group.bench_function("all_expired", |b| {
+        b.iter_batched(
+            || processing_queue(&rt, Duration::ZERO),
+            |queue| {
+                queue.process_timeouts();
+                queue
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    contention,
+    large_tasks,
+    bulk_push,
+    push,
+    wake_waiters,
+    process_timeouts
+);
+criterion_main!(benches);
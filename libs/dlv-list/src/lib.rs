@@ -2213,7 +2213,7 @@ impl<T> DoubleEndedIterator for IterMut<'_, T> {
       None
     } else {
       self.tail.map(|index| {
-        let entry = unsafe { &mut (*self.entries)[index.get()] }.occupied_mut();
+        let entry = unsafe { &mut (&mut (*self.entries))[index.get()] }.occupied_mut();
         self.tail = entry.previous;
         self.remaining -= 1;
         &mut entry.value
@@ -2234,7 +2234,7 @@ impl<'a, T> Iterator for IterMut<'a, T> {
       None
     } else {
       self.head.map(|index| {
-        let entry = unsafe { &mut (*self.entries)[index.get()] }.occupied_mut();
+        let entry = unsafe { &mut (&mut (*self.entries))[index.get()] }.occupied_mut();
         self.head = entry.next;
         self.remaining -= 1;
         &mut entry.value
@@ -2701,6 +2701,28 @@ mod test {
     assert_eq!(iter.next_back(), None);
   }
 
+  #[test]
+  fn test_iter_mut_references_from_both_ends_stay_valid() {
+    let mut list = VecList::new();
+    for value in 0..6 {
+      list.push_back(value);
+    }
+
+    let mut iter = list.iter_mut();
+    let mut yielded = Vec::new();
+    while let (Some(front), back) = (iter.next(), iter.next_back()) {
+      yielded.push(front);
+      yielded.extend(back);
+    }
+    for value in yielded {
+      *value *= 10;
+    }
+    assert_eq!(
+      list.into_iter().collect::<Vec<_>>(),
+      [0, 10, 20, 30, 40, 50]
+    );
+  }
+
   #[test]
   fn test_iter_mut_empty() {
     let mut list: VecList<i32> = VecList::new();
@@ -1,128 +1,819 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
 
 use axum::{
     Json, Router,
-    extract::State,
+    extract::{DefaultBodyLimit, FromRequestParts, Path, Query, Request, State},
+    http::{StatusCode, request::Parts},
+    middleware::{Next, from_fn_with_state},
+    response::{IntoResponse, Response},
     routing::{get, post},
 };
+use futures::future::join_all;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use tokio::time::sleep;
+use tokio::{
+    sync::oneshot,
+    task::yield_now,
+    time::{sleep, timeout},
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 use crate::{
     AppState, CacheState,
-    queue::{GenericTaskQueueWithBackup, TaskId},
+    cache::{CacheError, CacheStats},
+    codec::{Accept, Codec},
+    queue::{
+        GenericTaskQueueWithBackup, SubmitError, TaskEvent, TaskEventKind, TaskId, TimeoutAction,
+    },
+    results::{ResultStore, SeenKeys},
+    sink::CompletionSink,
 };
 
+// Request body limits of the queue routes, axum rejects larger bodies with 413
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BodyLimits {
+    pub default: usize,
+    // NOTE: only `/add_tasks`, which takes many tasks at once
+    pub bulk: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        Self {
+            default: 1024 * 1024,
+            bulk: 16 * 1024 * 1024,
+        }
+    }
+}
+
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub fn routes() -> Router<AppState> {
+    routes_with_body_limits(BodyLimits::default())
+}
+
+pub fn routes_with_body_limits(limits: BodyLimits) -> Router<AppState> {
+    routes_with_limits(limits, DEFAULT_REQUEST_TIMEOUT)
+}
+
+// Requests running longer than `request_timeout` are answered with 503, except the long polls of
+// `/get_task` and `/add_task_sync`, which wait on purpose and have timeouts of their own
+pub fn routes_with_limits(limits: BodyLimits, request_timeout: Duration) -> Router<AppState> {
+    let long_polls = Router::new()
+        .route("/add_task_sync", post(queue_add_task_sync))
+        .route("/get_task", get(queue_get_task));
     Router::new()
         .route("/add_task", post(queue_add_task))
-        .route("/get_task", get(queue_get_task))
+        .route(
+            "/add_tasks",
+            post(queue_add_tasks).layer(DefaultBodyLimit::max(limits.bulk)),
+        )
         .route("/submit_completed", post(queue_submit_completed))
+        .route("/heartbeat", post(queue_heartbeat))
+        .route("/fail", post(queue_fail))
+        .route("/requeue", post(queue_requeue))
+        .route("/processing_time", get(queue_processing_time))
+        .route("/events", get(queue_events))
+        .route("/flush", post(queue_flush))
+        .route("/result/{submission_id}", get(queue_get_result))
+        .layer(from_fn_with_state(request_timeout, cut_at_timeout))
+        .merge(long_polls)
+        // NOTE: the route layer of `/add_tasks` is applied after this one and overrides it
+        .layer(DefaultBodyLimit::max(limits.default))
+}
+
+pub fn cache_routes() -> Router<AppState> {
+    cache_routes_with_timeout(DEFAULT_REQUEST_TIMEOUT)
+}
+
+// See `routes_with_limits`, every cache route is cut at `request_timeout`
+pub fn cache_routes_with_timeout(request_timeout: Duration) -> Router<AppState> {
+    Router::new()
+        .route("/warm", post(cache_warm))
+        .route("/stats", get(cache_stats))
+        .route("/keys", get(cache_keys))
+        .route("/invalidate/{key}", post(cache_invalidate))
+        .layer(from_fn_with_state(request_timeout, cut_at_timeout))
+}
+
+// Drops the handler once `limit` passes, so a hung upstream doesn't hold the connection. A
+// completion dropped halfway is requeued by `submit_completed_with_reclaim`
+async fn cut_at_timeout(State(limit): State<Duration>, request: Request, next: Next) -> Response {
+    match timeout(limit, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!(?limit, "Request timed out");
+            ApiError::Unavailable("Request timed out".to_owned()).into_response()
+        }
+    }
+}
+
+pub type MainQueue = GenericTaskQueueWithBackup<Submission, 30_000>;
+
+// Error answer of every handler, its status with an `ApiErrorBody`
+#[derive(Debug)]
+pub enum ApiError {
+    // NOTE: an invalid task or id, the same request won't ever succeed
+    BadRequest(String),
+    NotFound(String),
+    Conflict(String),
+    // NOTE: the task left processing before the request, through a timeout, requeue or failure
+    Gone(String),
+    QueueFull,
+    Internal(String),
+    // NOTE: exploit storage, the collector or a callback failed
+    Upstream(String),
+    Timeout(String),
+    // NOTE: the request itself ran out of time, see `routes_with_limits`
+    Unavailable(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiErrorBody {
+    pub error: String,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Gone(_) => StatusCode::GONE,
+            Self::QueueFull => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let error = match self {
+            Self::QueueFull => "Queue is full".to_owned(),
+            Self::BadRequest(error)
+            | Self::NotFound(error)
+            | Self::Conflict(error)
+            | Self::Gone(error)
+            | Self::Internal(error)
+            | Self::Upstream(error)
+            | Self::Timeout(error)
+            | Self::Unavailable(error) => error,
+        };
+        (status, Json(ApiErrorBody { error })).into_response()
+    }
+}
+
+impl From<SubmitError> for ApiError {
+    fn from(err: SubmitError) -> Self {
+        match err {
+            SubmitError::NotFound => Self::NotFound("Task is not being processed".to_owned()),
+            SubmitError::AlreadyCompleted => Self::Conflict("Task is already completed".to_owned()),
+            SubmitError::TimedOut => Self::Gone("Task timed out and was reclaimed".to_owned()),
+            SubmitError::Requeued => Self::Gone("Task was requeued".to_owned()),
+            SubmitError::Failed => Self::Gone("Task was reported failed".to_owned()),
+            SubmitError::Stale => {
+                Self::Conflict("Task was handed out too long ago to complete".to_owned())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Submission {
+    pub id: String,
+    pub exploit_key: String,
+    pub priority: u8,
+    pub callback_url: Option<String>,
+    pub request_id: String,
+}
+
+// Layout of `Submission` stored under queue format version 1, before request ids
+#[derive(Debug, Deserialize)]
+struct SubmissionV1 {
+    id: String,
+    exploit_key: String,
+    priority: u8,
+    callback_url: Option<String>,
+}
+
+// Migration for `MainQueue`, tasks stored before request ids get a fresh one
+pub fn migrate_submission(version: u8, payload: &[u8]) -> Option<Submission> {
+    if version != 1 {
+        return None;
+    }
+    let (old, _): (SubmissionV1, _) =
+        bincode::serde::decode_from_slice(payload, bincode::config::standard()).ok()?;
+    Some(Submission {
+        id: old.id,
+        exploit_key: old.exploit_key,
+        priority: old.priority,
+        callback_url: old.callback_url,
+        request_id: RequestId::generate().0,
+    })
 }
 
-pub type MainQueue = GenericTaskQueueWithBackup<String, 30_000>;
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Id of the request that added a task, taken from `X-Request-Id` or generated when absent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4().simple().to_string())
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Infallible> {
+        let request_id = parts
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| !value.is_empty());
+        Ok(request_id.map_or_else(Self::generate, |value| Self(value.to_owned())))
+    }
+}
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueueTask {
     #[serde_as(as = "serde_with::hex::Hex")]
-    pub id: TaskId<String>,
+    pub id: TaskId<Submission>,
     pub submission_id: String,
-    pub exploit: Arc<String>,
+    pub exploit_key: String,
+    pub priority: u8,
+    // NOTE: `None` when requested with `include_exploit=false`
+    #[serde(default)]
+    pub exploit: Option<Arc<String>>,
+    pub request_id: String,
+    // NOTE: 1 on the first delivery, higher ones mean the task was redelivered
+    #[serde(default = "first_attempt")]
+    pub attempt: u32,
+}
+
+fn first_attempt() -> u32 {
+    1
 }
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueueCompletedTask {
     #[serde_as(as = "serde_with::hex::Hex")]
-    pub id: TaskId<String>,
+    pub id: TaskId<Submission>,
     pub info: String,
+    // NOTE: echoed for logs only, the stored request id is the one forwarded
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueFailedTask {
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub id: TaskId<Submission>,
+    // NOTE: logged only
+    pub reason: String,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueTaskRef {
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub id: TaskId<Submission>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct QueueTaskCompletion {
     pub submission_id: String,
     pub info: String,
+    // NOTE: not kept in the result store, so absent from /result answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    // NOTE: where `HttpSink` posts the completion instead of the collector, never serialized
+    #[serde(skip)]
+    pub callback_url: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct QueueState {
     pub queue: MainQueue,
-    pub client: reqwest::Client,
+    pub sync_timeout: Duration,
+    pub max_submission_id_len: usize,
+    // NOTE: completions nobody waits for go here, see `sink::HttpSink` for the collector
+    pub completions: Box<dyn CompletionSink>,
+    // NOTE: what happens to a task whose completion the collector or callback did not accept
+    pub collector_failure: TimeoutAction,
+    // NOTE: keyed by submission id, completions with a waiter are not sent to the collector
+    pub completion_waiters: Mutex<HashMap<String, oneshot::Sender<QueueTaskCompletion>>>,
+    pub results: ResultStore,
+    pub idempotency_keys: SeenKeys,
 }
 
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct QueueAddTask {
     pub submission_id: String,
+    #[serde(default)]
+    pub exploit_key: Option<String>,
+    #[serde(default)]
+    pub priority: u8,
+    #[serde(default)]
+    pub callback_url: Option<String>,
+    // NOTE: only add_task honors it, a retry with a key seen recently is answered without a push
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+impl QueueAddTask {
+    pub fn into_submission(self, request_id: String) -> Submission {
+        Submission {
+            exploit_key: self
+                .exploit_key
+                .unwrap_or_else(|| self.submission_id.clone()),
+            id: self.submission_id,
+            priority: self.priority,
+            callback_url: self.callback_url,
+            request_id,
+        }
+    }
+
+    pub fn validate(&self, max_submission_id_len: usize) -> Result<(), String> {
+        if self.submission_id.is_empty() {
+            return Err("submission_id must not be empty".to_owned());
+        }
+        if self.submission_id.len() > max_submission_id_len {
+            return Err(format!(
+                "submission_id must be at most {max_submission_id_len} bytes long, got {}",
+                self.submission_id.len()
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn validate_task(state: &QueueState, task: &QueueAddTask) -> Result<(), ApiError> {
+    task.validate(state.max_submission_id_len).map_err(|err| {
+        warn!(%err, "Rejected task");
+        ApiError::BadRequest(err)
+    })
 }
 
-pub async fn queue_add_task(State(state): State<Arc<QueueState>>, task: Json<QueueAddTask>) {
-    println!("Adding task {:?}", task);
-    let QueueAddTask { submission_id } = task.0;
-    state.queue.push(submission_id);
+pub async fn queue_add_task(
+    State(state): State<Arc<QueueState>>,
+    RequestId(request_id): RequestId,
+    task: Json<QueueAddTask>,
+) -> Result<(), ApiError> {
+    validate_task(&state, &task)?;
+    if let Some(key) = &task.idempotency_key
+        && !state.idempotency_keys.insert(key)
+    {
+        info!(submission_id = %task.submission_id, %request_id, %key, "Skipping retried task");
+        return Ok(());
+    }
+    info!(submission_id = %task.submission_id, %request_id, ?task, "Adding task");
+    state.queue.push(task.0.into_submission(request_id)).await;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueAddTasksResult {
+    pub accepted: usize,
+}
+
+pub async fn queue_add_tasks(
+    State(state): State<Arc<QueueState>>,
+    RequestId(request_id): RequestId,
+    Json(tasks): Json<Vec<QueueAddTask>>,
+) -> Result<Json<QueueAddTasksResult>, ApiError> {
+    // NOTE: all or nothing, like the push itself
+    for task in &tasks {
+        validate_task(&state, task)?;
+    }
+    info!(count = tasks.len(), %request_id, "Adding tasks");
+    let accepted = tasks.len();
+    let submissions = tasks
+        .into_iter()
+        .map(|task| task.into_submission(request_id.clone()))
+        .collect();
+    state.queue.push_many(submissions).await;
+    Ok(Json(QueueAddTasksResult { accepted }))
+}
+
+pub async fn queue_add_task_sync(
+    State(state): State<Arc<QueueState>>,
+    RequestId(request_id): RequestId,
+    Json(task): Json<QueueAddTask>,
+) -> Result<Json<QueueTaskCompletion>, ApiError> {
+    validate_task(&state, &task)?;
+    info!(submission_id = %task.submission_id, %request_id, ?task, "Adding task synchronously");
+    let submission_id = task.submission_id.clone();
+    let (tx, mut rx) = oneshot::channel();
+    {
+        let mut waiters = state.completion_waiters.lock().expect("Mutex poisoned");
+        if waiters
+            .get(&submission_id)
+            .is_some_and(|waiter| !waiter.is_closed())
+        {
+            return Err(ApiError::Conflict(
+                "submission_id is already awaited".to_owned(),
+            ));
+        }
+        waiters.insert(submission_id.clone(), tx);
+    }
+    state.queue.push(task.into_submission(request_id)).await;
+    if let Ok(Ok(completion)) = timeout(state.sync_timeout, &mut rx).await {
+        return Ok(Json(completion));
+    }
+    // NOTE: the waiter is fired under the lock, so it's either still registered or already sent
+    let mut waiters = state.completion_waiters.lock().expect("Mutex poisoned");
+    if waiters.remove(&submission_id).is_some() {
+        warn!(%submission_id, "Timed out waiting for task completion");
+        return Err(sync_timed_out());
+    }
+    rx.try_recv().map(Json).map_err(|_| sync_timed_out())
+}
+
+fn sync_timed_out() -> ApiError {
+    ApiError::Timeout("Task was not completed in time, it stays queued".to_owned())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueGetTaskParams {
+    // Workers fetching exploits themselves turn this off to skip the cache and upstream fetch
+    #[serde(default = "default_include_exploit")]
+    pub include_exploit: bool,
+}
+
+fn default_include_exploit() -> bool {
+    true
+}
+
+impl Default for QueueGetTaskParams {
+    fn default() -> Self {
+        Self {
+            include_exploit: default_include_exploit(),
+        }
+    }
 }
 
 pub async fn queue_get_task(
     State(state): State<Arc<QueueState>>,
     State(cache): State<Arc<CacheState>>,
-) -> Json<Option<QueueTask>> {
-    let Some((addr, id)) = state.queue.pop_with_timeout(Duration::from_secs(10)).await else {
-        return Json(None);
+    Query(params): Query<QueueGetTaskParams>,
+    Accept(format): Accept,
+) -> Result<Response, ApiError> {
+    let Some((submission, id, attempt)) =
+        state.queue.pop_with_attempt(Duration::from_secs(10)).await
+    else {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    };
+    let exploit = if params.include_exploit {
+        // NOTE: the task is left in processing and gets requeued once it times out
+        let exploit = cache
+            .exploits
+            .get(&submission.exploit_key)
+            .await
+            .map_err(|err| {
+                error!(exploit_key = %submission.exploit_key, ?err, "Failed to fetch exploit");
+                match err {
+                    CacheError::Fetch(err) => {
+                        ApiError::Upstream(format!("Failed to fetch exploit: {err:?}"))
+                    }
+                    err => ApiError::Internal(format!("Exploit cache failed: {err:?}")),
+                }
+            })?;
+        Some(exploit.body)
+    } else {
+        None
     };
     let task = QueueTask {
         id,
-        exploit: cache.exploits.get(&addr).await,
-        submission_id: addr,
+        exploit,
+        submission_id: submission.id.clone(),
+        exploit_key: submission.exploit_key.clone(),
+        priority: submission.priority,
+        request_id: submission.request_id.clone(),
+        attempt,
     };
-    Json(Some(task))
+    Ok(Codec(format, task).into_response())
 }
 
 pub async fn queue_submit_completed(
     State(state): State<Arc<QueueState>>,
-    Json(task): Json<QueueCompletedTask>,
-) {
+    Codec(_, task): Codec<QueueCompletedTask>,
+) -> Result<(), ApiError> {
     state
         .queue
-        .submit_completed_with_inspect(&task.id, async |entry| match entry {
-            Some(submission_id) => {
-                println!("Task {} completed: {}", submission_id, task.info);
+        .submit_completed_with_reclaim(&task.id, async |entry| match entry {
+            Ok(submission) => {
+                info!(
+                    task_id = %task.id.display_short(),
+                    submission_id = %submission.id,
+                    request_id = %submission.request_id,
+                    echoed_request_id = ?task.request_id,
+                    info = %task.info,
+                    "Task completed"
+                );
+                // NOTE: only once delivered, a refused completion requeues the task
+                let record = || {
+                    state
+                        .results
+                        .insert(submission.id.clone(), task.info.clone());
+                };
                 let req = QueueTaskCompletion {
-                    submission_id,
+                    submission_id: submission.id.clone(),
                     info: task.info.clone(),
+                    request_id: Some(submission.request_id.clone()),
+                    callback_url: submission.callback_url.clone(),
+                };
+                let unclaimed = {
+                    let mut waiters = state.completion_waiters.lock().expect("Mutex poisoned");
+                    match waiters.remove(&submission.id) {
+                        Some(waiter) => waiter.send(req).err(),
+                        None => Some(req),
+                    }
                 };
-                state
-                    .client
-                    .post("http://localhost:3002/submit")
-                    .json(&req)
-                    .send()
-                    .await
-                    .unwrap()
-                    .error_for_status()
-                    .unwrap();
+                let Some(req) = unclaimed else {
+                    record();
+                    return (Ok(()), None);
+                };
+                match state.completions.send(req).await {
+                    Ok(()) => {
+                        record();
+                        (Ok(()), None)
+                    }
+                    Err(err) => {
+                        warn!(
+                            submission_id = %submission.id,
+                            callback_url = ?submission.callback_url,
+                            %err,
+                            action = ?state.collector_failure,
+                            "Completion was not accepted downstream"
+                        );
+                        let err = ApiError::Upstream(format!("Completion was not accepted: {err}"));
+                        (Err(err), Some(state.collector_failure))
+                    }
+                }
             }
-            None => {
-                println!(
-                    "Task not found: {}, id: {}",
-                    task.info,
-                    hex::encode(task.id.to_bytes())
+            Err(err) => {
+                warn!(
+                    task_id = %task.id.display_short(),
+                    ?err,
+                    info = %task.info,
+                    "Task completion rejected"
                 );
+                (Err(err.into()), None)
             }
         })
-        .await;
+        .await
+}
+
+// The task is requeued right away instead of after its timeout, or dead lettered once it is out
+// of attempts
+pub async fn queue_fail(
+    State(state): State<Arc<QueueState>>,
+    Codec(_, task): Codec<QueueFailedTask>,
+) -> Result<(), ApiError> {
+    let task_id = task.id.display_short();
+    match state.queue.fail(&task.id, TimeoutAction::Requeue) {
+        Ok(action) => {
+            warn!(%task_id, reason = %task.reason, ?action, "Task failed by worker");
+            Ok(())
+        }
+        Err(err) => {
+            warn!(%task_id, ?err, reason = %task.reason, "Failure report rejected");
+            Err(err.into())
+        }
+    }
+}
+
+pub async fn queue_requeue(
+    State(state): State<Arc<QueueState>>,
+    Json(task): Json<QueueTaskRef>,
+) -> Result<(), ApiError> {
+    let task_id = task.id.display_short();
+    match state.queue.requeue_processing(&task.id) {
+        Ok(()) => {
+            info!(%task_id, "Task requeued manually");
+            Ok(())
+        }
+        // NOTE: 404 for every miss, finished tasks are not being processed either
+        Err(err) => {
+            warn!(%task_id, ?err, "Requeue rejected");
+            Err(ApiError::NotFound("Task is not being processed".to_owned()))
+        }
+    }
+}
+
+pub async fn queue_heartbeat(
+    State(state): State<Arc<QueueState>>,
+    Json(heartbeat): Json<QueueTaskRef>,
+) -> Result<(), ApiError> {
+    state.queue.heartbeat(&heartbeat.id).map_err(|err| {
+        warn!(
+            task_id = %heartbeat.id.display_short(),
+            ?err,
+            "Heartbeat rejected"
+        );
+        err.into()
+    })
+}
+
+// Zeroes until a task is completed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct QueueProcessingTime {
+    pub count: u64,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    pub avg_ms: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueFlushResult {
+    pub flushed_bytes: usize,
+}
+
+// Durability checkpoint for operators, tasks accepted before the call are on disk once it returns
+pub async fn queue_flush(State(state): State<Arc<QueueState>>) -> Json<QueueFlushResult> {
+    let flushed_bytes = state.queue.flush().await;
+    info!(flushed_bytes, "Flushed queue database on request");
+    Json(QueueFlushResult { flushed_bytes })
+}
+
+pub async fn queue_processing_time(
+    State(state): State<Arc<QueueState>>,
+) -> Json<QueueProcessingTime> {
+    let stats = state.queue.processing_time_stats();
+    Json(stats.map_or_else(QueueProcessingTime::default, |stats| {
+        QueueProcessingTime {
+            count: stats.count,
+            min_ms: stats.min.as_secs_f64() * 1000.0,
+            max_ms: stats.max.as_secs_f64() * 1000.0,
+            avg_ms: stats.avg.as_secs_f64() * 1000.0,
+        }
+    }))
 }
 
-pub async fn queue_collect_timeouts(state: Arc<QueueState>) {
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueEventKind {
+    Pushed,
+    Popped,
+    Completed,
+    TimedOut,
+    Requeued,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueEvent {
+    pub kind: QueueEventKind,
+    // NOTE: hex encoded processing id, absent for pending tasks
+    pub task_id: Option<String>,
+    pub submission_id: String,
+    pub at_unix_ms: u64,
+}
+
+impl From<TaskEvent<Submission>> for QueueEvent {
+    fn from(event: TaskEvent<Submission>) -> Self {
+        let kind = match event.kind {
+            TaskEventKind::Pushed => QueueEventKind::Pushed,
+            TaskEventKind::Popped => QueueEventKind::Popped,
+            TaskEventKind::Completed => QueueEventKind::Completed,
+            TaskEventKind::TimedOut => QueueEventKind::TimedOut,
+            TaskEventKind::Requeued => QueueEventKind::Requeued,
+            TaskEventKind::Failed => QueueEventKind::Failed,
+        };
+        let since_epoch = event.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Self {
+            kind,
+            task_id: event.id.map(|id| hex::encode(id.to_bytes())),
+            submission_id: event.task.id.clone(),
+            at_unix_ms: since_epoch.as_millis() as u64,
+        }
+    }
+}
+
+// Recent task lifecycle events, oldest first
+pub async fn queue_events(State(state): State<Arc<QueueState>>) -> Json<Vec<QueueEvent>> {
+    let events = state.queue.events();
+    Json(events.into_iter().map(Into::into).collect())
+}
+
+pub async fn queue_get_result(
+    State(state): State<Arc<QueueState>>,
+    Path(submission_id): Path<String>,
+) -> Result<Json<QueueTaskCompletion>, ApiError> {
+    let info = state.results.get(&submission_id).ok_or_else(|| {
+        ApiError::NotFound("Task is not completed yet or its result expired".to_owned())
+    })?;
+    Ok(Json(QueueTaskCompletion {
+        submission_id,
+        info,
+        request_id: None,
+        callback_url: None,
+    }))
+}
+
+pub async fn queue_flush_periodically(state: Arc<QueueState>, interval: Duration) -> ! {
+    loop {
+        sleep(interval).await;
+        state.queue.flush().await;
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheWarmResult {
+    pub fetched: usize,
+    pub present: usize,
+    pub failed: usize,
+}
+
+pub async fn cache_warm(
+    State(cache): State<Arc<CacheState>>,
+    Json(keys): Json<Vec<String>>,
+) -> Json<CacheWarmResult> {
+    info!(count = keys.len(), "Warming cache");
+    let lookups = keys.iter().map(|key| cache.exploits.get_with_meta(key));
+    let mut result = CacheWarmResult {
+        fetched: 0,
+        present: 0,
+        failed: 0,
+    };
+    for (key, lookup) in keys.iter().zip(join_all(lookups).await) {
+        match lookup {
+            Ok((_, meta)) if meta.hit => result.present += 1,
+            Ok(_) => result.fetched += 1,
+            Err(err) => {
+                warn!(exploit_key = %key, ?err, "Failed to warm exploit");
+                result.failed += 1;
+            }
+        }
+    }
+    Json(result)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheStatsResult {
+    pub hits: u64,
+    pub misses: u64,
+    pub len: usize,
+}
+
+pub async fn cache_stats(State(cache): State<Arc<CacheState>>) -> Json<CacheStatsResult> {
+    let CacheStats { hits, misses, len } = cache.exploits.stats();
+    Json(CacheStatsResult { hits, misses, len })
+}
+
+pub async fn cache_keys(State(cache): State<Arc<CacheState>>) -> Json<Vec<String>> {
+    Json(cache.exploits.keys())
+}
+
+pub async fn cache_invalidate(
+    State(cache): State<Arc<CacheState>>,
+    Path(key): Path<String>,
+) -> Result<(), ApiError> {
+    match cache.exploits.invalidate(&key) {
+        Ok(usages) => {
+            info!(exploit_key = %key, usages, "Exploit invalidated");
+            Ok(())
+        }
+        Err(_) => Err(ApiError::NotFound("Exploit is not cached".to_owned())),
+    }
+}
+
+pub async fn queue_collect_timeouts(state: Arc<QueueState>, batch: usize) {
+    let inspect = |id: TaskId<Submission>, task: &Submission| {
+        warn!(
+            task_id = %id.display_short(),
+            submission_id = %task.id,
+            "Task timed out"
+        );
+        TimeoutAction::Requeue
+    };
     loop {
-        sleep(Duration::from_secs(1)).await;
-        state.queue.process_timeouts_with_inspect(|id, task| {
-            println!(
-                "Task timeout: {}, id: {}",
-                &task,
-                hex::encode(id.to_bytes())
-            );
-        });
-        println!("Tasks left: {} pending, {} processing", state.queue.len_pending(), state.queue.len_processing());
+        state.queue.wait_next_timeout().await;
+        // NOTE: release the processing lock between batches
+        while state.queue.process_timeouts_batch_with_inspect(batch, inspect) {
+            yield_now().await;
+        }
+        debug!(
+            pending = state.queue.len_pending(),
+            processing = state.queue.len_processing(),
+            "Tasks left"
+        );
     }
 }
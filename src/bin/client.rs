@@ -2,9 +2,13 @@ use std::time::Duration;
 
 use anyhow::Result;
 use clap::Parser;
-use queues_demo::api::QueueAddTask;
-use rand::random_range;
+use queues_demo::{
+    api::QueueAddTask,
+    utils::{HttpTimeouts, build_client},
+};
+use rand::{Rng, SeedableRng, rngs::StdRng};
 use tokio::time::sleep;
+use tracing::info;
 
 #[derive(Debug, Parser)]
 struct Cli {
@@ -12,24 +16,55 @@ struct Cli {
     interval: f64,
     #[arg(long, short, default_value_t = 1000)]
     max_id: u64,
+    #[arg(long, short, default_value = "http://localhost:3000")]
+    server_url: String,
+    /// Stop after this many submissions instead of submitting forever
+    #[arg(long, short)]
+    count: Option<u64>,
+    /// Seed of the submission id generator, makes the generated load reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Print the submissions as JSON lines instead of sending them, without waiting in between
+    #[arg(long)]
+    dry_run: bool,
+    #[command(flatten)]
+    http_timeouts: HttpTimeouts,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    queues_demo::utils::init_tracing();
     let cli = Cli::parse();
-    let client = reqwest::Client::new();
-    loop {
-        let s = sleep(Duration::from_secs_f64(cli.interval));
+    let client = build_client(&cli.http_timeouts)?;
+    let mut rng = match cli.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_os_rng(),
+    };
+    let mut submitted = 0;
+    while cli.count.is_none_or(|count| submitted < count) {
+        submitted += 1;
         let req = QueueAddTask {
-            submission_id: format!("task{:x}", random_range(0..cli.max_id)),
+            submission_id: format!("task{:x}", rng.random_range(0..cli.max_id)),
+            exploit_key: None,
+            priority: 0,
+            callback_url: None,
+            idempotency_key: None,
         };
-        println!("Submiting {}", req.submission_id);
+        if cli.dry_run {
+            println!("{}", serde_json::to_string(&req)?);
+            continue;
+        }
+        let s = sleep(Duration::from_secs_f64(cli.interval));
+        info!(submission_id = %req.submission_id, "Submitting");
         client
-            .post("http://localhost:3000/queue/add_task")
+            .post(format!("{}/queue/add_task", cli.server_url))
             .json(&req)
             .send()
             .await?
             .error_for_status()?;
-        s.await;
+        if cli.count.is_none_or(|count| submitted < count) {
+            s.await;
+        }
     }
+    Ok(())
 }
@@ -2,21 +2,25 @@ use anyhow::Result;
 use axum::{Json, Router, routing::post};
 use queues_demo::api::QueueTaskCompletion;
 use tokio::net::TcpListener;
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    queues_demo::utils::init_tracing();
     let app = Router::new().route(
         "/submit",
         post(async |Json(task): Json<QueueTaskCompletion>| {
-            println!(
-                "Task {} completed with info: {}",
-                task.submission_id, task.info
+            info!(
+                submission_id = %task.submission_id,
+                request_id = ?task.request_id,
+                info = %task.info,
+                "Task completed"
             );
         }),
     );
     let listener = TcpListener::bind("[::]:3002").await?;
     let local_addr = listener.local_addr()?;
-    println!("listening on {}", local_addr);
+    info!(%local_addr, "Listening");
     axum::serve(listener, app).await?;
     Ok(())
 }
@@ -1,19 +1,21 @@
 use anyhow::Result;
 use axum::{Router, extract::Path, routing::get};
 use tokio::net::TcpListener;
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    queues_demo::utils::init_tracing();
     let app = Router::new().route(
         "/get_exploit/{id}",
         get(async |Path(id): Path<String>| {
-            println!("Got request for {id}");
+            info!(%id, "Got request for exploit");
             format!("/* exploit for {id} */")
         }),
     );
     let listener = TcpListener::bind("[::]:3001").await?;
     let local_addr = listener.local_addr()?;
-    println!("listening on {}", local_addr);
+    info!(%local_addr, "Listening");
     axum::serve(listener, app).await?;
     Ok(())
 }
@@ -1,54 +1,144 @@
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{Result, ensure};
+use clap::Parser;
 use futures::future::try_join_all;
-use queues_demo::api::{QueueCompletedTask, QueueTask};
+use queues_demo::{
+    api::QueueCompletedTask,
+    utils::{HttpTimeouts, build_client, or_shutdown, shutdown_on_ctrl_c},
+    worker::{Backoff, ProcessedExploits, poll_task, process_exploit},
+};
 use rand::random;
-use tokio::time::sleep;
+use reqwest::StatusCode;
+use tokio::{
+    sync::{Semaphore, watch},
+    time::sleep,
+};
+use tracing::{info, warn};
+
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Number of concurrent workers polling the queue
+    #[arg(long, short, default_value_t = 10)]
+    concurrency: u32,
+    /// Number of workers allowed to work on a task at the same time
+    #[arg(long, short, default_value_t = 10)]
+    permits: usize,
+    #[arg(long, short, default_value = "http://localhost:3000")]
+    server_url: String,
+    /// Client-side timeout for a single get_task long poll, overrides the request timeout
+    #[arg(long, default_value_t = 15_000)]
+    poll_timeout_ms: u64,
+    /// Upper bound of the simulated work, the actual duration is uniformly random below it
+    #[arg(long, default_value_t = 10.0)]
+    max_work_secs: f64,
+    /// Probability of abandoning a task without submitting it, to exercise server timeouts
+    #[arg(long, default_value_t = 0.0)]
+    fail_rate: f64,
+    /// Number of processed exploits kept by submission id and shared by all workers, 0 disables
+    #[arg(long, default_value_t = 1_024)]
+    local_cache_entries: usize,
+    /// First delay before polling again after the server could not be reached
+    #[arg(long, default_value_t = 100)]
+    backoff_base_ms: u64,
+    /// Longest delay between polls while the server can't be reached
+    #[arg(long, default_value_t = 10_000)]
+    backoff_max_ms: u64,
+    #[command(flatten)]
+    http_timeouts: HttpTimeouts,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let workers = (0..10).map(work);
+    queues_demo::utils::init_tracing();
+    let cli = Cli::parse();
+    ensure!(
+        (0.0..=1.0).contains(&cli.fail_rate),
+        "--fail-rate must be within [0, 1]"
+    );
+    ensure!(
+        cli.max_work_secs >= 0.0,
+        "--max-work-secs must not be negative"
+    );
+    let permits = Semaphore::new(cli.permits);
+    let processed = ProcessedExploits::new(cli.local_cache_entries);
+    let shutdown = shutdown_on_ctrl_c();
+    let workers =
+        (0..cli.concurrency).map(|i| work(i, &cli, &permits, &processed, shutdown.clone()));
     try_join_all(workers).await?;
     Ok(())
 }
 
-async fn work(i: u32) -> Result<()> {
-    let client = reqwest::Client::new();
+async fn work(
+    i: u32,
+    cli: &Cli,
+    permits: &Semaphore,
+    processed: &ProcessedExploits,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let client = build_client(&cli.http_timeouts)?;
+    let backoff = Backoff {
+        base: Duration::from_millis(cli.backoff_base_ms),
+        max: Duration::from_millis(cli.backoff_max_ms),
+    };
     loop {
-        let res: Option<QueueTask> = {
-            client
-                .get("http://localhost:3000/queue/get_task")
-                .send()
-                .await?
-                .error_for_status()?
-                .json()
-                .await?
+        // NOTE: shutdown only interrupts the poll, a received task is always finished and submitted
+        let poll = poll_task(
+            &client,
+            &cli.server_url,
+            Duration::from_millis(cli.poll_timeout_ms),
+            backoff,
+        );
+        let Some(res) = or_shutdown(&mut shutdown, poll).await else {
+            info!(worker = i, "Shutting down");
+            return Ok(());
         };
-        let Some(task) = res else {
-            println!("Worker {i} has no tasks to do");
+        let Some(task) = res? else {
+            info!(worker = i, "No tasks to do");
             continue;
         };
-        println!(
-            "Worker {i} got task {:x?} {} {}",
-            task.id, task.submission_id, task.exploit
+        let task_id = task.id.display_short();
+        info!(
+            worker = i,
+            %task_id,
+            submission_id = %task.submission_id,
+            request_id = %task.request_id,
+            priority = task.priority,
+            attempt = task.attempt,
+            exploit = ?task.exploit,
+            "Got task"
         );
-        // work
-        sleep(Duration::from_secs_f64(random()) * 10).await;
-        println!("Worker {i} done task {:x?}", task.id);
-        // Uncomment to simulate task dropping
-        // if random() {
-        //     continue;
-        // }
+        {
+            let _permit = permits.acquire().await?;
+            // work
+            sleep(Duration::from_secs_f64(random::<f64>() * cli.max_work_secs)).await;
+        }
+        if random::<f64>() < cli.fail_rate {
+            warn!(
+                worker = i,
+                %task_id,
+                "Intentionally abandoning task, it should be requeued after timeout"
+            );
+            continue;
+        }
+        info!(worker = i, %task_id, "Done task");
+        let exploit = task.exploit.as_deref().map_or("", String::as_str);
+        let info = processed.get_or_process(&task.submission_id, exploit, process_exploit);
         let resp = QueueCompletedTask {
             id: task.id,
-            info: task.exploit.to_string(),
+            info,
+            request_id: Some(task.request_id),
         };
-        client
-            .post("http://localhost:3000/queue/submit_completed")
+        let res = client
+            .post(format!("{}/queue/submit_completed", cli.server_url))
             .json(&resp)
             .send()
-            .await?
-            .error_for_status()?;
+            .await?;
+        // NOTE: the queue got the completion, it failed downstream and the queue applies its policy
+        if res.status() == StatusCode::BAD_GATEWAY {
+            warn!(worker = i, %task_id, "Completion was not delivered downstream");
+            continue;
+        }
+        res.error_for_status()?;
     }
 }
@@ -1,48 +1,248 @@
 use std::{
     borrow::Borrow,
+    convert::Infallible,
     fmt::Debug,
     future::Future,
     hash::Hash,
+    mem,
+    ops::Deref,
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, TryLockError,
     },
+    time::Duration,
 };
 
-use dashmap::DashMap;
+use dashmap::{DashMap, try_result::TryResult};
 use dlv_list::{Index, VecList};
-use tokio::task;
+use tokio::{sync::broadcast, time::Instant};
+use tracing::warn;
 
 use crate::utils::Timed;
 
-#[derive(Debug)]
+const EXPIRATIONS_CAPACITY: usize = 1024;
+const EVICT_CHUNK: usize = 256;
+// NOTE: upper bounds of the fetch latency buckets, one more bucket counts everything slower
+const FETCH_LATENCY_BOUNDS_MILLIS: [u64; 13] =
+    [1, 2, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpireKind {
+    Idle,
+    Used,
+    // NOTE: dropped by `set` to stay within `with_max_entries`, before its idle expiry
+    Evicted,
+}
+
+#[derive(Debug, Clone)]
 pub struct ImportantExpires<K> {
     pub key: K,
     pub usages: u64,
+    pub kind: ExpireKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheMeta {
+    pub hit: bool,
+    // Since the last access for idle entries, since the current usage began for used ones
+    pub age: Duration,
+    pub usages: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    // NOTE: lookups through `get`, `get_with_meta` and `get_or_insert_with`
+    pub hits: u64,
+    pub misses: u64,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencyBucket {
+    // Inclusive upper bound, `None` for the last bucket
+    pub le: Option<Duration>,
+    pub count: u64,
 }
 
 #[derive(Debug)]
-pub enum CacheError {
+pub enum CacheError<E = Infallible> {
     KeyExists,
     KeyNotFound,
     UsageUnderflow,
-    NoncriticalError,
+    // NOTE: only from `try_*` methods, nothing changed and the call can be retried
+    WouldBlock,
+    Closed,
+    // NOTE: at `with_max_entries` with every entry in use, nothing was evicted
+    CapacityFull,
+    Fetch(E),
 }
 
 pub trait DataGetter {
-    type Key: Borrow<Self::BorrowedKey> + for<'a> From<&'a Self::BorrowedKey>;
-    type BorrowedKey: ?Sized;
+    type Key: Borrow<Self::BorrowedKey>;
+    type BorrowedKey: ToOwned<Owned = Self::Key> + ?Sized;
     type Value;
-    fn get(&self, key: &Self::BorrowedKey) -> impl Future<Output = Self::Value>;
+    type Error;
+    fn get(
+        &self,
+        key: &Self::BorrowedKey,
+    ) -> impl Future<Output = Result<Self::Value, Self::Error>>;
+
+    // Revalidates a cached value, `None` when it is unchanged. Fetches it again by default
+    fn refresh(
+        &self,
+        key: &Self::BorrowedKey,
+        _current: &Self::Value,
+    ) -> impl Future<Output = Result<Option<Self::Value>, Self::Error>> {
+        async move { self.get(key).await.map(Some) }
+    }
+}
+
+// NOTE: lets several caches share one getter, and its client
+impl<G: DataGetter> DataGetter for Arc<G> {
+    type Key = G::Key;
+    type BorrowedKey = G::BorrowedKey;
+    type Value = G::Value;
+    type Error = G::Error;
+    fn get(
+        &self,
+        key: &Self::BorrowedKey,
+    ) -> impl Future<Output = Result<Self::Value, Self::Error>> {
+        (**self).get(key)
+    }
+    fn refresh(
+        &self,
+        key: &Self::BorrowedKey,
+        current: &Self::Value,
+    ) -> impl Future<Output = Result<Option<Self::Value>, Self::Error>> {
+        (**self).refresh(key, current)
+    }
+}
+
+// What an `EvictionPolicy` sees of a cached entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionCandidate<K> {
+    pub key: K,
+    pub kind: ExpireKind,
+    // Same as `CacheMeta::age`
+    pub age: Duration,
+    pub usages: u64,
+}
+
+pub trait EvictionPolicy<K>: Debug + Send + Sync {
+    // Gets every entry, the idle ones first and each list from its least recently touched entry.
+    // Returns the positions of the entries to evict, in eviction order
+    fn select(&self, candidates: &[EvictionCandidate<K>]) -> Vec<usize>;
+}
+
+// The built-in policy, evicts idle and used entries past their expiry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeExpiry {
+    pub idle_expire: Duration,
+    pub used_expire: Duration,
+}
+
+impl<K> EvictionPolicy<K> for TimeExpiry {
+    fn select(&self, candidates: &[EvictionCandidate<K>]) -> Vec<usize> {
+        candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| match candidate.kind {
+                ExpireKind::Idle => candidate.age > self.idle_expire,
+                ExpireKind::Used => candidate.age > self.used_expire,
+                ExpireKind::Evicted => false,
+            })
+            .map(|(position, _)| position)
+            .collect()
+    }
 }
 
-#[derive(Debug, Default)]
 pub struct Cache<G: DataGetter, const IDLE_EXPIRE_MILLIS: u128, const USED_EXPIRE_MILLIS: u128>
+where
+    G::Key: Hash + Eq + Clone,
+{
+    // NOTE: shared with every `CacheHandle`, so `handle` works through a plain reference
+    shared: Arc<SharedCache<G, IDLE_EXPIRE_MILLIS, USED_EXPIRE_MILLIS>>,
+}
+
+#[derive(Debug)]
+struct SharedCache<G: DataGetter, const IDLE_EXPIRE_MILLIS: u128, const USED_EXPIRE_MILLIS: u128>
 where
     G::Key: Hash + Eq + Clone,
 {
     cached: MapWithExpires<G::Key, G::Value, IDLE_EXPIRE_MILLIS, USED_EXPIRE_MILLIS>,
     getter: G,
+    closed: AtomicBool,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    fetch_latency: [AtomicU64; FETCH_LATENCY_BOUNDS_MILLIS.len() + 1],
+}
+
+impl<G, const FE: u128, const SE: u128> Debug for Cache<G, FE, SE>
+where
+    G: DataGetter + Debug,
+    G::Key: Hash + Eq + Clone + Debug,
+    G::Value: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.shared.fmt(f)
+    }
+}
+
+impl<G, const FE: u128, const SE: u128> Default for Cache<G, FE, SE>
+where
+    G: DataGetter + Default,
+    G::Key: Hash + Eq + Clone,
+{
+    fn default() -> Self {
+        Self::new(G::default())
+    }
+}
+
+impl<G, const FE: u128, const SE: u128> Cache<G, FE, SE>
+where
+    G: DataGetter,
+    G::Key: Hash + Eq + Clone,
+{
+    pub fn new(getter: G) -> Self {
+        let shared = SharedCache {
+            cached: MapWithExpires::default(),
+            getter,
+            closed: AtomicBool::new(false),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            fetch_latency: Default::default(),
+        };
+        Self {
+            shared: Arc::new(shared),
+        }
+    }
+
+    // NOTE: the `with_*` builders configure a cache before any handle to it exists
+    fn configure(&mut self) -> &mut MapWithExpires<G::Key, G::Value, FE, SE> {
+        let shared = Arc::get_mut(&mut self.shared).expect("Cache is configured after sharing");
+        &mut shared.cached
+    }
+
+    // Replaces `IDLE_EXPIRE_MILLIS` and `USED_EXPIRE_MILLIS` for `evict_expired`
+    pub fn with_expiry(mut self, idle_expire: Duration, used_expire: Duration) -> Self {
+        self.configure().idle_expire = idle_expire;
+        self.configure().used_expire = used_expire;
+        self
+    }
+
+    // Caps the number of entries, at the cap `set` and misses evict the least recently touched idle
+    // entry, or fail with `CacheError::CapacityFull` when every entry is in use
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.configure().max_entries = Some(max_entries);
+        self
+    }
+
+    // Lets `policy` pick what `evict_expired` and `evict_expired_budget` remove. Lookups still
+    // expire idle entries lazily after the idle expiry
+    pub fn with_eviction_policy(mut self, policy: impl EvictionPolicy<G::Key> + 'static) -> Self {
+        self.configure().policy = Some(Box::new(policy));
+        self
+    }
 }
 
 impl<G, const FE: u128, const SE: u128> Cache<G, FE, SE>
@@ -52,34 +252,375 @@ where
     G::Value: Clone + Default,
     G::BorrowedKey: Hash + Eq,
 {
-    pub async fn get(&self, key: &G::BorrowedKey) -> G::Value {
-        match self.cached.get(key) {
-            Some(value) => value,
+    // Rejects every later get, set and usage change with `CacheError::Closed`, fetches in flight
+    // complete but their values are not cached
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::Release);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire)
+    }
+
+    fn ensure_open<E>(&self) -> Result<(), CacheError<E>> {
+        if self.is_closed() {
+            return Err(CacheError::Closed);
+        }
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &G::BorrowedKey) -> Result<G::Value, CacheError<G::Error>> {
+        self.ensure_open()?;
+        match self.count_lookup(self.shared.cached.get(key)) {
+            Some(value) => Ok(value),
             None => self.fetch_and_set(key).await,
         }
     }
 
+    pub async fn get_with_meta(
+        &self,
+        key: &G::BorrowedKey,
+    ) -> Result<(G::Value, CacheMeta), CacheError<G::Error>> {
+        self.ensure_open()?;
+        if let Some(hit) = self.count_lookup(self.shared.cached.get_with_meta(key)) {
+            return Ok(hit);
+        }
+        let value = self.fetch_and_set(key).await?;
+        let meta = CacheMeta {
+            hit: false,
+            age: Duration::ZERO,
+            usages: self.shared.cached.usage_count(key).unwrap_or(0),
+        };
+        Ok((value, meta))
+    }
+
     pub fn set(&self, key: G::Key, value: G::Value) -> Result<(), CacheError> {
-        self.cached.set(key, value)
+        self.ensure_open()?;
+        self.shared.cached.set(key, value)
+    }
+
+    // Replaces the value in place, keeping expiry position and usages, or inserts it if absent
+    pub fn upsert(&self, key: G::Key, value: G::Value) -> Result<(), CacheError> {
+        self.ensure_open()?;
+        self.shared.cached.upsert(key, value)
+    }
+
+    // Like `upsert`, but returns the value it replaced, `None` when it inserted
+    pub fn replace(&self, key: G::Key, value: G::Value) -> Result<Option<G::Value>, CacheError> {
+        self.ensure_open()?;
+        self.shared.cached.replace(key, value)
+    }
+
+    pub fn add_usage(&self, key: &G::BorrowedKey) -> Result<(), CacheError> {
+        self.ensure_open()?;
+        self.shared.cached.add_usage(key)
+    }
+
+    pub fn remove_usage(&self, key: &G::BorrowedKey) -> Result<(), CacheError> {
+        self.ensure_open()?;
+        self.shared.cached.remove_usage(key)
+    }
+
+    // Like `remove_usage`, but fails with `WouldBlock` instead of waiting for a contended lock,
+    // for `Drop` impls and other paths that must not block
+    pub fn try_remove_usage(&self, key: &G::BorrowedKey) -> Result<(), CacheError> {
+        self.ensure_open()?;
+        self.shared.cached.try_remove_usage(key)
+    }
+
+    // Adds a usage that the returned guard removes when dropped, even on early return or panic
+    pub fn usage_guard(
+        &self,
+        key: &G::BorrowedKey,
+    ) -> Result<UsageGuard<'_, G, FE, SE>, CacheError> {
+        self.add_usage(key)?;
+        Ok(UsageGuard {
+            cache: self,
+            key: Some(key.to_owned()),
+        })
     }
 
-    pub async fn add_usage(&self, key: &G::BorrowedKey) -> Result<(), CacheError> {
-        self.cached.add_usage(key).await
+    pub fn usage_count(&self, key: &G::BorrowedKey) -> Option<u64> {
+        self.shared.cached.usage_count(key)
     }
 
-    pub async fn remove_usage(&self, key: &G::BorrowedKey) -> Result<(), CacheError> {
-        self.cached.remove_usage(key).await
+    // Keys in use for longer than `older_than`, with their usage counts
+    pub fn leaked_usages(&self, older_than: Duration) -> Vec<(G::Key, u64)> {
+        self.shared.cached.leaked_usages(older_than)
     }
 
     #[must_use]
     pub fn evict_expired(&self) -> Vec<ImportantExpires<G::Key>> {
-        self.cached.evict_expired()
+        self.shared.cached.evict_expired()
+    }
+
+    // Evicts at most `max` expired entries, idle ones first, and tells whether expired entries
+    // are left
+    #[must_use]
+    pub fn evict_expired_budget(&self, max: usize) -> (Vec<ImportantExpires<G::Key>>, bool) {
+        self.shared.cached.evict_expired_budget(max)
     }
 
-    async fn fetch_and_set(&self, key: &G::BorrowedKey) -> G::Value {
-        let data: G::Value = self.getter.get(key).await;
-        self.cached.set(key.into(), data.clone()).ok();
-        data
+    #[must_use]
+    pub fn expire_now(&self, include_used: bool) -> Vec<ImportantExpires<G::Key>> {
+        self.shared.cached.expire_now(include_used)
+    }
+
+    pub fn subscribe_expirations(&self) -> broadcast::Receiver<ImportantExpires<G::Key>> {
+        self.shared.cached.expirations.subscribe()
+    }
+
+    // Cross-checks the entries against the expiry lists, for tests. Only meaningful while no other
+    // call is running, sweeps remove entries before their list nodes
+    pub fn check_invariant(&self) -> Result<(), String> {
+        self.shared.cached.check_invariant()
+    }
+
+    // Like `get`, but computes a missing value with `f` instead of the getter
+    pub fn get_or_insert_with(
+        &self,
+        key: &G::BorrowedKey,
+        f: impl FnOnce() -> G::Value,
+    ) -> Result<G::Value, CacheError> {
+        self.ensure_open()?;
+        match self.count_lookup(self.shared.cached.get(key)) {
+            Some(value) => Ok(value),
+            None => Ok(self.set_or_converge(key, f())),
+        }
+    }
+
+    fn count_lookup<T>(&self, found: Option<T>) -> Option<T> {
+        let counter = if found.is_some() {
+            &self.shared.hits
+        } else {
+            &self.shared.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.shared.hits.load(Ordering::Relaxed),
+            misses: self.shared.misses.load(Ordering::Relaxed),
+            len: self.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shared.cached.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.cached.data.is_empty()
+    }
+
+    // Snapshot of the cached keys, in no particular order
+    pub fn keys(&self) -> Vec<G::Key> {
+        self.shared
+            .cached
+            .data
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    // Drops the entry even while in use, later `remove_usage` calls on it fail with `KeyNotFound`.
+    // Returns the usages it had
+    pub fn invalidate(&self, key: &G::BorrowedKey) -> Result<u64, CacheError> {
+        self.ensure_open()?;
+        self.shared
+            .cached
+            .remove(key)
+            .ok_or(CacheError::KeyNotFound)
+    }
+
+    // Like `invalidate` for every key starting with `prefix`, returns how many were dropped. Keys
+    // inserted while it runs may be left alone
+    pub fn invalidate_prefix(&self, prefix: &str) -> Result<usize, CacheError>
+    where
+        G::Key: AsRef<str>,
+    {
+        self.ensure_open()?;
+        let matching: Vec<G::Key> = self
+            .shared
+            .cached
+            .data
+            .iter()
+            .filter(|entry| entry.key().as_ref().starts_with(prefix))
+            .map(|entry| entry.key().clone())
+            .collect();
+        let removed = matching
+            .iter()
+            .filter(|key| self.shared.cached.remove::<G::Key>(key).is_some())
+            .count();
+        Ok(removed)
+    }
+
+    // Counts of getter calls by duration, failed calls included
+    pub fn fetch_latency_histogram(&self) -> Vec<LatencyBucket> {
+        let bounds = FETCH_LATENCY_BOUNDS_MILLIS
+            .iter()
+            .map(|&millis| Some(Duration::from_millis(millis)))
+            .chain([None]);
+        bounds
+            .zip(&self.shared.fetch_latency)
+            .map(|(le, count)| LatencyBucket {
+                le,
+                count: count.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    // Revalidates a cached value with the getter, an unchanged one is kept and its idle expiry
+    // renewed, a changed one replaces it in place. Fetches missing values like `get`
+    pub async fn refresh(&self, key: &G::BorrowedKey) -> Result<G::Value, CacheError<G::Error>> {
+        self.ensure_open()?;
+        let Some(current) = self.shared.cached.peek(key) else {
+            return self.fetch_and_set(key).await;
+        };
+        let started = Instant::now();
+        let refreshed = self.shared.getter.refresh(key, &current).await;
+        self.record_fetch_latency(started.elapsed());
+        let value = match refreshed.map_err(CacheError::Fetch)? {
+            Some(value) => {
+                self.ensure_open()?;
+                // NOTE: evicted since the peek with no room left, served uncached like a fetch
+                if self
+                    .shared
+                    .cached
+                    .upsert(key.to_owned(), value.clone())
+                    .is_err()
+                {
+                    return Ok(value);
+                }
+                value
+            }
+            None => current,
+        };
+        self.shared.cached.renew_idle(key);
+        Ok(value)
+    }
+
+    fn record_fetch_latency(&self, elapsed: Duration) {
+        let bucket = FETCH_LATENCY_BOUNDS_MILLIS
+            .partition_point(|&millis| Duration::from_millis(millis) < elapsed);
+        self.shared.fetch_latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    async fn fetch_and_set(&self, key: &G::BorrowedKey) -> Result<G::Value, CacheError<G::Error>> {
+        let started = Instant::now();
+        let fetched = self.shared.getter.get(key).await;
+        self.record_fetch_latency(started.elapsed());
+        let data: G::Value = fetched.map_err(CacheError::Fetch)?;
+        self.ensure_open()?;
+        Ok(self.set_or_converge(key, data))
+    }
+
+    fn set_or_converge(&self, key: &G::BorrowedKey, data: G::Value) -> G::Value {
+        match self.shared.cached.set(key.to_owned(), data.clone()) {
+            Ok(()) => data,
+            // NOTE: a concurrent miss cached its value first, converge on it unless already
+            // evicted. At capacity the value is served without caching it
+            Err(_) => self.shared.cached.get(key).unwrap_or(data),
+        }
+    }
+}
+
+impl<G, const FE: u128, const SE: u128> Cache<G, FE, SE>
+where
+    G: DataGetter,
+    G::Key: Hash + Eq + Clone,
+{
+    pub fn handle(&self) -> CacheHandle<G, FE, SE> {
+        CacheHandle(Cache {
+            shared: self.shared.clone(),
+        })
+    }
+
+    pub fn into_handle(self) -> CacheHandle<G, FE, SE> {
+        CacheHandle(self)
+    }
+}
+
+// Shared cache for code that can't hold an `Arc` around it, derefs to the `Cache`
+pub struct CacheHandle<G: DataGetter, const FE: u128, const SE: u128>(Cache<G, FE, SE>)
+where
+    G::Key: Hash + Eq + Clone;
+
+impl<G, const FE: u128, const SE: u128> Clone for CacheHandle<G, FE, SE>
+where
+    G: DataGetter,
+    G::Key: Hash + Eq + Clone,
+{
+    fn clone(&self) -> Self {
+        self.0.handle()
+    }
+}
+
+impl<G, const FE: u128, const SE: u128> Deref for CacheHandle<G, FE, SE>
+where
+    G: DataGetter,
+    G::Key: Hash + Eq + Clone,
+{
+    type Target = Cache<G, FE, SE>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<G, const FE: u128, const SE: u128> From<Arc<Cache<G, FE, SE>>> for CacheHandle<G, FE, SE>
+where
+    G: DataGetter,
+    G::Key: Hash + Eq + Clone,
+{
+    fn from(cache: Arc<Cache<G, FE, SE>>) -> Self {
+        cache.handle()
+    }
+}
+
+// A usage of a cache entry added by `Cache::usage_guard`, removed on drop
+#[must_use = "the usage is removed as soon as the guard is dropped"]
+pub struct UsageGuard<'a, G: DataGetter, const FE: u128, const SE: u128>
+where
+    G::Key: Hash + Eq + Clone,
+    G::Value: Clone,
+{
+    cache: &'a Cache<G, FE, SE>,
+    // NOTE: only `None` once `release` took it
+    key: Option<G::Key>,
+}
+
+impl<G, const FE: u128, const SE: u128> UsageGuard<'_, G, FE, SE>
+where
+    G: DataGetter,
+    G::Key: Hash + Eq + Clone,
+    G::Value: Clone,
+{
+    pub fn key(&self) -> &G::Key {
+        self.key.as_ref().expect("Key taken before drop")
+    }
+
+    // Removes the usage now, reporting the error that dropping would ignore
+    pub fn release(mut self) -> Result<(), CacheError> {
+        let key = self.key.take().expect("Key taken before drop");
+        self.cache.shared.cached.remove_usage::<G::Key>(&key)
+    }
+}
+
+impl<G, const FE: u128, const SE: u128> Drop for UsageGuard<'_, G, FE, SE>
+where
+    G: DataGetter,
+    G::Key: Hash + Eq + Clone,
+    G::Value: Clone,
+{
+    fn drop(&mut self) {
+        // NOTE: bypasses the closed check to keep usages balanced, `KeyNotFound` after an
+        // invalidate is the only expected error and there is nothing left to release then
+        if let Some(key) = self.key.take() {
+            let _ = self.cache.shared.cached.remove_usage::<G::Key>(&key);
+        }
     }
 }
 
@@ -92,6 +633,21 @@ where
     idle: Mutex<VecList<Timed<K>>>,
     used: Mutex<VecList<Timed<K>>>,
     data: DashMap<K, MapEntry<K, V>>,
+    expirations: broadcast::Sender<ImportantExpires<K>>,
+    // NOTE: the const parameters unless overridden at runtime
+    idle_expire: Duration,
+    used_expire: Duration,
+    // NOTE: `None` is `TimeExpiry` with the durations above, scanned from the list fronts only
+    policy: Option<Box<dyn EvictionPolicy<K>>>,
+    max_entries: Option<usize>,
+}
+
+fn try_lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, CacheError> {
+    match mutex.try_lock() {
+        Ok(guard) => Ok(guard),
+        Err(TryLockError::WouldBlock) => Err(CacheError::WouldBlock),
+        Err(TryLockError::Poisoned(_)) => panic!("Mutex poisoned"),
+    }
 }
 
 #[derive(Debug)]
@@ -110,6 +666,11 @@ where
             idle: Mutex::new(VecList::new()),
             used: Mutex::new(VecList::new()),
             data: DashMap::new(),
+            expirations: broadcast::channel(EXPIRATIONS_CAPACITY).0,
+            idle_expire: Duration::from_millis(FE as u64),
+            used_expire: Duration::from_millis(SE as u64),
+            policy: None,
+            max_entries: None,
         }
     }
 }
@@ -122,7 +683,7 @@ where
 {
     pub fn get<Q>(&self, key: &Q) -> Option<V>
     where
-        K: Borrow<Q> + for<'a> From<&'a Q>,
+        K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
         let (value, counter) = {
@@ -130,146 +691,492 @@ where
             let MapEntry { value, counter, .. } = kv_pair.value();
             (value.clone(), counter.load(Ordering::Relaxed))
         };
-        if counter == 0 {
-            self.renew_idle(key);
+        if counter == 0 && !self.renew_idle(key) {
+            return None;
         }
         Some(value)
     }
 
+    // Like `get`, without renewing the idle expiry
+    pub fn peek<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        Some(self.data.get(key)?.value.clone())
+    }
+
+    // Same renewal as `get`, but reads the list node too, so it takes both list locks
+    pub fn get_with_meta<Q>(&self, key: &Q) -> Option<(V, CacheMeta)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut idle = self.idle.lock().expect("Mutex poisoned");
+        let used = self.used.lock().expect("Mutex poisoned");
+        let mut entry = self.data.get_mut(key)?;
+        let usages = *entry.counter.get_mut();
+        let list = if usages == 0 { &*idle } else { &*used };
+        let age = list
+            .get(entry.index)
+            .expect("Invariant violated")
+            .timestamp
+            .elapsed();
+        if usages == 0 && age > self.idle_expire {
+            let index = entry.index;
+            drop(entry);
+            self.expire_idle_locked(&mut idle, key, index);
+            return None;
+        }
+        if usages == 0 {
+            let Timed { value, .. } = idle.remove(entry.index).expect("Invariant violated");
+            entry.index = idle.push_back(Timed::new(value));
+        }
+        let meta = CacheMeta {
+            hit: true,
+            age,
+            usages,
+        };
+        Some((entry.value.clone(), meta))
+    }
+
     pub fn set(&self, key: K, value: V) -> Result<(), CacheError> {
-        let index = self
-            .idle
-            .lock()
-            .expect("Mutex poisoned")
-            .push_back(Timed::new(key.clone()));
-        let counter = AtomicU64::new(0);
-        match self.data.entry(key) {
-            dashmap::Entry::Vacant(entry) => {
-                entry.insert(MapEntry {
-                    value,
-                    index,
-                    counter,
+        // NOTE: entries are only inserted here, under the idle lock, so neither a concurrent set of
+        // the same key nor one past `max_entries` can slip in between the checks and the insert
+        let mut idle = self.idle.lock().expect("Mutex poisoned");
+        if self.data.contains_key(&key) {
+            return Err(CacheError::KeyExists);
+        }
+        if let Some(max_entries) = self.max_entries {
+            self.make_room_locked(&mut idle, max_entries)?;
+        }
+        let index = idle.push_back(Timed::new(key.clone()));
+        let entry = MapEntry {
+            value,
+            index,
+            counter: AtomicU64::new(0),
+        };
+        let previous = self.data.insert(key, entry);
+        assert!(previous.is_none(), "Invariant violated");
+        Ok(())
+    }
+
+    // Evicts idle entries, least recently touched first, until one more fits under `max_entries`
+    fn make_room_locked(
+        &self,
+        idle: &mut VecList<Timed<K>>,
+        max_entries: usize,
+    ) -> Result<(), CacheError> {
+        while self.data.len() >= max_entries {
+            let Some(index) = idle.indices().next() else {
+                return Err(CacheError::CapacityFull);
+            };
+            let Timed { value: key, .. } = idle.remove(index).expect("Unreachable");
+            // NOTE: a concurrent sweep may have taken the entry already, leaving only its node
+            let Some((key, _)) = self.data.remove_if(&key, |_, entry| entry.index == index) else {
+                continue;
+            };
+            self.expirations
+                .send(ImportantExpires {
+                    key,
+                    usages: 0,
+                    kind: ExpireKind::Evicted,
+                })
+                .ok();
+        }
+        Ok(())
+    }
+
+    pub fn upsert(&self, key: K, value: V) -> Result<(), CacheError> {
+        self.replace(key, value).map(|_| ())
+    }
+
+    pub fn replace(&self, key: K, value: V) -> Result<Option<V>, CacheError> {
+        loop {
+            if let Some(mut entry) = self.data.get_mut(&key) {
+                return Ok(Some(mem::replace(&mut entry.value, value)));
+            }
+            // NOTE: `KeyExists` means a concurrent insert won, replace its value then
+            match self.set(key.clone(), value.clone()) {
+                Ok(()) => return Ok(None),
+                Err(CacheError::KeyExists) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn add_usage<Q>(&self, key: &Q) -> Result<(), CacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        {
+            let kv_pair = self.data.get(key).ok_or(CacheError::KeyNotFound)?;
+            let bumped = kv_pair
+                .counter
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                    (x > 0).then(|| x + 1)
                 });
-                Ok(())
+            if bumped.is_ok() {
+                return Ok(());
             }
-            dashmap::Entry::Occupied(_) => {
-                self.idle.lock().expect("Mutex poisoned").remove(index);
-                Err(CacheError::KeyExists)
+        }
+        // NOTE: the counter leaves and reaches zero only under both list locks, together with the
+        // node move, so a zero counter always means the node is in `idle`
+        let mut idle = self.idle.lock().expect("Mutex poisoned");
+        let mut used = self.used.lock().expect("Mutex poisoned");
+        let mut entry = self.data.get_mut(key).ok_or(CacheError::KeyNotFound)?;
+        if *entry.counter.get_mut() == 0 {
+            let Timed { value, .. } = idle.remove(entry.index).expect("Invariant violated");
+            entry.index = used.push_back(Timed::new(value));
+        }
+        *entry.counter.get_mut() += 1;
+        Ok(())
+    }
+
+    pub fn remove_usage<Q>(&self, key: &Q) -> Result<(), CacheError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        {
+            let kv_pair = self.data.get(key).ok_or(CacheError::KeyNotFound)?;
+            let dropped = kv_pair
+                .counter
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                    (x > 1).then(|| x - 1)
+                });
+            if dropped.is_ok() {
+                return Ok(());
             }
         }
+        let mut idle = self.idle.lock().expect("Mutex poisoned");
+        let mut used = self.used.lock().expect("Mutex poisoned");
+        let mut entry = self.data.get_mut(key).ok_or(CacheError::KeyNotFound)?;
+        Self::release_locked(&mut idle, &mut used, &mut entry)
     }
 
-    pub async fn add_usage<Q>(&self, key: &Q) -> Result<(), CacheError>
+    pub fn try_remove_usage<Q>(&self, key: &Q) -> Result<(), CacheError>
     where
-        K: Borrow<Q> + for<'a> From<&'a Q>,
+        K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
-        let kv_pair = self.data.get(key).ok_or(CacheError::KeyNotFound)?;
-        let MapEntry { index, counter, .. } = kv_pair.value();
-        let index = *index;
-        let prev = counter.fetch_add(1, Ordering::Relaxed);
-        drop(kv_pair);
-        if prev == 0 {
-            for _ in 0..10 {
-                {
-                    let mut idle = self.idle.lock().expect("Mutex poisoned");
-                    let mut used = self.used.lock().expect("Mutex poisoned");
-                    if let Some(Timed { value, .. }) = idle.remove(index) {
-                        self.data
-                            .get_mut(key)
-                            .expect("Cannot happen since removing requires is locked")
-                            .index = used.push_back(Timed::new(value));
-                        return Ok(());
-                    }
-                }
-                task::yield_now().await;
+        {
+            let kv_pair = match self.data.try_get(key) {
+                TryResult::Present(kv_pair) => kv_pair,
+                TryResult::Absent => return Err(CacheError::KeyNotFound),
+                TryResult::Locked => return Err(CacheError::WouldBlock),
+            };
+            let dropped = kv_pair
+                .counter
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                    (x > 1).then(|| x - 1)
+                });
+            if dropped.is_ok() {
+                return Ok(());
             }
-            return Err(CacheError::NoncriticalError);
         }
+        let mut idle = try_lock(&self.idle)?;
+        let mut used = try_lock(&self.used)?;
+        let mut entry = match self.data.try_get_mut(key) {
+            TryResult::Present(entry) => entry,
+            TryResult::Absent => return Err(CacheError::KeyNotFound),
+            TryResult::Locked => return Err(CacheError::WouldBlock),
+        };
+        Self::release_locked(&mut idle, &mut used, &mut entry)
+    }
+
+    // The last usage moves the node back to `idle`, so both list locks must be held
+    fn release_locked(
+        idle: &mut VecList<Timed<K>>,
+        used: &mut VecList<Timed<K>>,
+        entry: &mut MapEntry<K, V>,
+    ) -> Result<(), CacheError> {
+        match *entry.counter.get_mut() {
+            0 => return Err(CacheError::UsageUnderflow),
+            1 => {
+                let Timed { value, .. } = used.remove(entry.index).expect("Invariant violated");
+                entry.index = idle.push_back(Timed::new(value));
+            }
+            _ => {}
+        }
+        *entry.counter.get_mut() -= 1;
         Ok(())
     }
 
-    pub async fn remove_usage<Q>(&self, key: &Q) -> Result<(), CacheError>
+    pub fn remove<Q>(&self, key: &Q) -> Option<u64>
     where
-        K: Borrow<Q> + for<'a> From<&'a Q>,
+        K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
-        let kv_pair = self.data.get(key).ok_or(CacheError::KeyNotFound)?;
-        let MapEntry { index, counter, .. } = kv_pair.value();
-        let index = *index;
-        let prev = counter
-            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
-                if x > 0 {
-                    Some(x - 1)
-                } else {
-                    None
-                }
-            })
-            .map_err(|_| CacheError::UsageUnderflow)?;
-        drop(kv_pair);
-        if prev == 1 {
-            for _ in 0..10 {
-                {
-                    let mut idle = self.idle.lock().expect("Mutex poisoned");
-                    let mut used = self.used.lock().expect("Mutex poisoned");
-                    if let Some(Timed { value, .. }) = used.remove(index) {
-                        self.data
-                            .get_mut(key)
-                            .expect("Cannot happen since removing requires locking")
-                            .index = idle.push_back(Timed::new(value));
-                        return Ok(());
-                    }
+        // NOTE: the counter can't cross zero while both list locks are held, so it tells the list
+        let mut idle = self.idle.lock().expect("Mutex poisoned");
+        let mut used = self.used.lock().expect("Mutex poisoned");
+        let (_, entry) = self.data.remove(key)?;
+        let usages = entry.counter.load(Ordering::Relaxed);
+        let list = if usages == 0 { &mut idle } else { &mut used };
+        list.remove(entry.index).expect("Invariant violated");
+        Some(usages)
+    }
+
+    // Every entry has one node with its key, in `idle` with a zero counter and in `used` otherwise,
+    // and the lists have no other nodes
+    fn check_invariant(&self) -> Result<(), String>
+    where
+        K: Debug,
+    {
+        let idle = self.idle.lock().expect("Mutex poisoned");
+        let used = self.used.lock().expect("Mutex poisoned");
+        for entry in &self.data {
+            let usages = entry.counter.load(Ordering::Relaxed);
+            let list = if usages == 0 { &idle } else { &used };
+            match list.get(entry.index) {
+                Some(node) if node.value == *entry.key() => {}
+                node => {
+                    let key = entry.key();
+                    return Err(format!("{key:?} with {usages} usages has node {node:?}"));
                 }
-                task::yield_now().await;
             }
-            return Err(CacheError::NoncriticalError);
+        }
+        // NOTE: the nodes found above have distinct keys, so equal counts leave no stray nodes
+        let nodes = idle.len() + used.len();
+        if nodes != self.data.len() {
+            return Err(format!("{nodes} nodes for {} entries", self.data.len()));
         }
         Ok(())
     }
 
+    pub fn usage_count<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let kv_pair = self.data.get(key)?;
+        Some(kv_pair.counter.load(Ordering::Relaxed))
+    }
+
+    pub fn leaked_usages(&self, older_than: Duration) -> Vec<(K, u64)> {
+        // NOTE: a node enters `used` when its counter leaves zero and stays there until it returns
+        // to zero, so its timestamp is the start of the current usage
+        let candidates: Vec<_> = {
+            let used = self.used.lock().expect("Mutex poisoned");
+            used.indices()
+                .map(|index| (index, used.get(index).expect("Unreachable")))
+                .take_while(|(_, entry)| entry.timestamp.elapsed() > older_than)
+                .map(|(index, entry)| (index, entry.value.clone()))
+                .collect()
+        };
+        candidates
+            .into_iter()
+            .filter_map(|(index, key)| {
+                let entry = self.data.get(&key).filter(|entry| entry.index == index)?;
+                let usages = entry.counter.load(Ordering::Relaxed);
+                drop(entry);
+                Some((key, usages))
+            })
+            .collect()
+    }
+
     #[must_use]
     pub fn evict_expired(&self) -> Vec<ImportantExpires<K>> {
-        let mut idle = self.idle.lock().expect("Mutex poisoned");
-        while let Some(task) = idle.front() {
-            if task.timestamp.elapsed().as_millis() > FAST_EXPIRE_MILLIS {
-                let Timed { value: key, .. } = idle.pop_front().expect("Unreachable");
-                self.data.remove(key.borrow()).expect("Invariant violated");
-            } else {
-                break;
-            }
+        self.evict_expired_budget(usize::MAX).0
+    }
+
+    #[must_use]
+    pub fn evict_expired_budget(&self, max: usize) -> (Vec<ImportantExpires<K>>, bool) {
+        if let Some(policy) = &self.policy {
+            return self.evict_selected(&**policy, max);
         }
+        self.evict_while(
+            |timestamp| timestamp.elapsed() > self.idle_expire,
+            |timestamp| timestamp.elapsed() > self.used_expire,
+            max,
+        )
+    }
+
+    #[must_use]
+    pub fn expire_now(&self, include_used: bool) -> Vec<ImportantExpires<K>> {
+        self.evict_while(|_| true, |_| include_used, usize::MAX).0
+    }
+
+    fn evict_while(
+        &self,
+        idle_expired: impl Fn(Instant) -> bool,
+        used_expired: impl Fn(Instant) -> bool,
+        max: usize,
+    ) -> (Vec<ImportantExpires<K>>, bool) {
+        let (mut expires, idle_more) =
+            self.evict_list(&self.idle, idle_expired, ExpireKind::Idle, max);
+        let (used_expires, used_more) = self.evict_list(
+            &self.used,
+            used_expired,
+            ExpireKind::Used,
+            max - expires.len(),
+        );
+        expires.extend(used_expires);
+        (expires, idle_more || used_more)
+    }
+
+    // NOTE: with the budget used up, only checks whether the front of the list is expired
+    fn evict_list(
+        &self,
+        list: &Mutex<VecList<Timed<K>>>,
+        expired: impl Fn(Instant) -> bool,
+        kind: ExpireKind,
+        max: usize,
+    ) -> (Vec<ImportantExpires<K>>, bool) {
         let mut expires = vec![];
-        let mut used = self.used.lock().expect("Mutex poisoned");
-        while let Some(task) = used.front() {
-            if task.timestamp.elapsed().as_millis() > SLOW_EXPIRE_MILLIS {
-                let Timed { value: key, .. } = used.pop_front().expect("Unreachable");
-                let (key, value) = self.data.remove(key.borrow()).expect("Invariant violated");
-                expires.push(ImportantExpires {
+        loop {
+            let chunk = EVICT_CHUNK.min(max - expires.len());
+            if chunk == 0 {
+                let list = list.lock().expect("Mutex poisoned");
+                let more = list.front().is_some_and(|entry| expired(entry.timestamp));
+                return (expires, more);
+            }
+            // NOTE: the list lock is held only to scan a bounded chunk of its front
+            let candidates: Vec<_> = {
+                let list = list.lock().expect("Mutex poisoned");
+                list.indices()
+                    .take(chunk)
+                    .map(|index| (index, list.get(index).expect("Unreachable")))
+                    .take_while(|(_, entry)| expired(entry.timestamp))
+                    .map(|(index, entry)| (index, entry.value.clone()))
+                    .collect()
+            };
+            let scanned = candidates.len();
+            let mut evicted = vec![];
+            for (index, key) in candidates {
+                // NOTE: entries renewed, moved to the other list or evicted concurrently since the
+                // scan no longer point at this index and are left alone
+                let Some((key, entry)) = self.data.remove_if(&key, |_, entry| entry.index == index)
+                else {
+                    continue;
+                };
+                evicted.push(index);
+                let expire = ImportantExpires {
                     key,
-                    usages: value.counter.load(Ordering::Relaxed),
-                });
-            } else {
-                break;
+                    usages: entry.counter.load(Ordering::Relaxed),
+                    kind,
+                };
+                // NOTE: fails only when nobody is subscribed
+                self.expirations.send(expire.clone()).ok();
+                expires.push(expire);
+            }
+            {
+                let mut list = list.lock().expect("Mutex poisoned");
+                for index in &evicted {
+                    list.remove(*index);
+                }
+            }
+            if scanned < chunk || evicted.is_empty() {
+                return (expires, false);
             }
         }
-        expires
     }
 
-    fn renew_idle<Q>(&self, key: &Q)
+    // NOTE: unlike `evict_list` holds both list locks for the whole sweep, the policy needs a
+    // consistent snapshot of every entry
+    fn evict_selected(
+        &self,
+        policy: &dyn EvictionPolicy<K>,
+        max: usize,
+    ) -> (Vec<ImportantExpires<K>>, bool) {
+        let mut idle = self.idle.lock().expect("Mutex poisoned");
+        let mut used = self.used.lock().expect("Mutex poisoned");
+        let nodes: Vec<_> = [(&*idle, ExpireKind::Idle), (&*used, ExpireKind::Used)]
+            .into_iter()
+            .flat_map(|(list, kind)| {
+                list.indices()
+                    .map(move |index| (index, kind, list.get(index).expect("Unreachable")))
+            })
+            .collect();
+        let candidates: Vec<_> = nodes
+            .iter()
+            .map(|(_, kind, node)| EvictionCandidate {
+                key: node.value.clone(),
+                kind: *kind,
+                age: node.timestamp.elapsed(),
+                usages: self
+                    .data
+                    .get(&node.value)
+                    .map_or(0, |entry| entry.counter.load(Ordering::Relaxed)),
+            })
+            .collect();
+        let nodes: Vec<_> = nodes
+            .into_iter()
+            .map(|(index, kind, _)| (index, kind))
+            .collect();
+        let selected = policy.select(&candidates);
+        let more = selected.len() > max;
+        let mut expires = vec![];
+        for position in selected.into_iter().take(max) {
+            let (index, kind) = nodes[position];
+            let key = &candidates[position].key;
+            // NOTE: skips positions the policy returned twice
+            let Some((key, entry)) = self.data.remove_if(key, |_, entry| entry.index == index)
+            else {
+                continue;
+            };
+            let list = match kind {
+                ExpireKind::Idle => &mut idle,
+                ExpireKind::Used => &mut used,
+                ExpireKind::Evicted => unreachable!("Nodes are listed as idle or used"),
+            };
+            list.remove(index).expect("Invariant violated");
+            let expire = ImportantExpires {
+                key,
+                usages: entry.counter.load(Ordering::Relaxed),
+                kind,
+            };
+            self.expirations.send(expire.clone()).ok();
+            expires.push(expire);
+        }
+        (expires, more)
+    }
+
+    // Returns `false` when the entry is already past its idle expiry, it's evicted instead then
+    fn renew_idle<Q>(&self, key: &Q) -> bool
     where
-        K: Borrow<Q> + for<'a> From<&'a Q>,
+        K: Borrow<Q>,
         Q: Hash + Eq + ?Sized,
     {
         let mut idle = self.idle.lock().expect("Mutex poisoned");
         let Some(mut element) = self.data.get_mut(key) else {
-            // TODO: better logging
-            eprintln!("Sometimes unlucky");
-            return;
+            warn!("Entry was evicted before its idle timestamp could be renewed");
+            return true;
         };
-        let Some(Timed { value, .. }) = idle.remove(element.index) else {
+        let index = element.index;
+        let Some(node) = idle.get(index) else {
             assert!(*element.counter.get_mut() != 0, "Invariant violated");
-            return;
+            return true;
         };
+        if node.timestamp.elapsed() > self.idle_expire {
+            drop(element);
+            self.expire_idle_locked(&mut idle, key, index);
+            return false;
+        }
+        let Timed { value, .. } = idle.remove(index).expect("Invariant violated");
         element.index = idle.push_back(Timed::new(value));
+        true
+    }
+
+    // Lazy counterpart of `evict_expired` for a single idle entry, reported the same way
+    fn expire_idle_locked<Q>(&self, idle: &mut VecList<Timed<K>>, key: &Q, index: Index<Timed<K>>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        // NOTE: a concurrent sweep may have taken it already, then it also removes the node
+        let Some((key, _)) = self.data.remove_if(key, |_, entry| entry.index == index) else {
+            return;
+        };
+        idle.remove(index);
+        self.expirations
+            .send(ImportantExpires {
+                key,
+                usages: 0,
+                kind: ExpireKind::Idle,
+            })
+            .ok();
     }
 }
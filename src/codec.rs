@@ -0,0 +1,100 @@
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{ACCEPT, CONTENT_TYPE},
+        request::Parts,
+    },
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, de::DeserializeOwned};
+
+pub const MSGPACK_CONTENT_TYPE: &str = "application/msgpack";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    fn from_header(headers: &HeaderMap, name: impl axum::http::header::AsHeaderName) -> Self {
+        let Some(value) = headers.get(name).and_then(|value| value.to_str().ok()) else {
+            return Self::Json;
+        };
+        // NOTE: parameters and q-values are ignored, any MessagePack mention wins
+        let msgpack = value.split(',').any(|media_type| {
+            let essence = media_type.split(';').next().unwrap_or_default().trim();
+            essence.eq_ignore_ascii_case(MSGPACK_CONTENT_TYPE)
+                || essence.eq_ignore_ascii_case("application/x-msgpack")
+        });
+        if msgpack {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+}
+
+// Response format requested through the `Accept` header, JSON unless MessagePack is asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Accept(pub Format);
+
+impl<S: Send + Sync> FromRequestParts<S> for Accept {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Response> {
+        Ok(Self(Format::from_header(&parts.headers, ACCEPT)))
+    }
+}
+
+// Like `Json`, but also speaks MessagePack: decoded by `Content-Type`, encoded in the given format
+#[derive(Debug, Clone)]
+pub struct Codec<T>(pub Format, pub T);
+
+impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for Codec<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Response> {
+        match Format::from_header(req.headers(), CONTENT_TYPE) {
+            Format::Json => {
+                let Json(value) = Json::from_request(req, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                Ok(Self(Format::Json, value))
+            }
+            Format::MessagePack => {
+                let body = Bytes::from_request(req, state)
+                    .await
+                    .map_err(IntoResponse::into_response)?;
+                let value = rmp_serde::from_slice(&body).map_err(|err| {
+                    (
+                        StatusCode::BAD_REQUEST,
+                        format!("Invalid MessagePack: {err}"),
+                    )
+                        .into_response()
+                })?;
+                Ok(Self(Format::MessagePack, value))
+            }
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for Codec<T> {
+    fn into_response(self) -> Response {
+        match self {
+            Self(Format::Json, value) => Json(value).into_response(),
+            Self(Format::MessagePack, value) => match rmp_serde::to_vec_named(&value) {
+                Ok(body) => (
+                    [(CONTENT_TYPE, HeaderValue::from_static(MSGPACK_CONTENT_TYPE))],
+                    body,
+                )
+                    .into_response(),
+                Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+            },
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+};
+
+use clap::{Parser, ValueEnum};
+
+use crate::{
+    api::BodyLimits,
+    queue::{BackupCodec, TimeoutAction},
+    utils::{HttpPool, HttpTimeouts},
+};
+
+// Settings of the queue service, every flag can also be set through its environment variable
+#[derive(Debug, Clone, Parser)]
+#[command(name = "queues-demo")]
+pub struct Config {
+    #[arg(long, env = "QUEUE_PORT", default_value_t = 3000)]
+    pub port: u16,
+    #[arg(long, env = "QUEUE_DB_PATH", default_value = "queue.db")]
+    pub db_path: PathBuf,
+    /// Maximum number of timed out tasks reclaimed while holding the processing lock
+    #[arg(long, env = "QUEUE_TIMEOUT_SCAN_BATCH", default_value = "1000")]
+    pub timeout_scan_batch: NonZeroUsize,
+    /// How long a worker may hold a task without a heartbeat before it is requeued
+    #[arg(long, env = "QUEUE_EXEC_TIMEOUT_MS", default_value_t = 30_000)]
+    pub exec_timeout_ms: u64,
+    /// How long an exploit nobody uses stays cached
+    #[arg(long, env = "CACHE_IDLE_EXPIRE_MS", default_value_t = 30_000)]
+    pub cache_idle_expire_ms: u64,
+    /// How long an exploit stays cached while tasks still use it
+    #[arg(long, env = "CACHE_USED_EXPIRE_MS", default_value_t = 600_000)]
+    pub cache_used_expire_ms: u64,
+    /// Most exploits cached at once, the least recently used idle one is evicted to make room
+    #[arg(long, env = "CACHE_MAX_ENTRIES")]
+    pub cache_max_entries: Option<usize>,
+    #[arg(long, env = "CACHE_EXPIRE_SCAN_INTERVAL_MS", default_value_t = 10_000)]
+    pub cache_expire_scan_interval_ms: u64,
+    /// Maximum number of expired cache entries evicted in one go
+    #[arg(long, env = "CACHE_EXPIRE_SCAN_BATCH", default_value = "1000")]
+    pub cache_expire_scan_batch: NonZeroUsize,
+    /// How long add_task_sync waits for the task to be completed before answering 504
+    #[arg(long, env = "QUEUE_SYNC_TIMEOUT_MS", default_value_t = 60_000)]
+    pub sync_timeout_ms: u64,
+    #[arg(long, env = "QUEUE_MAX_SUBMISSION_ID_LEN", default_value_t = 256)]
+    pub max_submission_id_len: usize,
+    /// How long completion results stay retrievable through /queue/result
+    #[arg(long, env = "QUEUE_RESULT_TTL_MS", default_value_t = 600_000)]
+    pub result_ttl_ms: u64,
+    #[arg(long, env = "QUEUE_RESULT_CAPACITY", default_value_t = 10_000)]
+    pub result_capacity: usize,
+    /// How long add_task ignores a repeated idempotency_key
+    #[arg(long, env = "QUEUE_IDEMPOTENCY_WINDOW_MS", default_value_t = 60_000)]
+    pub idempotency_window_ms: u64,
+    #[arg(long, env = "QUEUE_IDEMPOTENCY_CAPACITY", default_value_t = 10_000)]
+    pub idempotency_capacity: usize,
+    #[arg(
+        long,
+        env = "EXPLOIT_STORAGE_URL",
+        default_value = "http://localhost:3001"
+    )]
+    pub exploit_storage_url: String,
+    /// Where completions of submissions without a callback_url are posted
+    #[arg(
+        long,
+        env = "COLLECTOR_URL",
+        default_value = "http://localhost:3002/submit"
+    )]
+    pub collector_url: String,
+    /// What to do with a task whose completion the collector or callback did not accept
+    #[arg(
+        long,
+        env = "COLLECTOR_FAILURE_POLICY",
+        value_enum,
+        default_value_t = CollectorFailurePolicy::Requeue
+    )]
+    pub collector_failure_policy: CollectorFailurePolicy,
+    /// Consecutive exploit storage failures after which fetches fail fast
+    #[arg(long, env = "EXPLOIT_BREAKER_THRESHOLD", default_value = "5")]
+    pub exploit_breaker_threshold: NonZeroU32,
+    /// How long fetches fail fast before a trial fetch is let through
+    #[arg(long, env = "EXPLOIT_BREAKER_COOLDOWN_MS", default_value_t = 10_000)]
+    pub exploit_breaker_cooldown_ms: u64,
+    #[command(flatten)]
+    pub http_timeouts: HttpTimeouts,
+    #[command(flatten)]
+    pub exploit_pool: HttpPool,
+    /// Flush every push to disk before acknowledging it, instead of flushing periodically
+    #[arg(long, env = "QUEUE_STRICT_DURABILITY")]
+    pub strict_durability: bool,
+    /// Dead letter tasks that time out this many times instead of requeueing them
+    #[arg(long, env = "QUEUE_MAX_ATTEMPTS")]
+    pub max_attempts: Option<NonZeroU32>,
+    /// Reclaim tasks this long after they were handed out, no matter how many heartbeats
+    #[arg(long, env = "QUEUE_MAX_LEASE_MS")]
+    pub max_lease_ms: Option<u64>,
+    /// Reject completions of tasks handed out or heartbeated longer ago than this
+    #[arg(long, env = "QUEUE_MAX_COMPLETION_AGE_MS")]
+    pub max_completion_age_ms: Option<u64>,
+    /// Maximum number of tasks handed out at once, get_task waits for a slot beyond that
+    #[arg(long, env = "QUEUE_MAX_IN_FLIGHT")]
+    pub max_in_flight: Option<NonZeroUsize>,
+    /// Number of recent task lifecycle events served by /queue/events
+    #[arg(long, env = "QUEUE_EVENT_LOG_CAPACITY", default_value_t = 1_000)]
+    pub event_log_capacity: usize,
+    /// Largest request body the queue routes accept, in bytes
+    #[arg(long, env = "QUEUE_MAX_BODY_BYTES", default_value_t = BodyLimits::default().default)]
+    pub max_body_bytes: usize,
+    /// Largest request body of queue/add_tasks, in bytes
+    #[arg(long, env = "QUEUE_MAX_BULK_BODY_BYTES", default_value_t = BodyLimits::default().bulk)]
+    pub max_bulk_body_bytes: usize,
+    /// Time limit of queue and cache requests, except the long polls of get_task and add_task_sync
+    #[arg(long, env = "QUEUE_REQUEST_TIMEOUT_MS", default_value_t = 30_000)]
+    pub request_timeout_ms: u64,
+    /// Encoding of new records in the queue database, records of either format are read back
+    #[arg(long, env = "QUEUE_BACKUP_FORMAT", value_enum, default_value_t = BackupFormat::Bincode)]
+    pub backup_format: BackupFormat,
+    /// Interval of the background flush in relaxed durability mode
+    #[arg(long, env = "QUEUE_FLUSH_INTERVAL_MS", default_value_t = 100)]
+    pub flush_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CollectorFailurePolicy {
+    /// Put the task back to pending, so another worker redoes it
+    Requeue,
+    /// Forget the task, its result only stays in /queue/result
+    Drop,
+    /// Keep the task in the dead letter queue
+    DeadLetter,
+}
+
+impl From<CollectorFailurePolicy> for TimeoutAction {
+    fn from(policy: CollectorFailurePolicy) -> Self {
+        match policy {
+            CollectorFailurePolicy::Requeue => Self::Requeue,
+            CollectorFailurePolicy::Drop => Self::Drop,
+            CollectorFailurePolicy::DeadLetter => Self::DeadLetter,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BackupFormat {
+    /// Compact binary records
+    Bincode,
+    /// JSON records, for inspecting the database with other tools
+    Json,
+}
+
+impl From<BackupFormat> for BackupCodec {
+    fn from(format: BackupFormat) -> Self {
+        match format {
+            BackupFormat::Bincode => Self::Bincode,
+            BackupFormat::Json => Self::Json,
+        }
+    }
+}
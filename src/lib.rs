@@ -1,37 +1,266 @@
 use api::QueueState;
-use axum::extract::FromRef;
+use axum::{
+    extract::FromRef,
+    http::{
+        StatusCode,
+        header::{ETAG, IF_NONE_MATCH},
+    },
+};
+use bytes::{Bytes, BytesMut};
 use cache::DataGetter;
-use std::sync::Arc;
+use std::{num::NonZeroU32, sync::Arc, time::Duration};
+use utils::CircuitBreaker;
 
 pub mod api;
 pub mod cache;
+pub mod codec;
+pub mod config;
 pub mod queue;
+pub mod results;
+pub mod sink;
+pub mod store;
 pub mod utils;
+pub mod worker;
 
-#[derive(Debug, Default)]
+const DEFAULT_MAX_PAYLOAD_BYTES: usize = 1 << 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct Exploit {
+    pub body: Arc<String>,
+    // Sent back as If-None-Match when the cached exploit is refreshed
+    pub etag: Option<String>,
+}
+
+// Exploit body as received, clones share the buffer
+#[derive(Debug, Clone, Default)]
+pub struct RawExploit {
+    pub body: Bytes,
+    pub etag: Option<String>,
+}
+
+// NOTE: invalid UTF-8 is replaced rather than rejected
+impl From<RawExploit> for Exploit {
+    fn from(raw: RawExploit) -> Self {
+        Self {
+            body: Arc::new(String::from_utf8_lossy(&raw.body).into_owned()),
+            etag: raw.etag,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    Request(reqwest::Error),
+    TooLarge { limit: usize },
+    // NOTE: the exploit storage failed too often recently, it was not asked
+    CircuitOpen,
+}
+
+impl FetchError {
+    // Failures blamed on the exploit storage itself, a missing exploit or an oversized one are not
+    fn is_upstream_failure(&self) -> bool {
+        match self {
+            Self::Request(err) => err.status().is_none_or(|status| status.is_server_error()),
+            Self::TooLarge { .. } | Self::CircuitOpen => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Request(err)
+    }
+}
+
+#[derive(Debug)]
 pub struct GetterStub {
     client: reqwest::Client,
+    base_url: String,
+    max_payload_bytes: usize,
+    breaker: Option<CircuitBreaker>,
+}
+
+impl GetterStub {
+    pub fn new(client: reqwest::Client, base_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            base_url: base_url.into(),
+            max_payload_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+            breaker: None,
+        }
+    }
+
+    pub fn with_max_payload_bytes(mut self, max_payload_bytes: usize) -> Self {
+        self.max_payload_bytes = max_payload_bytes;
+        self
+    }
+
+    // Fails fetches with `FetchError::CircuitOpen` after `threshold` consecutive upstream failures,
+    // until a trial fetch after `cooldown` succeeds
+    pub fn with_circuit_breaker(mut self, threshold: NonZeroU32, cooldown: Duration) -> Self {
+        self.breaker = Some(CircuitBreaker::new(threshold, cooldown));
+        self
+    }
+
+    async fn guarded<T>(
+        &self,
+        fetch: impl Future<Output = Result<T, FetchError>>,
+    ) -> Result<T, FetchError> {
+        let Some(breaker) = &self.breaker else {
+            return fetch.await;
+        };
+        if !breaker.try_acquire() {
+            return Err(FetchError::CircuitOpen);
+        }
+        let res = fetch.await;
+        breaker.record(!res.as_ref().is_err_and(FetchError::is_upstream_failure));
+        res
+    }
 }
 
 impl DataGetter for GetterStub {
     type Key = String;
     type BorrowedKey = str;
-    type Value = Arc<String>;
-    async fn get(&self, key: &str) -> Arc<String> {
-        // TODO: this shouldn't panic silently
-        Arc::new(
-            self.client
-                .get(format!("http://localhost:3001/get_exploit/{key}"))
-                .send()
-                .await
-                .unwrap()
-                .error_for_status()
-                .unwrap()
-                .text()
-                .await
-                .unwrap(),
-        )
+    type Value = Exploit;
+    type Error = FetchError;
+    async fn get(&self, key: &str) -> Result<Exploit, FetchError> {
+        let url = format!("{}/get_exploit/{key}", self.base_url);
+        self.guarded(fetch_exploit(&self.client, &url, self.max_payload_bytes))
+            .await
+    }
+    async fn refresh(&self, key: &str, current: &Exploit) -> Result<Option<Exploit>, FetchError> {
+        let url = format!("{}/get_exploit/{key}", self.base_url);
+        self.guarded(refresh_exploit(
+            &self.client,
+            &url,
+            self.max_payload_bytes,
+            current.etag.as_deref(),
+        ))
+        .await
+    }
+}
+
+// `GetterStub` caching the exploits as received, for callers that only forward the bytes and
+// shouldn't pay for a decoded copy
+#[derive(Debug)]
+pub struct RawGetterStub(pub GetterStub);
+
+impl DataGetter for RawGetterStub {
+    type Key = String;
+    type BorrowedKey = str;
+    type Value = RawExploit;
+    type Error = FetchError;
+    async fn get(&self, key: &str) -> Result<RawExploit, FetchError> {
+        let getter = &self.0;
+        let url = format!("{}/get_exploit/{key}", getter.base_url);
+        let fetch = fetch_exploit(&getter.client, &url, getter.max_payload_bytes);
+        getter.guarded(fetch).await
+    }
+    async fn refresh(
+        &self,
+        key: &str,
+        current: &RawExploit,
+    ) -> Result<Option<RawExploit>, FetchError> {
+        let getter = &self.0;
+        let url = format!("{}/get_exploit/{key}", getter.base_url);
+        let etag = current.etag.as_deref();
+        let refresh = refresh_exploit(&getter.client, &url, getter.max_payload_bytes, etag);
+        getter.guarded(refresh).await
+    }
+}
+
+// Fetches exploits of the numeric submissions generated by the client (`task{:x}`)
+#[derive(Debug)]
+pub struct NumericGetterStub {
+    client: reqwest::Client,
+    base_url: String,
+    max_payload_bytes: usize,
+}
+
+impl NumericGetterStub {
+    pub fn new(client: reqwest::Client, base_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            base_url: base_url.into(),
+            max_payload_bytes: DEFAULT_MAX_PAYLOAD_BYTES,
+        }
+    }
+
+    pub fn with_max_payload_bytes(mut self, max_payload_bytes: usize) -> Self {
+        self.max_payload_bytes = max_payload_bytes;
+        self
+    }
+}
+
+impl DataGetter for NumericGetterStub {
+    type Key = u64;
+    type BorrowedKey = u64;
+    type Value = Exploit;
+    type Error = FetchError;
+    async fn get(&self, key: &u64) -> Result<Exploit, FetchError> {
+        let url = format!("{}/get_exploit/task{key:x}", self.base_url);
+        fetch_exploit(&self.client, &url, self.max_payload_bytes).await
+    }
+    async fn refresh(&self, key: &u64, current: &Exploit) -> Result<Option<Exploit>, FetchError> {
+        let url = format!("{}/get_exploit/task{key:x}", self.base_url);
+        let etag = current.etag.as_deref();
+        refresh_exploit(&self.client, &url, self.max_payload_bytes, etag).await
+    }
+}
+
+async fn fetch_exploit<E: From<RawExploit>>(
+    client: &reqwest::Client,
+    url: &str,
+    limit: usize,
+) -> Result<E, FetchError> {
+    let response = client.get(url).send().await?.error_for_status()?;
+    read_exploit(response, limit).await.map(E::from)
+}
+
+// `None` when the exploit storage confirms the cached exploit with 304 Not Modified
+async fn refresh_exploit<E: From<RawExploit>>(
+    client: &reqwest::Client,
+    url: &str,
+    limit: usize,
+    etag: Option<&str>,
+) -> Result<Option<E>, FetchError> {
+    let mut request = client.get(url);
+    if let Some(etag) = etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    let response = request.send().await?.error_for_status()?;
+    if etag.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    read_exploit(response, limit).await.map(|raw| Some(E::from(raw)))
+}
+
+// Reads at most `limit` bytes of the body into a single buffer
+async fn read_exploit(
+    mut response: reqwest::Response,
+    limit: usize,
+) -> Result<RawExploit, FetchError> {
+    let content_length = response.content_length();
+    if content_length.is_some_and(|len| len > limit as u64) {
+        return Err(FetchError::TooLarge { limit });
+    }
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_owned);
+    // NOTE: sized up front when the length is known, so the chunks are copied only once
+    let mut body = BytesMut::with_capacity(content_length.map_or(0, |len| len as usize));
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            return Err(FetchError::TooLarge { limit });
+        }
+        body.extend_from_slice(&chunk);
     }
+    Ok(RawExploit {
+        body: body.freeze(),
+        etag,
+    })
 }
 
 impl FromRef<AppState> for Arc<CacheState> {
@@ -46,7 +275,7 @@ impl FromRef<AppState> for Arc<QueueState> {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct CacheState {
     pub exploits: cache::Cache<GetterStub, 30_000, 600_000>,
 }
@@ -1,52 +1,164 @@
 use std::{sync::Arc, time::Duration};
 
+use clap::Parser;
 use queues_demo::{
-    AppState, CacheState,
-    api::{MainQueue, QueueState},
+    AppState, CacheState, GetterStub,
+    api::{BodyLimits, MainQueue, QueueState, migrate_submission},
+    cache::{Cache, ExpireKind},
+    config::Config,
+    queue::Durability,
+    results::{ResultStore, SeenKeys},
+    sink::HttpSink,
+    store::SledStore,
 };
-use tokio::{select, time::sleep};
+use tokio::{select, sync::broadcast::error::RecvError, task::yield_now, time::sleep};
+use tracing::{debug, info, warn};
 
-async fn cache_collect_expires(state: Arc<CacheState>) -> ! {
+async fn cache_collect_expires(state: Arc<CacheState>, interval: Duration, batch: usize) -> ! {
     loop {
-        sleep(Duration::from_secs(10)).await;
-        let expires = state.exploits.evict_expired();
-        for expire in expires {
-            eprintln!(
-                "Cache \"bytecodes\": key {} expired while having {} usages",
-                expire.key, expire.usages
-            );
+        sleep(interval).await;
+        // NOTE: reported through subscribe_expirations, other tasks run between batches
+        while state.exploits.evict_expired_budget(batch).1 {
+            yield_now().await;
+        }
+    }
+}
+
+async fn cache_log_expires(state: Arc<CacheState>) -> ! {
+    let mut expirations = state.exploits.subscribe_expirations();
+    loop {
+        match expirations.recv().await {
+            Ok(expire) if expire.kind == ExpireKind::Idle => {
+                debug!(cache = "bytecodes", key = %expire.key, "Idle cache entry expired");
+            }
+            Ok(expire) if expire.kind == ExpireKind::Evicted => {
+                info!(cache = "bytecodes", key = %expire.key, "Cache entry evicted at capacity");
+            }
+            Ok(expire) => {
+                warn!(
+                    cache = "bytecodes",
+                    key = %expire.key,
+                    usages = expire.usages,
+                    "Cache entry expired while in use"
+                );
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(cache = "bytecodes", skipped, "Expirations were not logged");
+            }
+            Err(RecvError::Closed) => unreachable!("Cache outlives its subscribers"),
         }
     }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let db = sled::open("queue.db")?;
+async fn main() -> anyhow::Result<()> {
+    queues_demo::utils::init_tracing();
+    let cli = Config::parse();
+    let db = queues_demo::utils::open_db(&cli.db_path)?;
+    let durability = if cli.strict_durability {
+        Durability::Strict
+    } else {
+        Durability::Relaxed
+    };
+    let client = queues_demo::utils::build_pooled_client(&cli.http_timeouts, &cli.exploit_pool)?;
+    let store = SledStore::new(db);
+    let codec = cli.backup_format.into();
+    let mut queue = MainQueue::from_store_with_codec(store, migrate_submission, codec)
+        .with_durability(durability)
+        .with_event_log_capacity(cli.event_log_capacity)
+        .with_execution_timeout(Duration::from_millis(cli.exec_timeout_ms));
+    if let Some(max_attempts) = cli.max_attempts {
+        queue = queue.with_max_attempts(max_attempts);
+    }
+    if let Some(max_in_flight) = cli.max_in_flight {
+        queue = queue.with_max_in_flight(max_in_flight);
+    }
+    if let Some(max_lease_ms) = cli.max_lease_ms {
+        queue = queue.with_max_lease(Duration::from_millis(max_lease_ms));
+    }
+    if let Some(max_completion_age_ms) = cli.max_completion_age_ms {
+        queue = queue.with_max_completion_age(Duration::from_millis(max_completion_age_ms));
+    }
+    let mut exploits = Cache::new(
+        GetterStub::new(client.clone(), cli.exploit_storage_url).with_circuit_breaker(
+            cli.exploit_breaker_threshold,
+            Duration::from_millis(cli.exploit_breaker_cooldown_ms),
+        ),
+    )
+    .with_expiry(
+        Duration::from_millis(cli.cache_idle_expire_ms),
+        Duration::from_millis(cli.cache_used_expire_ms),
+    );
+    if let Some(max_entries) = cli.cache_max_entries {
+        exploits = exploits.with_max_entries(max_entries);
+    }
     let state = AppState {
         api: Arc::new(QueueState {
-            queue: MainQueue::new(db),
-            client: reqwest::Client::new(),
+            queue,
+            sync_timeout: Duration::from_millis(cli.sync_timeout_ms),
+            max_submission_id_len: cli.max_submission_id_len,
+            completions: Box::new(HttpSink::new(client, cli.collector_url)),
+            collector_failure: cli.collector_failure_policy.into(),
+            completion_waiters: Default::default(),
+            results: ResultStore::new(
+                Duration::from_millis(cli.result_ttl_ms),
+                cli.result_capacity,
+            ),
+            idempotency_keys: SeenKeys::new(
+                Duration::from_millis(cli.idempotency_window_ms),
+                cli.idempotency_capacity,
+            ),
         }),
-        cache: Arc::new(CacheState::default()),
+        cache: Arc::new(CacheState { exploits }),
     };
     let state_queue = state.api.clone();
     let state_cache = state.cache.clone();
 
+    let request_timeout = Duration::from_millis(cli.request_timeout_ms);
     let app = axum::Router::new()
-        .nest("/queue", queues_demo::api::routes())
+        .nest(
+            "/queue",
+            queues_demo::api::routes_with_limits(
+                BodyLimits {
+                    default: cli.max_body_bytes,
+                    bulk: cli.max_bulk_body_bytes,
+                },
+                request_timeout,
+            ),
+        )
+        .nest(
+            "/cache",
+            queues_demo::api::cache_routes_with_timeout(request_timeout),
+        )
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("[::]:3000").await?;
+    let listener = tokio::net::TcpListener::bind(("::", cli.port)).await?;
     let local_addr = listener.local_addr()?;
-    println!("listening on {}", local_addr);
+    info!(%local_addr, "Listening");
     select! {
         res = axum::serve(listener, app) => {
             res?;
         },
-        _ = queues_demo::api::queue_collect_timeouts(state_queue) => {
+        _ = queues_demo::api::queue_collect_timeouts(
+            state_queue.clone(),
+            cli.timeout_scan_batch.get(),
+        ) => {
+            unreachable!();
+        },
+        _ = queues_demo::api::queue_flush_periodically(
+            state_queue,
+            Duration::from_millis(cli.flush_interval_ms),
+        ), if durability == Durability::Relaxed => {
+            unreachable!();
+        },
+        _ = cache_collect_expires(
+            state_cache.clone(),
+            Duration::from_millis(cli.cache_expire_scan_interval_ms),
+            cli.cache_expire_scan_batch.get(),
+        ) => {
             unreachable!();
         },
-        _ = cache_collect_expires(state_cache) => {
+        _ = cache_log_expires(state_cache) => {
             unreachable!();
         },
     }
@@ -1,78 +1,571 @@
-use std::{collections::VecDeque, ops::Deref, sync::Mutex, time::Duration};
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::{self, Debug, Formatter},
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    num::{NonZeroU32, NonZeroUsize},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
 
-use dlv_list::VecList;
+use crossbeam_queue::SegQueue;
+use dlv_list::{Index, VecList};
+use futures::{Stream, StreamExt, stream};
 use serde::{Deserialize, Serialize, Serializer};
 use serde_with::SerializeAs;
-use tokio::{select, sync::Notify, time::sleep};
+use tokio::{
+    select,
+    sync::Notify,
+    time::{Instant, sleep, sleep_until},
+};
+use tracing::warn;
+use uuid::Uuid;
 
-use crate::utils::Timed;
+use crate::{
+    store::{SledStore, StoreWrite, Table, TaskStore},
+    utils::Timed,
+};
 
+// Task queue mirrored to a `TaskStore`, sled by default. The store is written before memory and
+// cleared after it, so a crash redelivers a task at worst, never loses it
 #[derive(Debug)]
-pub struct GenericTaskQueueWithBackup<T, const EXECUTION_TIMEOUT_MILLIS: u128> {
+pub struct GenericTaskQueueWithBackup<T, const EXECUTION_TIMEOUT_MILLIS: u128, S = SledStore> {
     queue: GenericTaskQueue<T, EXECUTION_TIMEOUT_MILLIS>,
-    db: sled::Db,
+    store: S,
+    // NOTE: store key of every task in memory, by the address of its `Arc`
+    keys: Mutex<HashMap<usize, u64>>,
+    durability: Durability,
+    codec: BackupCodec,
 }
 
-// TODO: Now it may fail on interaction with db
-impl<T: Serialize + for<'de> Deserialize<'de> + Clone, const ET: u128>
-    GenericTaskQueueWithBackup<T, ET>
-{
+// Encoding of the records written to the store. Records of either codec are read back, so it can
+// be switched on an existing store
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BackupCodec {
+    // `[QUEUE_FORMAT_VERSION][bincode task][attempts as a big endian u32]`
+    #[default]
+    Bincode,
+    // `{"task": ..., "attempts": ...}`, readable with any JSON tooling
+    Json,
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonRecord<T> {
+    task: T,
+    attempts: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    // Pushes return once they are flushed to disk, at the cost of a disk sync per push
+    Strict,
+    // Pushes return once they are written to sled's cache, disk is synced by `flush` calls and
+    // sled's own background flush
+    #[default]
+    Relaxed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitError {
+    NotFound,
+    AlreadyCompleted,
+    TimedOut,
+    Requeued,
+    Failed,
+    // NOTE: the task is still processing, it times out as usual
+    Stale,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutAction {
+    Requeue,
+    Drop,
+    DeadLetter,
+}
+
+// Bumped whenever the encoding of stored tasks changes, records of other versions go through the
+// migration passed to `new_with_migration`
+pub const QUEUE_FORMAT_VERSION: u8 = 2;
+
+// Decodes the payload of a record stored under another format version, `None` skips it
+pub type Migration<T> = fn(version: u8, payload: &[u8]) -> Option<T>;
+
+impl<T: Serialize + for<'de> Deserialize<'de>, const ET: u128> GenericTaskQueueWithBackup<T, ET> {
     pub fn new(db: sled::Db) -> Self {
-        let queue = GenericTaskQueue::default();
-        let x = Self { queue, db };
-        x.init_with_db();
+        Self::new_with_migration(db, |_, _| None)
+    }
+
+    pub fn new_with_migration(db: sled::Db, migrate: Migration<T>) -> Self {
+        Self::from_store_with_migration(SledStore::new(db), migrate)
+    }
+
+    pub fn new_with_codec(db: sled::Db, codec: BackupCodec) -> Self {
+        Self::from_store_with_codec(SledStore::new(db), |_, _| None, codec)
+    }
+}
+
+impl<T, const ET: u128, S> GenericTaskQueueWithBackup<T, ET, S>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+    S: TaskStore,
+{
+    // Restores the tasks already in `store`
+    pub fn from_store(store: S) -> Self {
+        Self::from_store_with_migration(store, |_, _| None)
+    }
+
+    pub fn from_store_with_migration(store: S, migrate: Migration<T>) -> Self {
+        Self::from_store_with_codec(store, migrate, BackupCodec::default())
+    }
+
+    // NOTE: records rewritten while restoring are written with `codec` too
+    pub fn from_store_with_codec(store: S, migrate: Migration<T>, codec: BackupCodec) -> Self {
+        let x = Self {
+            queue: GenericTaskQueue::default(),
+            store,
+            keys: Mutex::default(),
+            durability: Durability::default(),
+            codec,
+        };
+        x.init_with_db(migrate);
         x
     }
 
-    fn init_with_db(&self) {
-        for item in self.db.iter() {
-            let (item, _) = item.unwrap();
-            let (task, _): (T, _) = bincode::serde::decode_from_slice(&item, bincode::config::standard()).unwrap();
-            self.queue.push(task);
+    fn init_with_db(&self, migrate: Migration<T>) {
+        for (key, task, attempts) in self.restore(Table::Tasks, migrate) {
+            let task = Arc::new(task);
+            self.track(&task, key);
+            self.queue.push_queued(Queued {
+                value: task,
+                attempts,
+            });
+        }
+        for (key, task, _) in self.restore(Table::DeadLetter, migrate) {
+            let task = Arc::new(task);
+            self.track(&task, key);
+            self.queue
+                .dead_letter
+                .lock()
+                .expect("Mutex poisoned")
+                .push(task);
         }
     }
 
-    pub fn push(&self, item: T) {
-        self.queue.push(item.clone());
-        self.db
-            .insert(bincode::serde::encode_to_vec(&item, bincode::config::standard()).unwrap(), &[])
+    // NOTE: records that can't be decoded are left on disk untouched for manual inspection
+    fn restore(&self, table: Table, migrate: Migration<T>) -> Vec<(u64, T, u32)> {
+        let records = self.store.records(table);
+        let mut tasks = vec![];
+        for (key, value) in records {
+            // NOTE: records of the old layout had the encoded task as the key and no value, they
+            // are moved to a fresh sequential key in the order the store returns them
+            let legacy = value.is_empty();
+            // NOTE: no format version is a `{`, so JSON records can't be mistaken for bincode ones
+            if value.first() == Some(&b'{') {
+                let Ok(seq) = <[u8; 8]>::try_from(&*key) else {
+                    warn!(?table, "Skipping record with a malformed key");
+                    continue;
+                };
+                let record: JsonRecord<T> = match serde_json::from_slice(&value) {
+                    Ok(record) => record,
+                    Err(err) => {
+                        warn!(?table, %err, "Skipping undecodable JSON record");
+                        continue;
+                    }
+                };
+                tasks.push((u64::from_be_bytes(seq), record.task, record.attempts));
+                continue;
+            }
+            let record = if legacy { &key } else { &value };
+            let Some((&version, payload)) = record.split_first() else {
+                warn!(?table, "Skipping empty record");
+                continue;
+            };
+            // NOTE: records written before attempts were stored end right after the task
+            let (task, attempts, rewrite) = if version == QUEUE_FORMAT_VERSION {
+                match bincode::serde::decode_from_slice(payload, bincode::config::standard()) {
+                    Ok((task, len)) => match payload[len..] {
+                        [] => (task, 0, true),
+                        [a, b, c, d] => (task, u32::from_be_bytes([a, b, c, d]), false),
+                        _ => {
+                            warn!(?table, "Skipping record with trailing bytes");
+                            continue;
+                        }
+                    },
+                    Err(err) => {
+                        warn!(?table, %err, "Skipping undecodable record");
+                        continue;
+                    }
+                }
+            } else {
+                let Some(task) = migrate(version, payload) else {
+                    warn!(?table, version, "Skipping record of unknown format version");
+                    continue;
+                };
+                (task, 0, true)
+            };
+            let seq = if legacy {
+                let seq = self.next_key();
+                let writes = vec![
+                    StoreWrite::Remove(key),
+                    StoreWrite::Insert(seq.to_be_bytes().to_vec(), self.encode(&task, attempts)),
+                ];
+                self.store.apply(table, writes);
+                seq
+            } else {
+                let Ok(seq) = <[u8; 8]>::try_from(&*key) else {
+                    warn!(?table, "Skipping record with a malformed key");
+                    continue;
+                };
+                if rewrite {
+                    let write = StoreWrite::Insert(key, self.encode(&task, attempts));
+                    self.store.apply(table, vec![write]);
+                }
+                u64::from_be_bytes(seq)
+            };
+            tasks.push((seq, task, attempts));
+        }
+        tasks
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    // Returns the number of bytes the store wrote to disk
+    pub async fn flush(&self) -> usize {
+        self.store.flush().await
+    }
+
+    async fn flush_if_strict(&self) {
+        if self.durability == Durability::Strict {
+            self.flush().await;
+        }
+    }
+
+    // The task with the number of times it was popped, see `BackupCodec`
+    fn encode(&self, task: &T, attempts: u32) -> Vec<u8> {
+        if self.codec == BackupCodec::Json {
+            return serde_json::to_vec(&JsonRecord { task, attempts }).unwrap();
+        }
+        let mut value = vec![QUEUE_FORMAT_VERSION];
+        bincode::serde::encode_into_std_write(task, &mut value, bincode::config::standard())
             .unwrap();
+        value.extend(attempts.to_be_bytes());
+        value
+    }
+
+    // NOTE: store ids only grow, across restarts too, so keys sort in push order
+    fn next_key(&self) -> u64 {
+        self.store.generate_id()
+    }
+
+    fn track(&self, task: &Arc<T>, key: u64) {
+        let mut keys = self.keys.lock().expect("Mutex poisoned");
+        keys.insert(Arc::as_ptr(task).addr(), key);
+    }
+
+    fn record_key(&self, task: &T) -> [u8; 8] {
+        self.tracked_key(task).expect("Untracked task")
+    }
+
+    fn tracked_key(&self, task: &T) -> Option<[u8; 8]> {
+        let keys = self.keys.lock().expect("Mutex poisoned");
+        let key = keys.get(&std::ptr::from_ref(task).addr())?;
+        Some(key.to_be_bytes())
+    }
+
+    // NOTE: a record removed or dead lettered since the pop stays absent, the update is atomic
+    fn persist_attempts(&self, task: &T, attempts: u32) {
+        let Some(key) = self.tracked_key(task) else {
+            return;
+        };
+        self.store.update(&key, &mut |value| {
+            if value.first() == Some(&b'{') {
+                let mut record: serde_json::Value = serde_json::from_slice(value).unwrap();
+                record["attempts"] = attempts.into();
+                return serde_json::to_vec(&record).unwrap();
+            }
+            let mut value = value.to_vec();
+            let at = value.len() - size_of::<u32>();
+            value[at..].copy_from_slice(&attempts.to_be_bytes());
+            value
+        });
     }
 
-    pub async fn pop_with_timeout(&self, timeout: Duration) -> Option<(T, TaskId<T>)> {
-        self.queue.pop_with_timeout(timeout).await
+    // Removes the record of a task that left memory for good
+    fn forget(&self, task: &T) {
+        let key = {
+            let mut keys = self.keys.lock().expect("Mutex poisoned");
+            keys.remove(&std::ptr::from_ref(task).addr())
+                .expect("Untracked task")
+        };
+        let write = StoreWrite::Remove(key.to_be_bytes().to_vec());
+        self.store.apply(Table::Tasks, vec![write]);
     }
 
-    pub fn submit_completed(&self, id: &TaskId<T>) -> Option<T> {
+    pub async fn push(&self, item: T) {
+        let key = self.next_key();
+        let task = Arc::new(item);
+        let write = StoreWrite::Insert(key.to_be_bytes().to_vec(), self.encode(&task, 0));
+        self.store.apply(Table::Tasks, vec![write]);
+        self.flush_if_strict().await;
+        self.track(&task, key);
+        self.queue.push_queued(Queued::new(task));
+    }
+
+    pub async fn push_many(&self, items: Vec<T>) {
+        let mut writes = Vec::with_capacity(items.len());
+        let mut tasks = Vec::with_capacity(items.len());
+        for item in items {
+            let key = self.next_key();
+            let task = Arc::new(item);
+            writes.push(StoreWrite::Insert(
+                key.to_be_bytes().to_vec(),
+                self.encode(&task, 0),
+            ));
+            tasks.push((key, task));
+        }
+        self.store.apply(Table::Tasks, writes);
+        self.flush_if_strict().await;
+        for (key, task) in &tasks {
+            self.track(task, *key);
+        }
+        self.queue
+            .push_many_arcs(tasks.into_iter().map(|(_, task)| task).collect());
+    }
+
+    pub async fn pop_with_timeout(&self, timeout: Duration) -> Option<(Arc<T>, TaskId<T>)> {
+        self.pop_with_execution_timeout(timeout, self.queue.execution_timeout)
+            .await
+    }
+
+    pub fn stream(&self) -> impl Stream<Item = (Arc<T>, TaskId<T>)> + '_ {
+        stream::repeat(()).filter_map(move |()| self.pop_with_timeout(STREAM_POLL_TIMEOUT))
+    }
+
+    // See `GenericTaskQueue::pop_with_attempt`
+    pub async fn pop_with_attempt(&self, timeout: Duration) -> Option<(Arc<T>, TaskId<T>, u32)> {
+        self.pop_entry(timeout, self.queue.execution_timeout).await
+    }
+
+    pub async fn pop_with_execution_timeout(
+        &self,
+        timeout: Duration,
+        execution_timeout: Duration,
+    ) -> Option<(Arc<T>, TaskId<T>)> {
+        let (task, id, _) = self.pop_entry(timeout, execution_timeout).await?;
+        Some((task, id))
+    }
+
+    // NOTE: the attempt count is written to the record, so it survives a restart
+    async fn pop_entry(
+        &self,
+        timeout: Duration,
+        execution_timeout: Duration,
+    ) -> Option<(Arc<T>, TaskId<T>, u32)> {
+        let (task, id, attempt) = self.queue.pop_entry(timeout, execution_timeout).await?;
+        self.persist_attempts(&task, attempt);
+        Some((task, id, attempt))
+    }
+
+    pub fn submit_completed(&self, id: &TaskId<T>) -> Result<Arc<T>, SubmitError> {
         let res = self.queue.submit_completed(id);
-        if let Some(task) = &res {
-            self.db.remove(bincode::serde::encode_to_vec(task, bincode::config::standard()).unwrap()).unwrap();
+        if let Ok(task) = &res {
+            self.forget(task);
         }
         res
     }
 
+    // Keeps the stored record until `inspect` returns, a panic or drop requeues as an attempt
     pub async fn submit_completed_with_inspect<R>(
         &self,
         id: &TaskId<T>,
-        inspect: impl AsyncFnOnce(Option<T>) -> R,
+        inspect: impl AsyncFnOnce(Result<Arc<T>, SubmitError>) -> R,
+    ) -> R {
+        self.submit_completed_with_reclaim(id, async |entry| (inspect(entry).await, None))
+            .await
+    }
+
+    // Like `submit_completed_with_inspect`, but `inspect` may also refuse the completion, then the
+    // task is requeued, dropped or dead lettered as if it timed out
+    pub async fn submit_completed_with_reclaim<R>(
+        &self,
+        id: &TaskId<T>,
+        inspect: impl AsyncFnOnce(Result<Arc<T>, SubmitError>) -> (R, Option<TimeoutAction>),
     ) -> R {
-        match self.queue.submit_completed(id) {
-            Some(task) => {
-                let key = bincode::serde::encode_to_vec(&task, bincode::config::standard()).unwrap();
-                let res = inspect(Some(task)).await;
-                self.db.remove(key).unwrap();
+        match self.queue.submit_completed_entry(id) {
+            Ok((task, attempts)) => {
+                let mut guard = RequeueOnDrop {
+                    queue: self,
+                    task: Some((task.clone(), attempts)),
+                };
+                let (res, reclaim) = inspect(Ok(task.clone())).await;
+                guard.task = None;
+                match reclaim {
+                    Some(action) => self.reclaim_completed(task, attempts, action),
+                    None => self.forget(&task),
+                }
                 res
             }
-            None => inspect(None).await,
+            Err(err) => inspect(Err(err)).await.0,
         }
     }
 
+    // See `GenericTaskQueue::with_max_attempts`
+    pub fn with_max_attempts(mut self, max_attempts: NonZeroU32) -> Self {
+        self.queue = self.queue.with_max_attempts(max_attempts);
+        self
+    }
+
+    // See `GenericTaskQueue::with_max_lease`
+    pub fn with_max_lease(mut self, max_lease: Duration) -> Self {
+        self.queue = self.queue.with_max_lease(max_lease);
+        self
+    }
+
+    // See `GenericTaskQueue::with_max_completion_age`
+    pub fn with_max_completion_age(mut self, max_age: Duration) -> Self {
+        self.queue = self.queue.with_max_completion_age(max_age);
+        self
+    }
+
+    pub fn heartbeat(&self, id: &TaskId<T>) -> Result<(), SubmitError> {
+        self.queue.heartbeat(id)
+    }
+
+    // See `GenericTaskQueue::fail`
+    pub fn fail(
+        &self,
+        id: &TaskId<T>,
+        action: TimeoutAction,
+    ) -> Result<TimeoutAction, SubmitError> {
+        self.queue.fail_with_settle(id, action, |task, action| {
+            self.settle_reclaimed(task, action)
+        })
+    }
+
+    // NOTE: the stored record stays as is, requeued tasks are still pending on disk
+    pub fn requeue_processing(&self, id: &TaskId<T>) -> Result<(), SubmitError> {
+        self.queue.requeue_processing(id)
+    }
+
+    pub fn reclaim_all_processing(&self) -> usize {
+        self.queue.reclaim_all_processing()
+    }
+
+    pub fn processing_time_stats(&self) -> Option<ProcessingTimeStats> {
+        self.queue.processing_time_stats()
+    }
+
+    // See `GenericTaskQueue::with_execution_timeout`
+    pub fn with_execution_timeout(mut self, execution_timeout: Duration) -> Self {
+        self.queue = self.queue.with_execution_timeout(execution_timeout);
+        self
+    }
+
+    // See `GenericTaskQueue::with_max_in_flight`
+    pub fn with_max_in_flight(mut self, max_in_flight: NonZeroUsize) -> Self {
+        self.queue = self.queue.with_max_in_flight(max_in_flight);
+        self
+    }
+
+    // See `GenericTaskQueue::with_event_log_capacity`
+    pub fn with_event_log_capacity(mut self, capacity: usize) -> Self {
+        self.queue = self.queue.with_event_log_capacity(capacity);
+        self
+    }
+
+    pub fn events(&self) -> Vec<TaskEvent<T>> {
+        self.queue.events()
+    }
+
+    pub fn next_timeout_deadline(&self) -> Option<Instant> {
+        self.queue.next_timeout_deadline()
+    }
+
+    // See `GenericTaskQueue::wait_next_timeout`
+    pub async fn wait_next_timeout(&self) {
+        self.queue.wait_next_timeout().await;
+    }
+
     pub fn process_timeouts(&self) {
-        self.queue.process_timeouts();
+        self.process_timeouts_with_inspect(|_, _| TimeoutAction::Requeue);
     }
 
-    pub fn process_timeouts_with_inspect(&self, inspect: impl Fn(TaskId<T>, &T)) {
-        self.queue.process_timeouts_with_inspect(inspect);
+    pub fn process_timeouts_with_inspect(&self, inspect: impl Fn(TaskId<T>, &T) -> TimeoutAction) {
+        self.process_timeouts_batch_with_inspect(usize::MAX, inspect);
+    }
+
+    pub fn process_timeouts_batch_with_inspect(
+        &self,
+        batch: usize,
+        inspect: impl Fn(TaskId<T>, &T) -> TimeoutAction,
+    ) -> bool {
+        self.queue
+            .reclaim_timed_out(batch, inspect, |task, action| {
+                self.settle_reclaimed(task, action)
+            })
+    }
+
+    fn reclaim_completed(&self, task: Arc<T>, attempts: u32, action: TimeoutAction) {
+        self.queue
+            .reclaim_completed(task, attempts, action, |task, action| {
+                self.settle_reclaimed(task, action)
+            });
+    }
+
+    fn settle_reclaimed(&self, task: &T, action: TimeoutAction) {
+        match action {
+            TimeoutAction::Requeue => {}
+            TimeoutAction::Drop => self.forget(task),
+            // NOTE: the record moves under the same key, the task stays tracked
+            TimeoutAction::DeadLetter => {
+                self.store.move_to_dead_letter(&self.record_key(task));
+            }
+        }
+    }
+
+    pub fn drain_pending(&self) -> Vec<Arc<T>> {
+        let tasks = self.queue.drain_pending();
+        let mut writes = Vec::with_capacity(tasks.len());
+        {
+            let mut keys = self.keys.lock().expect("Mutex poisoned");
+            for task in &tasks {
+                let key = keys
+                    .remove(&Arc::as_ptr(task).addr())
+                    .expect("Untracked task");
+                writes.push(StoreWrite::Remove(key.to_be_bytes().to_vec()));
+            }
+        }
+        self.store.apply(Table::Tasks, writes);
+        tasks
+    }
+
+    // NOTE: only the records of the taken tasks are removed, a task dead lettered meanwhile keeps
+    // its own
+    pub fn take_dead_letter(&self) -> Vec<Arc<T>> {
+        let tasks = self.queue.take_dead_letter();
+        let mut writes = Vec::with_capacity(tasks.len());
+        {
+            let mut keys = self.keys.lock().expect("Mutex poisoned");
+            for task in &tasks {
+                let key = keys
+                    .remove(&Arc::as_ptr(task).addr())
+                    .expect("Untracked task");
+                writes.push(StoreWrite::Remove(key.to_be_bytes().to_vec()));
+            }
+        }
+        self.store.apply(Table::DeadLetter, writes);
+        tasks
     }
 
     pub fn len_pending(&self) -> usize {
@@ -82,45 +575,353 @@ impl<T: Serialize + for<'de> Deserialize<'de> + Clone, const ET: u128>
     pub fn len_processing(&self) -> usize {
         self.queue.len_processing()
     }
+
+    pub fn len_dead_letter(&self) -> usize {
+        self.queue.len_dead_letter()
+    }
+
+    pub fn shrink_processing(&self) {
+        self.queue.shrink_processing();
+    }
+
+    pub fn capacity_processing(&self) -> usize {
+        self.queue.capacity_processing()
+    }
 }
 
+// NOTE: counts as a failed attempt, so a completion that keeps failing ends in dead letter
+struct RequeueOnDrop<'a, T, const ET: u128, S>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+    S: TaskStore,
+{
+    queue: &'a GenericTaskQueueWithBackup<T, ET, S>,
+    task: Option<(Arc<T>, u32)>,
+}
+
+impl<T, const ET: u128, S> Drop for RequeueOnDrop<'_, T, ET, S>
+where
+    T: Serialize + for<'de> Deserialize<'de>,
+    S: TaskStore,
+{
+    fn drop(&mut self) {
+        if let Some((task, attempts)) = self.task.take() {
+            warn!("Completion was interrupted, requeueing the task");
+            self.queue
+                .reclaim_completed(task, attempts, TimeoutAction::Requeue);
+        }
+    }
+}
+
+// In-memory task queue, popped in push completion order with requeued tasks going to the back
 #[derive(Debug)]
 pub struct GenericTaskQueue<T, const EXECUTION_TIMEOUT_MILLIS: u128> {
     notify_incoming: Notify,
+    // NOTE: only notified with `max_in_flight` set, once per task leaving processing
+    notify_slot_freed: Notify,
+    // NOTE: notified on every pop, the new deadline may be the soonest
+    notify_deadline: Notify,
+    // NOTE: lock-free, so pushes from many clients don't serialize on a mutex
+    pending: SegQueue<Queued<T>>,
     // NOTE: lock in order of definition
-    pending: Mutex<VecDeque<T>>,
-    processing: Mutex<VecList<Timed<T>>>,
+    processing: Mutex<Processing<T>>,
+    // NOTE: mirrors `processing.tasks.len()`, changed under the processing lock but read without it
+    processing_len: AtomicUsize,
+    // NOTE: recently removed processing ids, to tell why a completion missed
+    retired: Mutex<VecDeque<(TaskId<T>, SubmitError)>>,
+    dead_letter: Mutex<Vec<Arc<T>>>,
+    max_attempts: Option<NonZeroU32>,
+    max_lease: Option<Duration>,
+    max_completion_age: Option<Duration>,
+    max_in_flight: Option<NonZeroUsize>,
+    // NOTE: `EXECUTION_TIMEOUT_MILLIS` unless overridden at runtime
+    execution_timeout: Duration,
+    processing_times: ProcessingTimes,
+    // NOTE: most recent last, empty while `event_log_capacity` is 0
+    events: Mutex<VecDeque<TaskEvent<T>>>,
+    event_log_capacity: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskEventKind {
+    Pushed,
+    Popped,
+    Completed,
+    TimedOut,
+    Requeued,
+    Failed,
+}
+
+#[derive(Debug)]
+pub struct TaskEvent<T> {
+    pub kind: TaskEventKind,
+    // NOTE: processing id, tasks have none while pending
+    pub id: Option<TaskId<T>>,
+    pub task: Arc<T>,
+    pub at: SystemTime,
+}
+
+impl<T> Clone for TaskEvent<T> {
+    fn clone(&self) -> Self {
+        Self {
+            kind: self.kind,
+            id: self.id,
+            task: self.task.clone(),
+            at: self.at,
+        }
+    }
+}
+
+// Time from pop to completion of completed tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessingTimeStats {
+    pub count: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub avg: Duration,
+}
+
+// NOTE: each counter is updated on its own, a concurrent read may see a completion half recorded
+#[derive(Debug)]
+struct ProcessingTimes {
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+    min_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl Default for ProcessingTimes {
+    fn default() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+            min_micros: AtomicU64::new(u64::MAX),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ProcessingTimes {
+    fn record(&self, elapsed: Duration) {
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.min_micros.fetch_min(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> Option<ProcessingTimeStats> {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return None;
+        }
+        Some(ProcessingTimeStats {
+            count,
+            min: Duration::from_micros(self.min_micros.load(Ordering::Relaxed)),
+            max: Duration::from_micros(self.max_micros.load(Ordering::Relaxed)),
+            avg: Duration::from_micros(self.sum_micros.load(Ordering::Relaxed) / count),
+        })
+    }
+}
+
+const RETIRED_HISTORY: usize = 1024;
+// NOTE: only bounds a single wait of the stream, the stream itself never ends
+const STREAM_POLL_TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Debug)]
+struct Queued<T> {
+    value: Arc<T>,
+    // NOTE: times the task has been popped before
+    attempts: u32,
+}
+
+impl<T> Queued<T> {
+    fn new(value: Arc<T>) -> Self {
+        Self { value, attempts: 0 }
+    }
 }
 
 impl<T, const ET: u128> Default for GenericTaskQueue<T, ET> {
     fn default() -> Self {
         Self {
             notify_incoming: Notify::new(),
-            pending: Mutex::new(VecDeque::new()),
-            processing: Mutex::new(VecList::new()),
+            notify_slot_freed: Notify::new(),
+            notify_deadline: Notify::new(),
+            pending: SegQueue::new(),
+            processing: Mutex::new(Processing::default()),
+            processing_len: AtomicUsize::new(0),
+            retired: Mutex::new(VecDeque::new()),
+            dead_letter: Mutex::new(Vec::new()),
+            max_attempts: None,
+            max_lease: None,
+            max_completion_age: None,
+            max_in_flight: None,
+            execution_timeout: Duration::from_millis(ET as u64),
+            processing_times: ProcessingTimes::default(),
+            events: Mutex::new(VecDeque::new()),
+            event_log_capacity: 0,
         }
     }
 }
 
-impl<T: Clone, const EXECUTION_TIMEOUT_MILLIS: u128> GenericTaskQueue<T, EXECUTION_TIMEOUT_MILLIS> {
+impl<T, const EXECUTION_TIMEOUT_MILLIS: u128> GenericTaskQueue<T, EXECUTION_TIMEOUT_MILLIS> {
+    // A task that timed out on its `max_attempts`th pop is dead lettered instead of requeued
+    pub fn with_max_attempts(mut self, max_attempts: NonZeroU32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    // Hard cap on the time since a task was popped, heartbeats can't extend it past that
+    pub fn with_max_lease(mut self, max_lease: Duration) -> Self {
+        self.max_lease = Some(max_lease);
+        self
+    }
+
+    // Completions of tasks not popped or heartbeated for longer than `max_age` are rejected with
+    // `SubmitError::Stale`
+    pub fn with_max_completion_age(mut self, max_age: Duration) -> Self {
+        self.max_completion_age = Some(max_age);
+        self
+    }
+
+    // Replaces `EXECUTION_TIMEOUT_MILLIS` as the execution timeout of `pop_with_timeout`
+    pub fn with_execution_timeout(mut self, execution_timeout: Duration) -> Self {
+        self.execution_timeout = execution_timeout;
+        self
+    }
+
+    // Pops wait, up to their timeout, while this many tasks are processing
+    pub fn with_max_in_flight(mut self, max_in_flight: NonZeroUsize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    // Called under the processing lock for every task leaving processing
+    fn free_slots(&self, count: usize) {
+        self.processing_len.fetch_sub(count, Ordering::Relaxed);
+        if self.max_in_flight.is_none() {
+            return;
+        }
+        for _ in 0..count {
+            self.notify_slot_freed.notify_one();
+        }
+    }
+
+    // Keeps the last `capacity` lifecycle events for `events`, 0 disables the log
+    pub fn with_event_log_capacity(mut self, capacity: usize) -> Self {
+        self.event_log_capacity = capacity;
+        self
+    }
+
+    pub fn events(&self) -> Vec<TaskEvent<T>> {
+        let events = self.events.lock().expect("Mutex poisoned");
+        events.iter().cloned().collect()
+    }
+
+    fn record_event(&self, kind: TaskEventKind, id: Option<TaskId<T>>, task: &Arc<T>) {
+        if self.event_log_capacity == 0 {
+            return;
+        }
+        let mut events = self.events.lock().expect("Mutex poisoned");
+        if events.len() == self.event_log_capacity {
+            events.pop_front();
+        }
+        events.push_back(TaskEvent {
+            kind,
+            id,
+            task: task.clone(),
+            at: SystemTime::now(),
+        });
+    }
+
     pub fn push(&self, item: T) {
-        self.pending.lock().expect("Mutex poisoned").push_back(item);
+        self.push_queued(Queued::new(Arc::new(item)));
+    }
+
+    fn push_queued(&self, queued: Queued<T>) {
+        self.record_event(TaskEventKind::Pushed, None, &queued.value);
+        self.pending.push(queued);
         self.notify_incoming.notify_one();
     }
 
-    pub async fn pop_with_timeout(&self, timeout: Duration) -> Option<(T, TaskId<T>)> {
+    pub fn push_many(&self, items: Vec<T>) {
+        self.push_many_arcs(items.into_iter().map(Arc::new).collect());
+    }
+
+    fn push_many_arcs(&self, tasks: Vec<Arc<T>>) {
+        let count = tasks.len();
+        for task in tasks {
+            let queued = Queued::new(task);
+            self.record_event(TaskEventKind::Pushed, None, &queued.value);
+            self.pending.push(queued);
+        }
+        // NOTE: one wakeup per item, so every parked waiter that can get a task wakes up
+        for _ in 0..count {
+            self.notify_incoming.notify_one();
+        }
+    }
+
+    pub async fn pop_with_timeout(&self, timeout: Duration) -> Option<(Arc<T>, TaskId<T>)> {
+        self.pop_with_execution_timeout(timeout, self.execution_timeout)
+            .await
+    }
+
+    // Like `pop_with_timeout`, also returns which attempt this pop is, 1 on the first delivery.
+    // A task requeued by a timeout, a refused completion or `requeue_processing` comes back with
+    // the next one
+    pub async fn pop_with_attempt(&self, timeout: Duration) -> Option<(Arc<T>, TaskId<T>, u32)> {
+        self.pop_entry(timeout, self.execution_timeout).await
+    }
+
+    // Endless stream of popped tasks, parks while the queue is empty
+    pub fn stream(&self) -> impl Stream<Item = (Arc<T>, TaskId<T>)> + '_ {
+        stream::repeat(()).filter_map(move |()| self.pop_with_timeout(STREAM_POLL_TIMEOUT))
+    }
+
+    // Like `pop_with_timeout`, but the task is reclaimed after `execution_timeout` instead of the
+    // queue-wide `EXECUTION_TIMEOUT_MILLIS`
+    pub async fn pop_with_execution_timeout(
+        &self,
+        timeout: Duration,
+        execution_timeout: Duration,
+    ) -> Option<(Arc<T>, TaskId<T>)> {
+        let (task, id, _) = self.pop_entry(timeout, execution_timeout).await?;
+        Some((task, id))
+    }
+
+    // Also returns the number of pops of the task, this one included
+    async fn pop_entry(
+        &self,
+        timeout: Duration,
+        execution_timeout: Duration,
+    ) -> Option<(Arc<T>, TaskId<T>, u32)> {
         let mut timeout = Box::pin(sleep(timeout));
         loop {
-            if let Some(item) = self.pending.lock().expect("Mutex poisoned").pop_front() {
-                let id = self
-                    .processing
-                    .lock()
-                    .expect("Mutex poisoned")
-                    .push_back(Timed::new(item.clone()));
-                return Some((item, TaskId(id)));
+            // NOTE: the pending pop is made under the processing lock, so concurrent pops can't
+            // overshoot `max_in_flight`
+            let full = {
+                let mut processing = self.processing.lock().expect("Mutex poisoned");
+                let full = self
+                    .max_in_flight
+                    .is_some_and(|max| processing.tasks.len() >= max.get());
+                if !full && let Some(Queued { value, attempts }) = self.pending.pop() {
+                    let id = processing.insert(
+                        value.clone(),
+                        execution_timeout,
+                        self.max_lease,
+                        attempts + 1,
+                    );
+                    self.processing_len.fetch_add(1, Ordering::Relaxed);
+                    drop(processing);
+                    self.notify_deadline.notify_one();
+                    self.record_event(TaskEventKind::Popped, Some(id), &value);
+                    return Some((value, id, attempts + 1));
+                }
+                full
             };
             select! {
                 _ = self.notify_incoming.notified() => {},
+                _ = self.notify_slot_freed.notified(), if full => {},
                 _ = &mut timeout => {
                     return None;
                 },
@@ -128,65 +929,522 @@ impl<T: Clone, const EXECUTION_TIMEOUT_MILLIS: u128> GenericTaskQueue<T, EXECUTI
         }
     }
 
-    pub fn submit_completed(&self, id: &TaskId<T>) -> Option<T> {
-        self.processing
-            .lock()
-            .expect("Mutex poisoned")
-            .remove(id.0)
-            .map(|task| task.value)
+    pub fn submit_completed(&self, id: &TaskId<T>) -> Result<Arc<T>, SubmitError> {
+        self.submit_completed_entry(id).map(|(task, _)| task)
+    }
+
+    // Like `submit_completed`, with the attempts of the task for `reclaim_completed`
+    fn submit_completed_entry(&self, id: &TaskId<T>) -> Result<(Arc<T>, u32), SubmitError> {
+        let mut processing = self.processing.lock().expect("Mutex poisoned");
+        let mut retired = self.retired.lock().expect("Mutex poisoned");
+        if let Some(max_age) = self.max_completion_age
+            && processing
+                .renewed_at(id)
+                .is_some_and(|at| at.elapsed() > max_age)
+        {
+            return Err(SubmitError::Stale);
+        }
+        match processing.remove(id) {
+            Some(entry) => {
+                self.free_slots(1);
+                Self::retire(&mut retired, *id, SubmitError::AlreadyCompleted);
+                self.processing_times.record(entry.popped_at.elapsed());
+                self.record_event(TaskEventKind::Completed, Some(*id), &entry.value);
+                Ok((entry.value, entry.attempts))
+            }
+            None => Err(Self::miss_reason(&retired, id)),
+        }
+    }
+
+    // `None` until a task is completed
+    pub fn processing_time_stats(&self) -> Option<ProcessingTimeStats> {
+        self.processing_times.stats()
+    }
+
+    // Restarts the execution timeout of a processing task, for workers on long tasks
+    pub fn heartbeat(&self, id: &TaskId<T>) -> Result<(), SubmitError> {
+        let mut processing = self.processing.lock().expect("Mutex poisoned");
+        if let Some(entry) = processing.tasks.get(id) {
+            if self.max_lease.is_some_and(|lease| entry.popped_at.elapsed() > lease) {
+                return Err(SubmitError::TimedOut);
+            }
+            processing.renew(id);
+            return Ok(());
+        }
+        let retired = self.retired.lock().expect("Mutex poisoned");
+        Err(Self::miss_reason(&retired, id))
+    }
+
+    fn miss_reason(retired: &VecDeque<(TaskId<T>, SubmitError)>, id: &TaskId<T>) -> SubmitError {
+        retired
+            .iter()
+            .find(|(retired_id, _)| retired_id == id)
+            .map_or(SubmitError::NotFound, |(_, reason)| *reason)
+    }
+
+    fn retire(
+        retired: &mut VecDeque<(TaskId<T>, SubmitError)>,
+        id: TaskId<T>,
+        reason: SubmitError,
+    ) {
+        if retired.len() == RETIRED_HISTORY {
+            retired.pop_front();
+        }
+        retired.push_back((id, reason));
+    }
+
+    // Soonest deadline of a processing task, `process_timeouts` has nothing to reclaim before it
+    pub fn next_timeout_deadline(&self) -> Option<Instant> {
+        let processing = self.processing.lock().expect("Mutex poisoned");
+        processing.next_deadline()
+    }
+
+    // Waits until the soonest deadline passes, parked while nothing is processing. Meant for a
+    // single timeout loop, which calls `process_timeouts` after it
+    pub async fn wait_next_timeout(&self) {
+        loop {
+            // NOTE: a pop since the read leaves a permit, so its deadline is not missed
+            let Some(deadline) = self.next_timeout_deadline() else {
+                self.notify_deadline.notified().await;
+                continue;
+            };
+            select! {
+                _ = sleep_until(deadline) => return,
+                _ = self.notify_deadline.notified() => {},
+            }
+        }
     }
 
     pub fn process_timeouts(&self) {
-        self.process_timeouts_with_inspect(|_, _| {})
+        self.process_timeouts_with_inspect(|_, _| TimeoutAction::Requeue)
     }
 
-    pub fn process_timeouts_with_inspect(&self, inspect: impl Fn(TaskId<T>, &T)) {
-        let mut pending = self.pending.lock().expect("Mutex poisoned");
+    pub fn process_timeouts_with_inspect(&self, inspect: impl Fn(TaskId<T>, &T) -> TimeoutAction) {
+        self.process_timeouts_batch_with_inspect(usize::MAX, inspect);
+    }
+
+    // Reclaims at most `batch` timed out tasks, returns whether more are left
+    pub fn process_timeouts_batch_with_inspect(
+        &self,
+        batch: usize,
+        inspect: impl Fn(TaskId<T>, &T) -> TimeoutAction,
+    ) -> bool {
+        self.reclaim_timed_out(batch, inspect, |_, _| {})
+    }
+
+    // `settle` sees the action actually taken, which differs from the inspected one for tasks out
+    // of attempts
+    fn reclaim_timed_out(
+        &self,
+        batch: usize,
+        inspect: impl Fn(TaskId<T>, &T) -> TimeoutAction,
+        settle: impl Fn(&T, TimeoutAction),
+    ) -> bool {
+        let mut processing = self.processing.lock().expect("Mutex poisoned");
+        let mut retired = self.retired.lock().expect("Mutex poisoned");
+        let mut expired = processing.due(Instant::now(), batch.saturating_add(1));
+        let more = expired.len() > batch;
+        expired.truncate(batch);
+        self.free_slots(expired.len());
+        for id in expired {
+            let ProcessingEntry {
+                value: task,
+                attempts,
+                ..
+            } = processing.remove(&id).expect("Invariant violated");
+            Self::retire(&mut retired, id, SubmitError::TimedOut);
+            self.record_event(TaskEventKind::TimedOut, Some(id), &task);
+            let action = self.limit_attempts(inspect(id, &task), attempts);
+            settle(&task, action);
+            self.apply_reclaim(task, attempts, action);
+        }
+        more
+    }
+
+    fn limit_attempts(&self, action: TimeoutAction, attempts: u32) -> TimeoutAction {
+        let exhausted = self
+            .max_attempts
+            .is_some_and(|max_attempts| attempts >= max_attempts.get());
+        if action == TimeoutAction::Requeue && exhausted {
+            return TimeoutAction::DeadLetter;
+        }
+        action
+    }
+
+    // Reclaims a processing task its worker gave up on, as if it timed out right now. Returns the
+    // action taken, a task out of attempts is dead lettered instead of requeued
+    pub fn fail(
+        &self,
+        id: &TaskId<T>,
+        action: TimeoutAction,
+    ) -> Result<TimeoutAction, SubmitError> {
+        self.fail_with_settle(id, action, |_, _| {})
+    }
+
+    fn fail_with_settle(
+        &self,
+        id: &TaskId<T>,
+        action: TimeoutAction,
+        settle: impl FnOnce(&T, TimeoutAction),
+    ) -> Result<TimeoutAction, SubmitError> {
         let mut processing = self.processing.lock().expect("Mutex poisoned");
-        while let Some(task) = processing.front() {
-            if task.timestamp.elapsed().as_millis() > EXECUTION_TIMEOUT_MILLIS {
-                let id = processing.front_index().expect("Unreachable");
-                let task = processing.pop_front().expect("Unreachable");
-                inspect(TaskId(id), &task.value);
-                pending.push_back(task.value);
+        let mut retired = self.retired.lock().expect("Mutex poisoned");
+        let Some(ProcessingEntry {
+            value: task,
+            attempts,
+            ..
+        }) = processing.remove(id)
+        else {
+            return Err(Self::miss_reason(&retired, id));
+        };
+        self.free_slots(1);
+        Self::retire(&mut retired, *id, SubmitError::Failed);
+        self.record_event(TaskEventKind::Failed, Some(*id), &task);
+        let action = self.limit_attempts(action, attempts);
+        settle(&task, action);
+        self.apply_reclaim(task, attempts, action);
+        Ok(action)
+    }
+
+    // Moves a processing task back to pending right away, regardless of its timeout
+    pub fn requeue_processing(&self, id: &TaskId<T>) -> Result<(), SubmitError> {
+        let mut processing = self.processing.lock().expect("Mutex poisoned");
+        let mut retired = self.retired.lock().expect("Mutex poisoned");
+        let Some(ProcessingEntry {
+            value: task,
+            attempts,
+            ..
+        }) = processing.remove(id)
+        else {
+            return Err(Self::miss_reason(&retired, id));
+        };
+        self.free_slots(1);
+        Self::retire(&mut retired, *id, SubmitError::Requeued);
+        self.record_event(TaskEventKind::Requeued, Some(*id), &task);
+        self.apply_reclaim(task, attempts, TimeoutAction::Requeue);
+        Ok(())
+    }
+
+    // Requeues every task in processing regardless of its deadline, in the order they were popped
+    // or renewed. Late completions of them are rejected with `SubmitError::Requeued`
+    pub fn reclaim_all_processing(&self) -> usize {
+        let mut processing = self.processing.lock().expect("Mutex poisoned");
+        let mut retired = self.retired.lock().expect("Mutex poisoned");
+        let drained = processing.drain();
+        let count = drained.len();
+        self.free_slots(count);
+        for (id, entry) in drained {
+            Self::retire(&mut retired, id, SubmitError::Requeued);
+            self.record_event(TaskEventKind::Requeued, Some(id), &entry.value);
+            self.apply_reclaim(entry.value, entry.attempts, TimeoutAction::Requeue);
+        }
+        count
+    }
+
+    // Takes back a task returned by `submit_completed_entry` whose completion could not be
+    // delivered, like `fail` a task out of attempts is dead lettered instead of requeued
+    fn reclaim_completed(
+        &self,
+        task: Arc<T>,
+        attempts: u32,
+        action: TimeoutAction,
+        settle: impl FnOnce(&T, TimeoutAction),
+    ) {
+        let action = self.limit_attempts(action, attempts);
+        if action == TimeoutAction::Requeue {
+            self.record_event(TaskEventKind::Requeued, None, &task);
+        }
+        settle(&task, action);
+        self.apply_reclaim(task, attempts, action);
+    }
+
+    fn apply_reclaim(&self, task: Arc<T>, attempts: u32, action: TimeoutAction) {
+        match action {
+            TimeoutAction::Requeue => {
+                self.pending.push(Queued {
+                    value: task,
+                    attempts,
+                });
                 self.notify_incoming.notify_one();
-            } else {
-                break;
+            }
+            TimeoutAction::Drop => {}
+            TimeoutAction::DeadLetter => {
+                self.dead_letter
+                    .lock()
+                    .expect("Mutex poisoned")
+                    .push(task);
             }
         }
     }
 
+    pub fn drain_pending(&self) -> Vec<Arc<T>> {
+        // NOTE: holding the processing lock keeps timeouts from requeueing into the drained queue,
+        // pushes racing with the drain may or may not be taken
+        let _processing = self.processing.lock().expect("Mutex poisoned");
+        std::iter::from_fn(|| self.pending.pop())
+            .map(|queued| queued.value)
+            .collect()
+    }
+
+    pub fn take_dead_letter(&self) -> Vec<Arc<T>> {
+        std::mem::take(&mut *self.dead_letter.lock().expect("Mutex poisoned"))
+    }
+
     pub fn len_pending(&self) -> usize {
-        let pending = self.pending.lock().expect("Mutex poisoned");
-        pending.len()
+        self.pending.len()
     }
 
+    // NOTE: `len_pending` and `len_processing` take no lock, so they don't contend with pushes and
+    // pops, but are only a snapshot while those run concurrently
     pub fn len_processing(&self) -> usize {
+        self.processing_len.load(Ordering::Relaxed)
+    }
+
+    pub fn len_dead_letter(&self) -> usize {
+        let dead_letter = self.dead_letter.lock().expect("Mutex poisoned");
+        dead_letter.len()
+    }
+
+    // NOTE: `pending` needs no shrinking, the segment queue frees its blocks as they are drained,
+    // it's processing bookkeeping that stays at its peak size after a burst
+    pub fn shrink_processing(&self) {
+        let mut processing = self.processing.lock().expect("Mutex poisoned");
+        processing.shrink_to_fit();
+    }
+
+    pub fn capacity_processing(&self) -> usize {
         let processing = self.processing.lock().expect("Mutex poisoned");
-        processing.len()
+        processing.order.capacity().max(processing.tasks.capacity())
+    }
+}
+
+// NOTE: every id in `tasks` has exactly one node in `order` and one deadline in `deadlines`, and
+// vice versa
+#[derive(Debug)]
+struct Processing<T> {
+    // NOTE: in order of the last pop or heartbeat
+    order: VecList<Timed<TaskId<T>>>,
+    // NOTE: timeouts differ per task, so `order` is not deadline order
+    deadlines: BTreeSet<(Instant, TaskId<T>)>,
+    tasks: HashMap<TaskId<T>, ProcessingEntry<T>>,
+}
+
+#[derive(Debug)]
+struct ProcessingEntry<T> {
+    value: Arc<T>,
+    index: Index<Timed<TaskId<T>>>,
+    timeout: Duration,
+    attempts: u32,
+    popped_at: Instant,
+    // NOTE: end of `max_lease`, heartbeats don't move the deadline past it
+    lease_end: Option<Instant>,
+    deadline: Instant,
+}
+
+impl<T> Default for Processing<T> {
+    fn default() -> Self {
+        Self {
+            order: VecList::new(),
+            deadlines: BTreeSet::new(),
+            tasks: HashMap::new(),
+        }
     }
 }
 
-#[derive(Debug, Copy, Serialize, Deserialize)]
+impl<T> Processing<T> {
+    fn insert(
+        &mut self,
+        value: Arc<T>,
+        timeout: Duration,
+        max_lease: Option<Duration>,
+        attempts: u32,
+    ) -> TaskId<T> {
+        let id = TaskId::new();
+        let timed = Timed::new(id);
+        let popped_at = timed.timestamp;
+        let lease_end = max_lease.map(|lease| popped_at + lease);
+        let deadline = Self::deadline(popped_at + timeout, lease_end);
+        let index = self.order.push_back(timed);
+        self.deadlines.insert((deadline, id));
+        self.tasks.insert(
+            id,
+            ProcessingEntry {
+                value,
+                index,
+                timeout,
+                attempts,
+                popped_at,
+                lease_end,
+                deadline,
+            },
+        );
+        id
+    }
+
+    fn deadline(timeout_end: Instant, lease_end: Option<Instant>) -> Instant {
+        lease_end.map_or(timeout_end, |lease_end| timeout_end.min(lease_end))
+    }
+
+    fn renew(&mut self, id: &TaskId<T>) -> bool {
+        let Some(entry) = self.tasks.get_mut(id) else {
+            return false;
+        };
+        self.order.remove(entry.index).expect("Invariant violated");
+        let timed = Timed::new(*id);
+        let deadline = Self::deadline(timed.timestamp + entry.timeout, entry.lease_end);
+        entry.index = self.order.push_back(timed);
+        let removed = self.deadlines.remove(&(entry.deadline, *id));
+        assert!(removed, "Invariant violated");
+        self.deadlines.insert((deadline, *id));
+        entry.deadline = deadline;
+        true
+    }
+
+    // Up to `max` tasks past their deadline at `now`, soonest deadline first
+    fn due(&self, now: Instant, max: usize) -> Vec<TaskId<T>> {
+        self.deadlines
+            .iter()
+            .take_while(|(deadline, _)| *deadline < now)
+            .take(max)
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.first().map(|(deadline, _)| *deadline)
+    }
+
+    // Last pop or heartbeat of a processing task
+    fn renewed_at(&self, id: &TaskId<T>) -> Option<Instant> {
+        let entry = self.tasks.get(id)?;
+        let timed = self.order.get(entry.index).expect("Invariant violated");
+        Some(timed.timestamp)
+    }
+
+    fn shrink_to_fit(&mut self) {
+        let moved = self.order.pack_to_fit();
+        for entry in self.tasks.values_mut() {
+            entry.index = moved[&entry.index];
+        }
+        self.tasks.shrink_to_fit();
+    }
+
+    fn remove(&mut self, id: &TaskId<T>) -> Option<ProcessingEntry<T>> {
+        let entry = self.tasks.remove(id)?;
+        self.order.remove(entry.index).expect("Invariant violated");
+        let removed = self.deadlines.remove(&(entry.deadline, *id));
+        assert!(removed, "Invariant violated");
+        Some(entry)
+    }
+
+    fn drain(&mut self) -> Vec<(TaskId<T>, ProcessingEntry<T>)> {
+        let drained: Vec<_> = std::mem::take(&mut self.order)
+            .into_iter()
+            .map(|Timed { value: id, .. }| {
+                let entry = self.tasks.remove(&id).expect("Invariant violated");
+                (id, entry)
+            })
+            .collect();
+        assert!(self.tasks.is_empty(), "Invariant violated");
+        self.deadlines.clear();
+        drained
+    }
+}
+
+// Id of one pop of a task, a requeued task gets a new one when popped again, so completions with
+// an id from before the requeue never reach the new processing entry
+#[derive(Serialize, Deserialize)]
 #[serde(from = "[u8; 16]", into = "[u8; 16]")]
 #[serde(bound(serialize = "", deserialize = ""))]
-pub struct TaskId<T>(dlv_list::Index<Timed<T>>);
+pub struct TaskId<T>(Uuid, PhantomData<fn() -> T>);
+
+impl<T> TaskId<T> {
+    fn new() -> Self {
+        Self(Uuid::new_v4(), PhantomData)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.0.into_bytes()
+    }
+
+    // Base62 of the id for logs, 22 characters instead of 32 hex digits, always the same for an id
+    pub fn display_short(&self) -> String {
+        let mut value = self.0.as_u128();
+        let mut digits = [b'0'; SHORT_ID_LEN];
+        for digit in digits.iter_mut().rev() {
+            *digit = BASE62[(value % 62) as usize];
+            value /= 62;
+        }
+        String::from_utf8(digits.to_vec()).expect("Base62 digits are ASCII")
+    }
+
+    // Inverse of `display_short`
+    pub fn parse_short(short: &str) -> Option<Self> {
+        if short.len() != SHORT_ID_LEN {
+            return None;
+        }
+        let mut value: u128 = 0;
+        for byte in short.bytes() {
+            let digit = BASE62.iter().position(|&b| b == byte)?;
+            value = value.checked_mul(62)?.checked_add(digit as u128)?;
+        }
+        Some(Self(Uuid::from_u128(value), PhantomData))
+    }
+}
+
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// NOTE: 62^22 > 2^128 > 62^21
+const SHORT_ID_LEN: usize = 22;
+
+impl<T> Debug for TaskId<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TaskId").field(&self.0).finish()
+    }
+}
 
 impl<T> Clone for TaskId<T> {
     fn clone(&self) -> Self {
-        TaskId(self.0)
+        *self
+    }
+}
+
+impl<T> Copy for TaskId<T> {}
+
+impl<T> PartialEq for TaskId<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl<T> Eq for TaskId<T> {}
+
+impl<T> PartialOrd for TaskId<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for TaskId<T> {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl<T> Hash for TaskId<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
     }
 }
 
 impl<T> From<TaskId<T>> for [u8; 16] {
     fn from(id: TaskId<T>) -> Self {
-        id.0.to_bytes()
+        id.to_bytes()
     }
 }
 
 impl<T> From<[u8; 16]> for TaskId<T> {
     fn from(bytes: [u8; 16]) -> Self {
-        Self(dlv_list::Index::from_bytes(bytes))
+        Self(Uuid::from_bytes(bytes), PhantomData)
     }
 }
 
@@ -203,13 +1461,6 @@ impl<T> SerializeAs<TaskId<T>> for serde_with::hex::Hex {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&hex::encode(source.0.to_bytes()))
-    }
-}
-
-impl<T> Deref for TaskId<T> {
-    type Target = dlv_list::Index<Timed<T>>;
-    fn deref(&self) -> &Self::Target {
-        &self.0
+        serializer.serialize_str(&hex::encode(source.to_bytes()))
     }
 }
@@ -0,0 +1,125 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::utils::Timed;
+
+// Last completion info per submission id, bounded in size and expiring after `ttl`
+#[derive(Debug)]
+pub struct ResultStore {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<ResultEntries>,
+}
+
+#[derive(Debug, Default)]
+struct ResultEntries {
+    results: HashMap<String, Timed<String>>,
+    // NOTE: insertion order, one node per result
+    order: VecDeque<(String, Instant)>,
+}
+
+impl ResultStore {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Mutex::new(ResultEntries::default()),
+        }
+    }
+
+    pub fn insert(&self, submission_id: String, info: String) {
+        let mut entries = self.entries.lock().expect("Mutex poisoned");
+        let result = Timed::new(info);
+        // NOTE: an overwritten result moves to the back, like a new one
+        if entries.results.contains_key(&submission_id) {
+            entries.order.retain(|(id, _)| *id != submission_id);
+        }
+        entries
+            .order
+            .push_back((submission_id.clone(), result.timestamp));
+        entries.results.insert(submission_id, result);
+        self.prune(&mut entries);
+    }
+
+    pub fn get(&self, submission_id: &str) -> Option<String> {
+        let mut entries = self.entries.lock().expect("Mutex poisoned");
+        self.prune(&mut entries);
+        entries
+            .results
+            .get(submission_id)
+            .map(|result| result.value.clone())
+    }
+
+    // Results kept, expired ones included until the next lookup or insert
+    pub fn len(&self) -> usize {
+        self.entries.lock().expect("Mutex poisoned").order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn prune(&self, entries: &mut ResultEntries) {
+        while let Some((submission_id, timestamp)) = entries.order.front() {
+            if entries.results.len() <= self.capacity && timestamp.elapsed() <= self.ttl {
+                break;
+            }
+            entries.results.remove(submission_id);
+            entries.order.pop_front();
+        }
+    }
+}
+
+// Keys seen within the last `ttl`, bounded in size like `ResultStore`
+#[derive(Debug)]
+pub struct SeenKeys {
+    ttl: Duration,
+    capacity: usize,
+    entries: Mutex<SeenEntries>,
+}
+
+#[derive(Debug, Default)]
+struct SeenEntries {
+    keys: HashMap<String, Instant>,
+    order: VecDeque<(String, Instant)>,
+}
+
+impl SeenKeys {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            entries: Mutex::new(SeenEntries::default()),
+        }
+    }
+
+    // Returns whether `key` is new, a key seen again keeps its first timestamp, so retries don't
+    // extend the window
+    pub fn insert(&self, key: &str) -> bool {
+        let mut entries = self.entries.lock().expect("Mutex poisoned");
+        self.prune(&mut entries);
+        if entries.keys.contains_key(key) {
+            return false;
+        }
+        let now = Instant::now();
+        entries.keys.insert(key.to_owned(), now);
+        entries.order.push_back((key.to_owned(), now));
+        self.prune(&mut entries);
+        true
+    }
+
+    fn prune(&self, entries: &mut SeenEntries) {
+        while let Some((key, timestamp)) = entries.order.front() {
+            if entries.keys.len() <= self.capacity && timestamp.elapsed() <= self.ttl {
+                break;
+            }
+            entries.keys.remove(key);
+            entries.order.pop_front();
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::fmt::Debug;
+
+use futures::future::BoxFuture;
+use tokio::sync::mpsc;
+
+use crate::api::{QueueTaskCompletion, REQUEST_ID_HEADER};
+
+// Delivers completions no `add_task_sync` caller waits for, an error applies the collector policy
+pub trait CompletionSink: Debug + Send + Sync {
+    fn send(&self, completion: QueueTaskCompletion) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+// Posts completions as JSON to their callback_url, or to the collector when there is none
+#[derive(Debug, Clone)]
+pub struct HttpSink {
+    client: reqwest::Client,
+    collector_url: String,
+}
+
+impl HttpSink {
+    pub fn new(client: reqwest::Client, collector_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            collector_url: collector_url.into(),
+        }
+    }
+}
+
+impl CompletionSink for HttpSink {
+    fn send(&self, completion: QueueTaskCompletion) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            let url = completion
+                .callback_url
+                .as_deref()
+                .unwrap_or(&self.collector_url);
+            let mut request = self.client.post(url);
+            if let Some(request_id) = &completion.request_id {
+                request = request.header(REQUEST_ID_HEADER, request_id);
+            }
+            request
+                .json(&completion)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)?;
+            Ok(())
+        })
+    }
+}
+
+// Hands completions over to an in-process receiver, for tests and embedding the queue
+#[derive(Debug, Clone)]
+pub struct ChannelSink {
+    sender: mpsc::UnboundedSender<QueueTaskCompletion>,
+}
+
+impl ChannelSink {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<QueueTaskCompletion>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self { sender }, receiver)
+    }
+}
+
+impl CompletionSink for ChannelSink {
+    fn send(&self, completion: QueueTaskCompletion) -> BoxFuture<'_, anyhow::Result<()>> {
+        // NOTE: a dropped receiver refuses the completion like a collector that is down
+        let sent = self
+            .sender
+            .send(completion)
+            .map_err(|_| anyhow::anyhow!("Completion receiver is closed"));
+        Box::pin(async move { sent })
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use sled::{Transactional, transaction::ConflictableTransactionResult};
+
+// Persistent storage behind `GenericTaskQueueWithBackup`, opaque records in two tables.
+// Implementations panic on storage failures, the queue can't recover from them
+pub trait TaskStore {
+    // Unique, and only growing across restarts too, so records under later ids sort after
+    fn generate_id(&self) -> u64;
+
+    // Every record of `table`, in key order
+    fn records(&self, table: Table) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+    // Applies every write at once, a crash leaves either all of them or none
+    fn apply(&self, table: Table, writes: Vec<StoreWrite>);
+
+    // Replaces the value under `key` in `Table::Tasks`, a missing record stays missing
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&[u8]) -> Vec<u8>);
+
+    // Moves the record under `key` from `Table::Tasks` to `Table::DeadLetter` at once, keeping its
+    // key, does nothing when it's missing
+    fn move_to_dead_letter(&self, key: &[u8]);
+
+    fn clear(&self, table: Table);
+
+    // Makes every write so far durable, returns the number of bytes written
+    fn flush(&self) -> impl Future<Output = usize> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Table {
+    Tasks,
+    DeadLetter,
+}
+
+impl Table {
+    fn index(self) -> usize {
+        match self {
+            Self::Tasks => 0,
+            Self::DeadLetter => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreWrite {
+    Insert(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+// Tasks in the default tree of the db, dead lettered ones in its `dead_letter` tree
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    db: sled::Db,
+    dead_letter: sled::Tree,
+}
+
+impl SledStore {
+    pub fn new(db: sled::Db) -> Self {
+        let dead_letter = db.open_tree("dead_letter").unwrap();
+        Self { db, dead_letter }
+    }
+
+    fn tree(&self, table: Table) -> &sled::Tree {
+        match table {
+            Table::Tasks => &self.db,
+            Table::DeadLetter => &self.dead_letter,
+        }
+    }
+}
+
+impl TaskStore for SledStore {
+    fn generate_id(&self) -> u64 {
+        self.db.generate_id().unwrap()
+    }
+
+    fn records(&self, table: Table) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.tree(table)
+            .iter()
+            .map(|record| {
+                let (key, value) = record.unwrap();
+                (key.to_vec(), value.to_vec())
+            })
+            .collect()
+    }
+
+    fn apply(&self, table: Table, writes: Vec<StoreWrite>) {
+        let mut batch = sled::Batch::default();
+        for write in writes {
+            match write {
+                StoreWrite::Insert(key, value) => batch.insert(key, value),
+                StoreWrite::Remove(key) => batch.remove(key),
+            }
+        }
+        self.tree(table).apply_batch(batch).unwrap();
+    }
+
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&[u8]) -> Vec<u8>) {
+        self.db
+            .fetch_and_update(key, |value| Some(update(value?)))
+            .unwrap();
+    }
+
+    fn move_to_dead_letter(&self, key: &[u8]) {
+        (&*self.db, &self.dead_letter)
+            .transaction(|(db, dead_letter)| -> ConflictableTransactionResult<()> {
+                if let Some(value) = db.remove(key)? {
+                    dead_letter.insert(key, value)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    fn clear(&self, table: Table) {
+        self.tree(table).clear().unwrap();
+    }
+
+    async fn flush(&self) -> usize {
+        self.db.flush_async().await.unwrap()
+    }
+}
+
+type Records = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// Keeps the records in memory only, for tests and queues that need no durability. Clones share the
+// records, so a queue built from a clone reloads what another queue left, like from a sled db
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryStore {
+    // NOTE: both tables behind one lock, so moves between them are atomic
+    tables: Arc<Mutex<[Records; 2]>>,
+    ids: Arc<AtomicU64>,
+}
+
+impl InMemoryStore {
+    // Independent copy of the current records, later writes to either side don't show in the other
+    pub fn snapshot(&self) -> Self {
+        let tables = self.tables.lock().expect("Mutex poisoned").clone();
+        Self {
+            tables: Arc::new(Mutex::new(tables)),
+            ids: Arc::new(AtomicU64::new(self.ids.load(Ordering::Relaxed))),
+        }
+    }
+}
+
+impl TaskStore for InMemoryStore {
+    fn generate_id(&self) -> u64 {
+        self.ids.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn records(&self, table: Table) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let tables = self.tables.lock().expect("Mutex poisoned");
+        tables[table.index()].clone().into_iter().collect()
+    }
+
+    fn apply(&self, table: Table, writes: Vec<StoreWrite>) {
+        let mut tables = self.tables.lock().expect("Mutex poisoned");
+        let records = &mut tables[table.index()];
+        for write in writes {
+            match write {
+                StoreWrite::Insert(key, value) => records.insert(key, value),
+                StoreWrite::Remove(key) => records.remove(&key),
+            };
+        }
+    }
+
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&[u8]) -> Vec<u8>) {
+        let mut tables = self.tables.lock().expect("Mutex poisoned");
+        if let Some(value) = tables[Table::Tasks.index()].get_mut(key) {
+            *value = update(value);
+        }
+    }
+
+    fn move_to_dead_letter(&self, key: &[u8]) {
+        let mut tables = self.tables.lock().expect("Mutex poisoned");
+        let [tasks, dead_letter] = &mut *tables;
+        if let Some(value) = tasks.remove(key) {
+            dead_letter.insert(key.to_vec(), value);
+        }
+    }
+
+    fn clear(&self, table: Table) {
+        self.tables.lock().expect("Mutex poisoned")[table.index()].clear();
+    }
+
+    async fn flush(&self) -> usize {
+        0
+    }
+}
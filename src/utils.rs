@@ -1,18 +1,220 @@
-use std::time::Instant;
+use std::{
+    num::NonZeroU32,
+    path::Path,
+    pin::pin,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use clap::Args;
+use tokio::{select, sync::watch};
+
+use tracing_subscriber::EnvFilter;
+
+pub fn init_tracing() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct HttpTimeouts {
+    #[arg(long, env = "HTTP_CONNECT_TIMEOUT_MS", default_value_t = 2_000)]
+    pub http_connect_timeout_ms: u64,
+    /// Timeout of a whole outbound request, including reading the response body
+    #[arg(long, env = "HTTP_REQUEST_TIMEOUT_MS", default_value_t = 10_000)]
+    pub http_request_timeout_ms: u64,
+}
+
+// Connection reuse of the shared client, tuned for the bursts of exploit fetches on cache misses
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct HttpPool {
+    /// Idle connections kept open per host, more are closed once their requests are done
+    #[arg(long, env = "EXPLOIT_POOL_MAX_IDLE_PER_HOST", default_value_t = 32)]
+    pub pool_max_idle_per_host: usize,
+    #[arg(long, env = "EXPLOIT_POOL_IDLE_TIMEOUT_MS", default_value_t = 90_000)]
+    pub pool_idle_timeout_ms: u64,
+    /// Interval of TCP keep-alive probes on open connections
+    #[arg(long, env = "EXPLOIT_TCP_KEEPALIVE_MS", default_value_t = 60_000)]
+    pub tcp_keepalive_ms: u64,
+    /// Talk HTTP/2 without upgrade to every upstream, so concurrent fetches share one connection
+    #[arg(long, env = "EXPLOIT_HTTP2")]
+    pub http2: bool,
+    /// Interval of HTTP/2 pings keeping idle connections alive, none by default
+    #[arg(long, env = "EXPLOIT_HTTP2_KEEPALIVE_MS")]
+    pub http2_keepalive_ms: Option<u64>,
+}
+
+// Same as the clap defaults
+impl Default for HttpPool {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_ms: 90_000,
+            tcp_keepalive_ms: 60_000,
+            http2: false,
+            http2_keepalive_ms: None,
+        }
+    }
+}
+
+fn client_builder(timeouts: &HttpTimeouts) -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .connect_timeout(Duration::from_millis(timeouts.http_connect_timeout_ms))
+        .timeout(Duration::from_millis(timeouts.http_request_timeout_ms))
+}
+
+// One client for every outbound call of a service, so they share a connection pool
+pub fn build_client(timeouts: &HttpTimeouts) -> reqwest::Result<reqwest::Client> {
+    client_builder(timeouts).build()
+}
+
+// Like `build_client`, with the connection pool tuned by `pool`
+pub fn build_pooled_client(
+    timeouts: &HttpTimeouts,
+    pool: &HttpPool,
+) -> reqwest::Result<reqwest::Client> {
+    let mut builder = client_builder(timeouts)
+        .pool_max_idle_per_host(pool.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_millis(pool.pool_idle_timeout_ms))
+        .tcp_keepalive(Duration::from_millis(pool.tcp_keepalive_ms));
+    if pool.http2 {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(interval_ms) = pool.http2_keepalive_ms {
+        builder = builder
+            .http2_keep_alive_interval(Duration::from_millis(interval_ms))
+            .http2_keep_alive_while_idle(true);
+    }
+    builder.build()
+}
+
+// Opens the queue database, explaining the lock held by another running instance
+pub fn open_db(path: &Path) -> anyhow::Result<sled::Db> {
+    match sled::open(path) {
+        Ok(db) => Ok(db),
+        // NOTE: sled reports a failed file lock as a plain io error, only the message tells
+        Err(sled::Error::Io(err)) if err.to_string().contains("could not acquire lock") => {
+            anyhow::bail!(
+                "Queue database {} is locked, another instance is probably running. \
+                 Stop it or pass a different --db-path",
+                path.display()
+            )
+        }
+        Err(err) => {
+            Err(err).with_context(|| format!("Failed to open queue database {}", path.display()))
+        }
+    }
+}
+
+// Flips to true on Ctrl-C, long-running loops check it between iterations
+pub fn shutdown_on_ctrl_c() -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = tx.send(true);
+        }
+    });
+    rx
+}
+
+// Runs `fut` unless shutdown is requested first, in which case it is dropped
+pub async fn or_shutdown<F: Future>(
+    shutdown: &mut watch::Receiver<bool>,
+    fut: F,
+) -> Option<F::Output> {
+    let mut fut = pin!(fut);
+    select! {
+        biased;
+        requested = async { shutdown.wait_for(|&requested| requested).await.is_ok() } => {
+            // NOTE: a dropped sender means nobody can request shutdown anymore
+            if requested { None } else { Some(fut.await) }
+        }
+        output = &mut fut => Some(output),
+    }
+}
+
+// Fails fast after `threshold` consecutive failures. Once `cooldown` has passed one trial call is
+// let through per cooldown, a success closes the circuit again
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: NonZeroU32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    // NOTE: `None` while closed
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: NonZeroU32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    // Whether a call may go through, every allowed call must be followed by `record`
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        match state.open_until {
+            None => true,
+            Some(open_until) if Instant::now() < open_until => false,
+            Some(_) => {
+                // NOTE: half-open, the rest keeps failing fast while the trial is in flight, and a
+                // dropped trial doesn't keep the circuit open forever
+                state.open_until = Some(Instant::now() + self.cooldown);
+                true
+            }
+        }
+    }
+
+    pub fn record(&self, success: bool) {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        if success {
+            *state = BreakerState::default();
+            return;
+        }
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.threshold.get() {
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state
+            .lock()
+            .expect("Mutex poisoned")
+            .open_until
+            .is_some()
+    }
+}
 
 #[derive(Debug)]
 pub struct Timed<T> {
     pub value: T,
-    pub timestamp: Instant,
+    // NOTE: tokio's clock so paused runtimes can advance it
+    pub timestamp: tokio::time::Instant,
 }
 
 impl<T> Timed<T> {
     pub fn new(value: T) -> Self {
         Self {
             value,
-            timestamp: Instant::now(),
+            timestamp: tokio::time::Instant::now(),
         }
     }
 }
-
-
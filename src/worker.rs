@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rand::random_range;
+use reqwest::StatusCode;
+use tokio::time::sleep;
+use tracing::warn;
+
+use crate::{
+    api::QueueTask,
+    cache::{Cache, CacheStats, DataGetter},
+};
+
+// Entries are only added through `Cache::get_or_insert_with`, a lookup that would fetch fails
+#[derive(Debug, Default)]
+struct NoFetch;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotCached;
+
+impl DataGetter for NoFetch {
+    type Key = String;
+    type BorrowedKey = str;
+    type Value = String;
+    type Error = NotCached;
+    async fn get(&self, _key: &str) -> Result<String, NotCached> {
+        Err(NotCached)
+    }
+}
+
+// Processed exploit results by submission id, LRU bounded and forgotten after 10 minutes unused
+#[derive(Debug)]
+pub struct ProcessedExploits(Cache<NoFetch, 600_000, 600_000>);
+
+impl ProcessedExploits {
+    pub fn new(max_entries: usize) -> Self {
+        Self(Cache::new(NoFetch).with_max_entries(max_entries))
+    }
+
+    // Runs `process` only on a miss, so on every call when built with `max_entries` at zero
+    pub fn get_or_process(
+        &self,
+        submission_id: &str,
+        exploit: &str,
+        process: impl FnOnce(&str) -> String,
+    ) -> String {
+        self.0
+            .get_or_insert_with(submission_id, || process(exploit))
+            .expect("Local cache is never closed")
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.0.stats()
+    }
+}
+
+// What the demo worker does with an exploit, it echoes it back as the completion info
+pub fn process_exploit(exploit: &str) -> String {
+    exploit.to_owned()
+}
+
+// Exponential backoff between retries, with jitter so workers that failed together don't retry
+// together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    // Delay before retry `attempt`, counted from 0. Doubles from `base` up to `max`, then a random
+    // half of it is taken off
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let capped = self
+            .base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max);
+        capped / 2 + capped.mul_f64(random_range(0.0..0.5))
+    }
+}
+
+// Long polls get_task, `None` when the poll ended without a task. Connection failures and server
+// errors, like those of a restarting server, are retried with `backoff`, other errors are returned
+pub async fn poll_task(
+    client: &reqwest::Client,
+    server_url: &str,
+    poll_timeout: Duration,
+    backoff: Backoff,
+) -> anyhow::Result<Option<QueueTask>> {
+    let mut attempt = 0;
+    loop {
+        let res = client
+            .get(format!("{server_url}/queue/get_task"))
+            .timeout(poll_timeout)
+            .send()
+            .await;
+        let err = match res {
+            Err(err) if err.is_timeout() => return Ok(None),
+            Err(err) if err.is_connect() || err.is_request() => anyhow!(err),
+            Err(err) => return Err(err.into()),
+            Ok(res) if res.status().is_server_error() => {
+                anyhow!("Server answered {}", res.status())
+            }
+            Ok(res) if res.status() == StatusCode::NO_CONTENT => return Ok(None),
+            Ok(res) => return Ok(Some(res.error_for_status()?.json().await?)),
+        };
+        let delay = backoff.delay(attempt);
+        warn!(%err, attempt, ?delay, "Polling failed, retrying after a backoff");
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
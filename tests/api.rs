@@ -0,0 +1,1525 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    Json, Router,
+    body::Body,
+    extract::{ConnectInfo, FromRequest, FromRequestParts, Path, Query, Request, State},
+    http::{
+        HeaderMap, StatusCode, Version,
+        header::{ACCEPT, CONTENT_TYPE, ETAG, IF_NONE_MATCH, USER_AGENT},
+    },
+    response::IntoResponse,
+    routing::{get, post},
+};
+use queues_demo::{
+    AppState, CacheState, Exploit, FetchError, GetterStub, NumericGetterStub, RawGetterStub,
+    api::{
+        ApiError, ApiErrorBody, BodyLimits, MainQueue, QueueAddTask, QueueCompletedTask,
+        QueueEventKind, QueueFailedTask, QueueGetTaskParams, QueueState, QueueTask,
+        QueueTaskCompletion, QueueTaskRef, REQUEST_ID_HEADER, RequestId, cache_invalidate,
+        cache_keys, cache_routes_with_timeout, cache_stats, cache_warm, migrate_submission,
+        queue_add_task, queue_add_task_sync, queue_add_tasks, queue_collect_timeouts, queue_events,
+        queue_fail, queue_flush, queue_get_result, queue_get_task, queue_heartbeat,
+        queue_processing_time, queue_requeue, queue_submit_completed, routes_with_body_limits,
+        routes_with_limits,
+    },
+    cache::{Cache, CacheError, DataGetter},
+    codec::{Accept, Codec, Format, MSGPACK_CONTENT_TYPE},
+    queue::{QUEUE_FORMAT_VERSION, TaskId, TimeoutAction},
+    results::{ResultStore, SeenKeys},
+    sink::{ChannelSink, HttpSink},
+    utils::{HttpPool, HttpTimeouts, build_client, build_pooled_client},
+};
+use tracing_test::traced_test;
+
+mod common;
+
+use common::serve;
+
+fn state(sync_timeout: Duration) -> Arc<QueueState> {
+    state_with_result_ttl(sync_timeout, Duration::from_secs(60))
+}
+
+fn state_with_result_ttl(sync_timeout: Duration, result_ttl: Duration) -> Arc<QueueState> {
+    state_with_client(reqwest::Client::new(), sync_timeout, result_ttl)
+}
+
+fn state_with_client(
+    client: reqwest::Client,
+    sync_timeout: Duration,
+    result_ttl: Duration,
+) -> Arc<QueueState> {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    state_on_db(db, client, sync_timeout, result_ttl)
+}
+
+fn state_on_db(
+    db: sled::Db,
+    client: reqwest::Client,
+    sync_timeout: Duration,
+    result_ttl: Duration,
+) -> Arc<QueueState> {
+    Arc::new(QueueState {
+        queue: MainQueue::new(db).with_event_log_capacity(16),
+        sync_timeout,
+        max_submission_id_len: 16,
+        completions: Box::new(HttpSink::new(client, "http://localhost:3002/submit")),
+        collector_failure: TimeoutAction::Requeue,
+        completion_waiters: Default::default(),
+        results: ResultStore::new(result_ttl, 16),
+        idempotency_keys: SeenKeys::new(Duration::from_secs(60), 16),
+    })
+}
+
+fn status_code<T>(res: Result<T, ApiError>) -> StatusCode {
+    res.map_or_else(|err| err.status(), |_| StatusCode::OK)
+}
+
+fn add_task(submission_id: &str) -> Json<QueueAddTask> {
+    Json(QueueAddTask {
+        submission_id: submission_id.to_owned(),
+        exploit_key: None,
+        priority: 0,
+        callback_url: None,
+        idempotency_key: None,
+    })
+}
+
+#[tokio::test]
+async fn add_task_sync_returns_completion() {
+    let state = state(Duration::from_secs(10));
+    let caller = tokio::spawn(queue_add_task_sync(
+        State(state.clone()),
+        RequestId::generate(),
+        add_task("a"),
+    ));
+
+    let (submission, id) = state
+        .queue
+        .pop_with_timeout(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(submission.id, "a");
+    let completed = QueueCompletedTask {
+        id,
+        info: "done".to_owned(),
+        request_id: None,
+    };
+    let res = queue_submit_completed(State(state.clone()), Codec(Format::Json, completed)).await;
+    assert_eq!(status_code(res), StatusCode::OK);
+
+    let Json(completion) = caller.await.unwrap().unwrap();
+    assert_eq!(completion.submission_id, "a");
+    assert_eq!(completion.info, "done");
+    assert!(state.completion_waiters.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn add_task_sync_times_out_and_keeps_task() {
+    let state = state(Duration::from_millis(50));
+    let res = queue_add_task_sync(State(state.clone()), RequestId::generate(), add_task("a")).await;
+    assert_eq!(res.unwrap_err().status(), StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(state.queue.len_pending(), 1);
+    assert!(state.completion_waiters.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+#[traced_test]
+async fn timed_out_task_is_logged_with_its_id() {
+    let state = state(Duration::from_secs(10));
+    queue_add_task(State(state.clone()), RequestId::generate(), add_task("a"))
+        .await
+        .unwrap();
+    let (_, id) = state
+        .queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(10))
+        .await
+        .unwrap();
+    let collector = tokio::spawn(queue_collect_timeouts(state.clone(), 16));
+    while state.queue.len_pending() == 0 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    collector.abort();
+
+    let task_id = format!("task_id={}", id.display_short());
+    logs_assert(|lines: &[&str]| {
+        let logged = lines.iter().any(|line| {
+            line.contains(" WARN ")
+                && line.contains("Task timed out")
+                && line.contains(&task_id)
+                && line.contains("submission_id=a")
+        });
+        logged
+            .then_some(())
+            .ok_or_else(|| "No warning for the timed out task".to_owned())
+    });
+}
+
+#[tokio::test]
+async fn add_tasks_pushes_the_whole_array() {
+    let state = state(Duration::from_secs(10));
+    queue_add_task(
+        State(state.clone()),
+        RequestId::generate(),
+        add_task("first"),
+    )
+    .await
+    .unwrap();
+
+    let tasks = ["a", "b", "c"].map(|id| add_task(id).0).into();
+    let Json(res) = queue_add_tasks(State(state.clone()), RequestId::generate(), Json(tasks))
+        .await
+        .unwrap();
+    assert_eq!(res.accepted, 3);
+    assert_eq!(state.queue.len_pending(), 4);
+
+    // NOTE: one invalid task rejects the whole array
+    let tasks = ["d", ""].map(|id| add_task(id).0).into();
+    let res = queue_add_tasks(State(state.clone()), RequestId::generate(), Json(tasks)).await;
+    assert_eq!(status_code(res), StatusCode::BAD_REQUEST);
+    assert_eq!(state.queue.len_pending(), 4);
+}
+
+#[tokio::test]
+async fn add_task_rejects_empty_submission_id() {
+    let state = state(Duration::from_secs(10));
+    let res = queue_add_task(State(state.clone()), RequestId::generate(), add_task("")).await;
+    assert_eq!(res.into_response().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(state.queue.len_pending(), 0);
+}
+
+#[tokio::test]
+async fn add_task_rejects_oversized_submission_id() {
+    let state = state(Duration::from_secs(10));
+    let res = queue_add_task(
+        State(state.clone()),
+        RequestId::generate(),
+        add_task(&"a".repeat(17)),
+    )
+    .await;
+    assert_eq!(res.into_response().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(state.queue.len_pending(), 0);
+}
+
+#[tokio::test]
+async fn add_task_accepts_valid_submission_id() {
+    let state = state(Duration::from_secs(10));
+    let res = queue_add_task(
+        State(state.clone()),
+        RequestId::generate(),
+        add_task(&"a".repeat(16)),
+    )
+    .await;
+    assert_eq!(res.into_response().status(), StatusCode::OK);
+    assert_eq!(state.queue.len_pending(), 1);
+}
+
+#[tokio::test]
+async fn retried_add_task_is_enqueued_once() {
+    let state = state(Duration::from_secs(10));
+    let add = async |submission_id: &str, idempotency_key: Option<&str>| {
+        let mut task = add_task(submission_id);
+        task.idempotency_key = idempotency_key.map(str::to_owned);
+        let res = queue_add_task(State(state.clone()), RequestId::generate(), task).await;
+        res.into_response().status()
+    };
+
+    assert_eq!(add("a", Some("retry-1")).await, StatusCode::OK);
+    assert_eq!(add("a", Some("retry-1")).await, StatusCode::OK);
+    assert_eq!(state.queue.len_pending(), 1);
+    // NOTE: only the key counts, not the content
+    assert_eq!(add("a", Some("retry-2")).await, StatusCode::OK);
+    assert_eq!(add("a", None).await, StatusCode::OK);
+    assert_eq!(state.queue.len_pending(), 3);
+}
+
+#[test]
+fn resubmitted_results_are_kept_once() {
+    let results = ResultStore::new(Duration::from_secs(60), 2);
+    for i in 0..1_000 {
+        results.insert("a".to_owned(), i.to_string());
+    }
+    assert_eq!(results.len(), 1);
+    assert_eq!(results.get("a").as_deref(), Some("999"));
+
+    results.insert("b".to_owned(), "b".to_owned());
+    results.insert("a".to_owned(), "last".to_owned());
+    results.insert("c".to_owned(), "c".to_owned());
+    // NOTE: the overwrite made "a" newer than "b", so "b" is the one dropped
+    assert_eq!(results.len(), 2);
+    assert_eq!(results.get("a").as_deref(), Some("last"));
+    assert_eq!(results.get("b"), None);
+}
+
+#[test]
+fn seen_keys_expire_after_the_window() {
+    let keys = SeenKeys::new(Duration::from_millis(50), 16);
+    assert!(keys.insert("a"));
+    assert!(!keys.insert("a"));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(keys.insert("a"));
+}
+
+#[tokio::test]
+async fn completed_result_is_retrievable_until_expired() {
+    let state = state_with_result_ttl(Duration::from_secs(10), Duration::from_millis(100));
+    let res = queue_get_result(State(state.clone()), Path("a".to_owned())).await;
+    assert_eq!(res.unwrap_err().status(), StatusCode::NOT_FOUND);
+
+    let caller = tokio::spawn(queue_add_task_sync(
+        State(state.clone()),
+        RequestId::generate(),
+        add_task("a"),
+    ));
+    let (_, id) = state
+        .queue
+        .pop_with_timeout(Duration::from_secs(10))
+        .await
+        .unwrap();
+    let completed = QueueCompletedTask {
+        id,
+        info: "done".to_owned(),
+        request_id: None,
+    };
+    queue_submit_completed(State(state.clone()), Codec(Format::Json, completed))
+        .await
+        .unwrap();
+    let Json(completion) = caller.await.unwrap().unwrap();
+    assert_eq!(completion.info, "done");
+
+    let Json(result) = queue_get_result(State(state.clone()), Path("a".to_owned()))
+        .await
+        .unwrap();
+    assert_eq!(result.submission_id, "a");
+    assert_eq!(result.info, "done");
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let res = queue_get_result(State(state.clone()), Path("a".to_owned())).await;
+    assert_eq!(res.unwrap_err().status(), StatusCode::NOT_FOUND);
+}
+
+fn user_agent(headers: &HeaderMap) -> String {
+    headers
+        .get(USER_AGENT)
+        .map_or("", |agent| agent.to_str().unwrap())
+        .to_owned()
+}
+
+// Exploit storage answering with the caller's user agent, and a collector recording it
+async fn spawn_upstream() -> (String, Arc<Mutex<Vec<String>>>) {
+    let submitted = Arc::new(Mutex::new(vec![]));
+    let app = Router::new()
+        .route(
+            "/get_exploit/{key}",
+            get(async |headers: HeaderMap| user_agent(&headers)),
+        )
+        .route(
+            "/submit",
+            post({
+                let submitted = submitted.clone();
+                async move |headers: HeaderMap| {
+                    submitted.lock().unwrap().push(user_agent(&headers));
+                }
+            }),
+        );
+    let url = serve(app).await;
+    (url, submitted)
+}
+
+#[tokio::test]
+async fn shared_client_is_used_for_fetches_and_completions() {
+    let (url, submitted) = spawn_upstream().await;
+    let client = reqwest::Client::builder()
+        .user_agent("shared-client")
+        .build()
+        .unwrap();
+
+    let cache = Cache::<GetterStub, 30_000, 600_000>::new(GetterStub::new(client.clone(), &url));
+    assert_eq!(*cache.get("a").await.unwrap().body, "shared-client");
+
+    let state = state_with_client(client, Duration::from_secs(10), Duration::from_secs(60));
+    let mut task = add_task("a");
+    task.callback_url = Some(format!("{url}/submit"));
+    queue_add_task(State(state.clone()), RequestId::generate(), task)
+        .await
+        .unwrap();
+    let (_, id) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    let completed = QueueCompletedTask {
+        id,
+        info: "done".to_owned(),
+        request_id: None,
+    };
+    let res = queue_submit_completed(State(state), Codec(Format::Json, completed)).await;
+    assert_eq!(status_code(res), StatusCode::OK);
+    assert_eq!(*submitted.lock().unwrap(), ["shared-client"]);
+}
+
+#[tokio::test]
+async fn exploit_fetch_fails_at_request_timeout() {
+    let app = Router::new().route(
+        "/get_exploit/{key}",
+        get(async || tokio::time::sleep(Duration::from_secs(30)).await),
+    );
+    let url = serve(app).await;
+
+    let client = build_client(&HttpTimeouts {
+        http_connect_timeout_ms: 1_000,
+        http_request_timeout_ms: 100,
+    })
+    .unwrap();
+    let cache = Cache::<GetterStub, 30_000, 600_000>::new(GetterStub::new(client, url));
+    let started = Instant::now();
+    let res = cache.get("a").await;
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(matches!(
+        res,
+        Err(CacheError::Fetch(FetchError::Request(err))) if err.is_timeout()
+    ));
+}
+
+#[tokio::test]
+async fn numeric_keys_are_fetched_used_and_evicted() {
+    let app = Router::new().route(
+        "/get_exploit/{key}",
+        get(async |Path(key): Path<String>| key),
+    );
+    let url = serve(app).await;
+    let getter = NumericGetterStub::new(reqwest::Client::new(), url);
+    let cache = Cache::<NumericGetterStub, 30_000, 600_000>::new(getter);
+
+    assert_eq!(*cache.get(&0x2a).await.unwrap().body, "task2a");
+    assert_eq!(*cache.get(&0x2a).await.unwrap().body, "task2a");
+    assert_eq!((cache.stats().hits, cache.stats().misses), (1, 1));
+
+    let exploit = Exploit {
+        body: Arc::new("seven".to_owned()),
+        etag: None,
+    };
+    cache.set(7, exploit.clone()).unwrap();
+    assert!(matches!(cache.set(7, exploit), Err(CacheError::KeyExists)));
+    assert_eq!(*cache.get(&7).await.unwrap().body, "seven");
+
+    cache.add_usage(&7).unwrap();
+    assert_eq!(cache.usage_count(&7), Some(1));
+    assert!(cache.add_usage(&8).is_err());
+
+    let expired = cache.expire_now(false);
+    assert_eq!(expired.iter().map(|e| e.key).collect::<Vec<u64>>(), [0x2a]);
+    assert_eq!(cache.keys(), [7]);
+
+    cache.remove_usage(&7).unwrap();
+    assert_eq!(cache.usage_count(&7), Some(0));
+    let expired = cache.expire_now(false);
+    assert_eq!(expired.iter().map(|e| e.key).collect::<Vec<u64>>(), [7]);
+    assert!(cache.is_empty());
+    cache.check_invariant().unwrap();
+}
+
+// Answers every fetch with the HTTP version and the client address of its connection
+async fn spawn_connection_echo() -> String {
+    let app = Router::new().route(
+        "/get_exploit/{key}",
+        get(
+            async |ConnectInfo(peer): ConnectInfo<SocketAddr>, version: Version| {
+                format!("{version:?} {peer}")
+            },
+        ),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    url
+}
+
+#[tokio::test]
+async fn pooled_client_applies_its_pool_settings() {
+    let url = spawn_connection_echo().await;
+    let timeouts = HttpTimeouts {
+        http_connect_timeout_ms: 1_000,
+        http_request_timeout_ms: 1_000,
+    };
+    let fetch_twice = async |pool: HttpPool| {
+        let client = build_pooled_client(&timeouts, &pool).unwrap();
+        let getter = GetterStub::new(client, url.clone());
+        let first = getter.get("a").await.unwrap().body;
+        let second = getter.get("a").await.unwrap().body;
+        (first, second)
+    };
+
+    let http2 = HttpPool {
+        http2: true,
+        ..HttpPool::default()
+    };
+    let (first, second) = fetch_twice(http2).await;
+    assert!(first.starts_with("HTTP/2.0 "), "{first}");
+    assert_eq!(first, second);
+
+    // NOTE: without idle connections every fetch opens a new one
+    let no_idle = HttpPool {
+        pool_max_idle_per_host: 0,
+        ..HttpPool::default()
+    };
+    let (first, second) = fetch_twice(no_idle).await;
+    assert!(first.starts_with("HTTP/1.1 "), "{first}");
+    assert_ne!(first, second);
+}
+
+#[tokio::test]
+async fn raw_getter_caches_large_bodies_as_shared_bytes() {
+    const LEN: usize = 4 << 20;
+    let app = Router::new().route("/get_exploit/{key}", get(async || vec![7u8; LEN]));
+    let url = serve(app).await;
+
+    let getter = GetterStub::new(reqwest::Client::new(), url).with_max_payload_bytes(LEN);
+    let cache = Cache::<RawGetterStub, 30_000, 600_000>::new(RawGetterStub(getter));
+    let fetched = cache.get("a").await.unwrap();
+    assert_eq!(fetched.body.len(), LEN);
+    assert!(fetched.body.iter().all(|&byte| byte == 7));
+    let cached = cache.get("a").await.unwrap();
+    assert_eq!(cache.stats().hits, 1);
+    // NOTE: both point into the one buffer the body was read into
+    assert_eq!(cached.body.as_ptr(), fetched.body.as_ptr());
+}
+
+#[tokio::test]
+async fn oversized_exploits_are_rejected_with_and_without_length() {
+    let chunked = async || {
+        let chunks = std::iter::repeat_n(Ok::<_, Infallible>(vec![0u8; 64]), 4);
+        Body::from_stream(futures::stream::iter(chunks))
+    };
+    let app = Router::new()
+        .route("/sized/get_exploit/{key}", get(async || vec![0u8; 256]))
+        .route("/chunked/get_exploit/{key}", get(chunked))
+        .route("/fitting/get_exploit/{key}", get(async || vec![0u8; 100]));
+    let url = serve(app).await;
+    let getter = |path: &str| {
+        GetterStub::new(reqwest::Client::new(), format!("{url}/{path}")).with_max_payload_bytes(100)
+    };
+
+    for path in ["sized", "chunked"] {
+        let res = getter(path).get("a").await;
+        assert!(
+            matches!(res, Err(FetchError::TooLarge { limit: 100 })),
+            "{path}: {res:?}"
+        );
+    }
+    let exploit = getter("fitting").get("a").await.unwrap();
+    assert_eq!(exploit.body.len(), 100);
+}
+
+#[tokio::test]
+async fn invalid_utf8_exploits_are_decoded_lossily() {
+    let app = Router::new().route(
+        "/get_exploit/{key}",
+        get(async || vec![b'a', 0xff, b'b', 0xc3]),
+    );
+    let url = serve(app).await;
+    let exploit = GetterStub::new(reqwest::Client::new(), url)
+        .get("a")
+        .await
+        .unwrap();
+    assert_eq!(*exploit.body, "a\u{FFFD}b\u{FFFD}");
+}
+
+#[tokio::test]
+async fn processing_task_can_be_requeued_manually() {
+    let state = state(Duration::from_secs(10));
+    queue_add_task(State(state.clone()), RequestId::generate(), add_task("a"))
+        .await
+        .unwrap();
+    let (_, id) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+
+    let res = queue_requeue(State(state.clone()), Json(QueueTaskRef { id })).await;
+    assert_eq!(status_code(res), StatusCode::OK);
+    assert_eq!(state.queue.len_processing(), 0);
+    let (task, _) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(task.id, "a");
+
+    let res = queue_requeue(State(state.clone()), Json(QueueTaskRef { id })).await;
+    assert_eq!(status_code(res), StatusCode::NOT_FOUND);
+    let unknown = TaskId::from([0; 16]);
+    let res = queue_requeue(State(state), Json(QueueTaskRef { id: unknown })).await;
+    assert_eq!(status_code(res), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn warming_populates_the_cache() {
+    let (url, _) = spawn_upstream().await;
+    let cache = Arc::new(CacheState {
+        exploits: Cache::new(GetterStub::new(reqwest::Client::new(), url)),
+    });
+    cache.exploits.get("a").await.unwrap();
+
+    let keys = vec!["a".to_owned(), "b".to_owned(), "c".to_owned()];
+    let Json(result) = cache_warm(State(cache.clone()), Json(keys)).await;
+    assert_eq!(result.present, 1);
+    assert_eq!(result.fetched, 2);
+    assert_eq!(result.failed, 0);
+    for key in ["a", "b", "c"] {
+        let (_, meta) = cache.exploits.get_with_meta(key).await.unwrap();
+        assert!(meta.hit);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn get_task_answers_no_content_when_empty() {
+    let state = state(Duration::from_secs(10));
+    let cache = Arc::new(CacheState {
+        exploits: Cache::new(GetterStub::new(reqwest::Client::new(), "http://unused")),
+    });
+    let res = queue_get_task(
+        State(state),
+        State(cache),
+        Query::default(),
+        Accept::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn get_task_answers_ok_with_task() {
+    let (url, _) = spawn_upstream().await;
+    let state = state(Duration::from_secs(10));
+    let cache = Arc::new(CacheState {
+        exploits: Cache::new(GetterStub::new(reqwest::Client::new(), url)),
+    });
+    queue_add_task(State(state.clone()), RequestId::generate(), add_task("a"))
+        .await
+        .unwrap();
+    let res = queue_get_task(
+        State(state),
+        State(cache),
+        Query::default(),
+        Accept::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let task: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(task["submission_id"], "a");
+}
+
+// Exploit storage serving `v{version}` tagged with its version, answering 304 to a matching tag
+async fn spawn_tagged_upstream() -> (String, Arc<Mutex<u32>>, Arc<Mutex<Vec<StatusCode>>>) {
+    let version = Arc::new(Mutex::new(1));
+    let answered = Arc::new(Mutex::new(vec![]));
+    let app = Router::new().route(
+        "/get_exploit/{key}",
+        get({
+            let version = version.clone();
+            let answered = answered.clone();
+            async move |headers: HeaderMap| {
+                let version = *version.lock().unwrap();
+                let etag = format!("\"{version}\"");
+                let status = if headers.get(IF_NONE_MATCH).is_some_and(|tag| *tag == *etag) {
+                    StatusCode::NOT_MODIFIED
+                } else {
+                    StatusCode::OK
+                };
+                answered.lock().unwrap().push(status);
+                (status, [(ETAG, etag)], format!("v{version}"))
+            }
+        }),
+    );
+    let url = serve(app).await;
+    (url, version, answered)
+}
+
+#[tokio::test]
+async fn refresh_keeps_unmodified_exploit_and_renews_it() {
+    let (url, _, answered) = spawn_tagged_upstream().await;
+    let cache =
+        Cache::<GetterStub, 30_000, 600_000>::new(GetterStub::new(reqwest::Client::new(), url));
+    let exploit = cache.get("a").await.unwrap();
+    assert_eq!(exploit.etag.as_deref(), Some("\"1\""));
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let refreshed = cache.refresh("a").await.unwrap();
+    assert!(Arc::ptr_eq(&refreshed.body, &exploit.body));
+    assert_eq!(
+        *answered.lock().unwrap(),
+        [StatusCode::OK, StatusCode::NOT_MODIFIED]
+    );
+    let (_, meta) = cache.get_with_meta("a").await.unwrap();
+    assert!(meta.age < Duration::from_millis(50));
+}
+
+#[tokio::test]
+async fn refresh_replaces_modified_exploit() {
+    let (url, version, _) = spawn_tagged_upstream().await;
+    let cache =
+        Cache::<GetterStub, 30_000, 600_000>::new(GetterStub::new(reqwest::Client::new(), url));
+    cache.get("a").await.unwrap();
+
+    *version.lock().unwrap() = 2;
+    let refreshed = cache.refresh("a").await.unwrap();
+    assert_eq!(*refreshed.body, "v2");
+    assert_eq!(refreshed.etag.as_deref(), Some("\"2\""));
+    assert_eq!(*cache.get("a").await.unwrap().body, "v2");
+}
+
+#[tokio::test]
+async fn completion_is_decoded_from_json_and_msgpack() {
+    let completed = QueueCompletedTask {
+        id: TaskId::from([7; 16]),
+        info: "done".to_owned(),
+        request_id: None,
+    };
+    let bodies = [
+        (
+            "application/json",
+            serde_json::to_vec(&completed).unwrap(),
+            Format::Json,
+        ),
+        (
+            MSGPACK_CONTENT_TYPE,
+            rmp_serde::to_vec_named(&completed).unwrap(),
+            Format::MessagePack,
+        ),
+    ];
+    for (content_type, body, expected) in bodies {
+        let req = Request::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        let Codec(format, decoded) = Codec::<QueueCompletedTask>::from_request(req, &())
+            .await
+            .unwrap();
+        assert_eq!(format, expected);
+        assert_eq!(decoded.id, completed.id);
+        assert_eq!(decoded.info, "done");
+    }
+}
+
+#[tokio::test]
+async fn task_is_encoded_in_accepted_format() {
+    let (url, _) = spawn_upstream().await;
+    let state = state(Duration::from_secs(10));
+    let cache = Arc::new(CacheState {
+        exploits: Cache::new(GetterStub::new(reqwest::Client::new(), url)),
+    });
+    for submission_id in ["a", "b"] {
+        queue_add_task(
+            State(state.clone()),
+            RequestId::generate(),
+            add_task(submission_id),
+        )
+        .await
+        .unwrap();
+    }
+
+    let (mut parts, _) = Request::builder()
+        .header(ACCEPT, "application/msgpack; q=0.9, application/json")
+        .body(())
+        .unwrap()
+        .into_parts();
+    let accept = Accept::from_request_parts(&mut parts, &()).await.unwrap();
+    assert_eq!(accept, Accept(Format::MessagePack));
+    let res = queue_get_task(
+        State(state.clone()),
+        State(cache.clone()),
+        Query::default(),
+        accept,
+    )
+    .await
+    .unwrap();
+    assert_eq!(res.headers()[CONTENT_TYPE], MSGPACK_CONTENT_TYPE);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let task: QueueTask = rmp_serde::from_slice(&body).unwrap();
+    assert_eq!(task.submission_id, "a");
+
+    let res = queue_get_task(
+        State(state),
+        State(cache),
+        Query::default(),
+        Accept(Format::Json),
+    )
+    .await
+    .unwrap();
+    assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let task: QueueTask = serde_json::from_slice(&body).unwrap();
+    assert_eq!(task.submission_id, "b");
+}
+
+#[tokio::test]
+async fn request_id_flows_from_add_task_to_collector() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let app = Router::new().route(
+        "/submit",
+        post({
+            let received = received.clone();
+            async move |headers: HeaderMap, Json(completion): Json<QueueTaskCompletion>| {
+                let header = headers[REQUEST_ID_HEADER].to_str().unwrap().to_owned();
+                received
+                    .lock()
+                    .unwrap()
+                    .push((header, completion.request_id));
+            }
+        }),
+    );
+    let url = serve(app).await;
+
+    let state = state(Duration::from_secs(10));
+    let mut task = add_task("a");
+    task.callback_url = Some(format!("{url}/submit"));
+    let request_id = RequestId("trace-1".to_owned());
+    queue_add_task(State(state.clone()), request_id, task)
+        .await
+        .unwrap();
+    let (submission, id) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(submission.request_id, "trace-1");
+
+    let completed = QueueCompletedTask {
+        id,
+        info: "done".to_owned(),
+        request_id: Some(submission.request_id.clone()),
+    };
+    let res = queue_submit_completed(State(state), Codec(Format::Json, completed)).await;
+    assert_eq!(status_code(res), StatusCode::OK);
+    assert_eq!(
+        *received.lock().unwrap(),
+        [("trace-1".to_owned(), Some("trace-1".to_owned()))]
+    );
+}
+
+#[tokio::test]
+async fn callback_url_survives_restart() {
+    let received = Arc::new(Mutex::new(vec![]));
+    let app = Router::new().route(
+        "/hook",
+        post({
+            let received = received.clone();
+            async move |Json(completion): Json<QueueTaskCompletion>| {
+                received.lock().unwrap().push(completion.submission_id);
+            }
+        }),
+    );
+    let url = serve(app).await;
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let restart = || {
+        let client = reqwest::Client::new();
+        state_on_db(
+            db.clone(),
+            client,
+            Duration::from_secs(10),
+            Duration::from_secs(60),
+        )
+    };
+
+    let state = restart();
+    let mut task = add_task("a");
+    task.callback_url = Some(format!("{url}/hook"));
+    queue_add_task(State(state.clone()), RequestId::generate(), task)
+        .await
+        .unwrap();
+    drop(state);
+
+    let state = restart();
+    let (submission, id) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(submission.callback_url, Some(format!("{url}/hook")));
+    let completed = QueueCompletedTask {
+        id,
+        info: "done".to_owned(),
+        request_id: None,
+    };
+    let res = queue_submit_completed(State(state.clone()), Codec(Format::Json, completed)).await;
+    assert_eq!(status_code(res), StatusCode::OK);
+    assert_eq!(*received.lock().unwrap(), ["a"]);
+    assert_eq!(state.queue.len_pending(), 0);
+}
+
+#[tokio::test]
+async fn missing_request_id_is_generated() {
+    let (mut parts, _) = Request::builder()
+        .header(REQUEST_ID_HEADER, "given")
+        .body(())
+        .unwrap()
+        .into_parts();
+    let given = RequestId::from_request_parts(&mut parts, &())
+        .await
+        .unwrap();
+    assert_eq!(given, RequestId("given".to_owned()));
+
+    let (mut parts, _) = Request::builder().body(()).unwrap().into_parts();
+    let RequestId(generated) = RequestId::from_request_parts(&mut parts, &())
+        .await
+        .unwrap();
+    assert!(!generated.is_empty());
+}
+
+#[test]
+fn submissions_without_request_id_are_migrated() {
+    #[derive(serde::Serialize)]
+    struct SubmissionV1 {
+        id: String,
+        exploit_key: String,
+        priority: u8,
+        callback_url: Option<String>,
+    }
+    let old = SubmissionV1 {
+        id: "a".to_owned(),
+        exploit_key: "key".to_owned(),
+        priority: 3,
+        callback_url: None,
+    };
+    let payload = bincode::serde::encode_to_vec(&old, bincode::config::standard()).unwrap();
+    let submission = migrate_submission(1, &payload).unwrap();
+    assert_eq!(submission.id, "a");
+    assert_eq!(submission.exploit_key, "key");
+    assert_eq!(submission.priority, 3);
+    assert!(!submission.request_id.is_empty());
+    assert!(migrate_submission(QUEUE_FORMAT_VERSION + 1, &payload).is_none());
+}
+
+#[tokio::test]
+async fn processing_time_is_reported() {
+    let state = state(Duration::from_secs(10));
+    let Json(empty) = queue_processing_time(State(state.clone())).await;
+    assert_eq!(empty.count, 0);
+
+    queue_add_task(State(state.clone()), RequestId::generate(), add_task("a"))
+        .await
+        .unwrap();
+    let (_, id) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    state.queue.submit_completed(&id).unwrap();
+    let Json(reported) = queue_processing_time(State(state)).await;
+    assert_eq!(reported.count, 1);
+    assert!(reported.min_ms >= 20.0);
+    assert_eq!(reported.min_ms, reported.max_ms);
+}
+
+#[tokio::test]
+async fn events_are_served_oldest_first() {
+    let state = state(Duration::from_secs(10));
+    queue_add_task(State(state.clone()), RequestId::generate(), add_task("a"))
+        .await
+        .unwrap();
+    let (_, id) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+
+    let Json(events) = queue_events(State(state)).await;
+    assert_eq!(events.len(), 2);
+    assert!(matches!(events[0].kind, QueueEventKind::Pushed));
+    assert_eq!(events[0].task_id, None);
+    assert!(matches!(events[1].kind, QueueEventKind::Popped));
+    assert_eq!(events[1].task_id, Some(hex::encode(id.to_bytes())));
+    assert!(events.iter().all(|event| event.submission_id == "a"));
+}
+
+#[tokio::test]
+async fn cache_admin_routes_report_and_invalidate() {
+    let (url, _) = spawn_upstream().await;
+    let cache = Arc::new(CacheState {
+        exploits: Cache::new(GetterStub::new(reqwest::Client::new(), url)),
+    });
+    cache.exploits.get("a").await.unwrap();
+    cache.exploits.get("b").await.unwrap();
+    cache.exploits.get("a").await.unwrap();
+
+    let Json(stats) = cache_stats(State(cache.clone())).await;
+    assert_eq!((stats.hits, stats.misses, stats.len), (1, 2, 2));
+    let Json(mut keys) = cache_keys(State(cache.clone())).await;
+    keys.sort();
+    assert_eq!(keys, ["a", "b"]);
+
+    let res = cache_invalidate(State(cache.clone()), Path("a".to_owned())).await;
+    assert_eq!(status_code(res), StatusCode::OK);
+    let res = cache_invalidate(State(cache.clone()), Path("a".to_owned())).await;
+    assert_eq!(status_code(res), StatusCode::NOT_FOUND);
+    let Json(keys) = cache_keys(State(cache)).await;
+    assert_eq!(keys, ["b"]);
+}
+
+#[tokio::test]
+async fn get_task_includes_exploit_only_when_asked() {
+    let (url, _) = spawn_upstream().await;
+    let state = state(Duration::from_secs(10));
+    let cache = Arc::new(CacheState {
+        exploits: Cache::new(GetterStub::new(reqwest::Client::new(), url)),
+    });
+    for submission_id in ["a", "b"] {
+        queue_add_task(
+            State(state.clone()),
+            RequestId::generate(),
+            add_task(submission_id),
+        )
+        .await
+        .unwrap();
+    }
+
+    let params: Query<QueueGetTaskParams> =
+        Query::try_from_uri(&"/get_task?include_exploit=false".parse().unwrap()).unwrap();
+    let res = queue_get_task(
+        State(state.clone()),
+        State(cache.clone()),
+        params,
+        Accept::default(),
+    )
+    .await
+    .unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let task: QueueTask = serde_json::from_slice(&body).unwrap();
+    assert_eq!(task.submission_id, "a");
+    assert_eq!(task.exploit, None);
+    assert!(cache.exploits.is_empty());
+
+    let params: Query<QueueGetTaskParams> =
+        Query::try_from_uri(&"/get_task".parse().unwrap()).unwrap();
+    let res = queue_get_task(
+        State(state),
+        State(cache.clone()),
+        params,
+        Accept::default(),
+    )
+    .await
+    .unwrap();
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let task: QueueTask = serde_json::from_slice(&body).unwrap();
+    assert_eq!(task.submission_id, "b");
+    assert_eq!(task.exploit.as_deref().map(String::as_str), Some(""));
+    assert_eq!(cache.exploits.len(), 1);
+}
+
+// Exploit storage answering 500 while `failing` is set, counting the requests it got
+async fn spawn_flaky_upstream() -> (String, Arc<AtomicBool>, Arc<AtomicUsize>) {
+    let failing = Arc::new(AtomicBool::new(true));
+    let requests = Arc::new(AtomicUsize::new(0));
+    let app = Router::new().route(
+        "/get_exploit/{key}",
+        get({
+            let failing = failing.clone();
+            let requests = requests.clone();
+            async move || {
+                requests.fetch_add(1, Ordering::Relaxed);
+                if failing.load(Ordering::Relaxed) {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::OK
+                }
+            }
+        }),
+    );
+    let url = serve(app).await;
+    (url, failing, requests)
+}
+
+#[tokio::test]
+async fn repeated_fetch_failures_open_the_circuit() {
+    let (url, failing, requests) = spawn_flaky_upstream().await;
+    let getter = GetterStub::new(reqwest::Client::new(), url)
+        .with_circuit_breaker(NonZeroU32::new(3).unwrap(), Duration::from_millis(100));
+    for _ in 0..3 {
+        assert!(matches!(
+            getter.get("a").await,
+            Err(FetchError::Request(err)) if err.is_status()
+        ));
+    }
+    assert!(matches!(
+        getter.get("a").await,
+        Err(FetchError::CircuitOpen)
+    ));
+    assert_eq!(requests.load(Ordering::Relaxed), 3);
+
+    // NOTE: a failed trial after the cooldown opens the circuit again
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert!(matches!(getter.get("a").await, Err(FetchError::Request(_))));
+    assert!(matches!(
+        getter.get("a").await,
+        Err(FetchError::CircuitOpen)
+    ));
+    assert_eq!(requests.load(Ordering::Relaxed), 4);
+
+    failing.store(false, Ordering::Relaxed);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    getter.get("a").await.unwrap();
+    getter.get("b").await.unwrap();
+    assert_eq!(requests.load(Ordering::Relaxed), 6);
+}
+
+#[tokio::test]
+async fn collector_failure_applies_policy_to_the_task() {
+    let app = Router::new().route("/submit", post(async || StatusCode::INTERNAL_SERVER_ERROR));
+    let url = format!("{}/submit", serve(app).await);
+
+    for policy in [
+        TimeoutAction::Requeue,
+        TimeoutAction::DeadLetter,
+        TimeoutAction::Drop,
+    ] {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let state = Arc::new(QueueState {
+            queue: MainQueue::new(db.clone()),
+            sync_timeout: Duration::from_secs(10),
+            max_submission_id_len: 16,
+            completions: Box::new(HttpSink::new(reqwest::Client::new(), url.clone())),
+            collector_failure: policy,
+            completion_waiters: Default::default(),
+            results: ResultStore::new(Duration::from_secs(60), 16),
+            idempotency_keys: SeenKeys::new(Duration::from_secs(60), 16),
+        });
+        queue_add_task(State(state.clone()), RequestId::generate(), add_task("a"))
+            .await
+            .unwrap();
+        let (_, id) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+        let completed = QueueCompletedTask {
+            id,
+            info: "done".to_owned(),
+            request_id: None,
+        };
+        let res =
+            queue_submit_completed(State(state.clone()), Codec(Format::Json, completed)).await;
+        assert_eq!(status_code(res), StatusCode::BAD_GATEWAY, "{policy:?}");
+        assert_eq!(state.queue.len_processing(), 0);
+        let res = queue_get_result(State(state.clone()), Path("a".to_owned())).await;
+        assert_eq!(status_code(res), StatusCode::NOT_FOUND, "{policy:?}");
+
+        let (pending, dead_letter) = match policy {
+            TimeoutAction::Requeue => (1, 0),
+            TimeoutAction::DeadLetter => (0, 1),
+            TimeoutAction::Drop => (0, 0),
+        };
+        assert_eq!(state.queue.len_pending(), pending, "{policy:?}");
+        assert_eq!(state.queue.len_dead_letter(), dead_letter, "{policy:?}");
+        // NOTE: the same tasks come back after a restart
+        drop(state);
+        let restarted = MainQueue::new(db);
+        assert_eq!(restarted.len_pending(), pending, "{policy:?}");
+        assert_eq!(restarted.len_dead_letter(), dead_letter, "{policy:?}");
+    }
+}
+
+#[tokio::test]
+async fn completions_are_delivered_to_an_in_process_sink() {
+    let (sink, mut completions) = ChannelSink::new();
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let state = Arc::new(QueueState {
+        queue: MainQueue::new(db),
+        sync_timeout: Duration::from_secs(10),
+        max_submission_id_len: 16,
+        completions: Box::new(sink),
+        collector_failure: TimeoutAction::Requeue,
+        completion_waiters: Default::default(),
+        results: ResultStore::new(Duration::from_secs(60), 16),
+        idempotency_keys: SeenKeys::new(Duration::from_secs(60), 16),
+    });
+    let complete = async |submission_id: &str| {
+        let mut task = add_task(submission_id);
+        // NOTE: never contacted, the sink has no use for urls
+        task.callback_url = Some("http://unused".to_owned());
+        let request_id = RequestId(format!("trace-{submission_id}"));
+        queue_add_task(State(state.clone()), request_id, task)
+            .await
+            .unwrap();
+        let (_, id) = state.queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+        let completed = QueueCompletedTask {
+            id,
+            info: "done".to_owned(),
+            request_id: None,
+        };
+        queue_submit_completed(State(state.clone()), Codec(Format::Json, completed)).await
+    };
+
+    let res = complete("a").await;
+    assert_eq!(status_code(res), StatusCode::OK);
+    let completion = completions.try_recv().unwrap();
+    assert_eq!(completion.submission_id, "a");
+    assert_eq!(completion.info, "done");
+    assert_eq!(completion.request_id.as_deref(), Some("trace-a"));
+
+    drop(completions);
+    let res = complete("b").await;
+    assert_eq!(status_code(res), StatusCode::BAD_GATEWAY);
+    assert_eq!(state.queue.len_pending(), 1);
+}
+
+#[tokio::test]
+async fn get_task_waits_for_a_free_slot_at_max_in_flight() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let state = Arc::new(QueueState {
+        queue: MainQueue::new(db).with_max_in_flight(NonZeroUsize::new(1).unwrap()),
+        sync_timeout: Duration::from_secs(10),
+        max_submission_id_len: 16,
+        completions: Box::new(HttpSink::new(
+            reqwest::Client::new(),
+            "http://localhost:3002/submit",
+        )),
+        collector_failure: TimeoutAction::Requeue,
+        completion_waiters: Default::default(),
+        results: ResultStore::new(Duration::from_secs(60), 16),
+        idempotency_keys: SeenKeys::new(Duration::from_secs(60), 16),
+    });
+    let cache = Arc::new(CacheState {
+        exploits: Cache::new(GetterStub::new(reqwest::Client::new(), "http://unused")),
+    });
+    for submission_id in ["a", "b"] {
+        queue_add_task(
+            State(state.clone()),
+            RequestId::generate(),
+            add_task(submission_id),
+        )
+        .await
+        .unwrap();
+    }
+    let get_task = || {
+        queue_get_task(
+            State(state.clone()),
+            State(cache.clone()),
+            Query(QueueGetTaskParams {
+                include_exploit: false,
+            }),
+            Accept::default(),
+        )
+    };
+    let first = get_task().await.unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(first.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let first: QueueTask = serde_json::from_slice(&body).unwrap();
+    assert_eq!(first.submission_id, "a");
+
+    let second = tokio::spawn(get_task());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!second.is_finished());
+    assert_eq!(state.queue.len_pending(), 1);
+
+    // NOTE: the completion goes to an unreachable collector, the waiter answer is enough here
+    let (tx, _rx) = tokio::sync::oneshot::channel();
+    state
+        .completion_waiters
+        .lock()
+        .unwrap()
+        .insert("a".to_owned(), tx);
+    let completed = QueueCompletedTask {
+        id: first.id,
+        info: "done".to_owned(),
+        request_id: None,
+    };
+    let res = queue_submit_completed(State(state.clone()), Codec(Format::Json, completed)).await;
+    assert_eq!(status_code(res), StatusCode::OK);
+
+    let second = tokio::time::timeout(Duration::from_secs(1), second)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let body = axum::body::to_bytes(second.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let second: QueueTask = serde_json::from_slice(&body).unwrap();
+    assert_eq!(second.submission_id, "b");
+}
+
+#[tokio::test]
+async fn flush_reports_flushed_bytes() {
+    // NOTE: without sled's background flush, which could write the push out first
+    let db = sled::Config::new()
+        .temporary(true)
+        .flush_every_ms(None)
+        .open()
+        .unwrap();
+    let state = Arc::new(QueueState {
+        queue: MainQueue::new(db),
+        sync_timeout: Duration::from_secs(10),
+        max_submission_id_len: 16,
+        completions: Box::new(HttpSink::new(
+            reqwest::Client::new(),
+            "http://localhost:3002/submit",
+        )),
+        collector_failure: TimeoutAction::Requeue,
+        completion_waiters: Default::default(),
+        results: ResultStore::new(Duration::from_secs(60), 16),
+        idempotency_keys: SeenKeys::new(Duration::from_secs(60), 16),
+    });
+    queue_add_task(State(state.clone()), RequestId::generate(), add_task("a"))
+        .await
+        .unwrap();
+    let Json(res) = queue_flush(State(state.clone())).await;
+    assert!(res.flushed_bytes > 0);
+    // NOTE: nothing changed since, so there is nothing left to write
+    let Json(res) = queue_flush(State(state)).await;
+    assert_eq!(res.flushed_bytes, 0);
+}
+
+#[tokio::test]
+async fn fail_requeues_until_out_of_attempts() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let state = Arc::new(QueueState {
+        queue: MainQueue::new(db).with_max_attempts(NonZeroU32::new(2).unwrap()),
+        sync_timeout: Duration::from_secs(10),
+        max_submission_id_len: 16,
+        completions: Box::new(HttpSink::new(
+            reqwest::Client::new(),
+            "http://localhost:3002/submit",
+        )),
+        collector_failure: TimeoutAction::Requeue,
+        completion_waiters: Default::default(),
+        results: ResultStore::new(Duration::from_secs(60), 16),
+        idempotency_keys: SeenKeys::new(Duration::from_secs(60), 16),
+    });
+    queue_add_task(State(state.clone()), RequestId::generate(), add_task("a"))
+        .await
+        .unwrap();
+    let fail = |id| {
+        let failed = QueueFailedTask {
+            id,
+            reason: "unsupported exploit".into(),
+        };
+        queue_fail(State(state.clone()), Codec(Format::Json, failed))
+    };
+
+    let (_, id, attempt) = state
+        .queue
+        .pop_with_attempt(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(attempt, 1);
+    assert_eq!(status_code(fail(id).await), StatusCode::OK);
+    assert_eq!(state.queue.len_pending(), 1);
+    assert_eq!(state.queue.len_processing(), 0);
+    assert_eq!(state.queue.len_dead_letter(), 0);
+    assert_eq!(status_code(fail(id).await), StatusCode::GONE);
+
+    let (_, id, attempt) = state
+        .queue
+        .pop_with_attempt(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(attempt, 2);
+    assert_eq!(status_code(fail(id).await), StatusCode::OK);
+    assert_eq!(state.queue.len_pending(), 0);
+    assert_eq!(state.queue.len_dead_letter(), 1);
+}
+
+#[tokio::test]
+async fn oversized_bodies_are_rejected_per_route() {
+    let state = AppState {
+        api: state(Duration::from_secs(10)),
+        cache: Arc::new(CacheState {
+            exploits: Cache::new(GetterStub::new(reqwest::Client::new(), "http://unused")),
+        }),
+    };
+    let limits = BodyLimits {
+        default: 1024,
+        bulk: 8 * 1024,
+    };
+    let app = Router::new()
+        .nest("/queue", routes_with_body_limits(limits))
+        .with_state(state.clone());
+    let url = format!("{}/queue", serve(app).await);
+    let client = reqwest::Client::new();
+    let task = |submission_id: &str| QueueAddTask {
+        submission_id: submission_id.to_owned(),
+        exploit_key: Some("x".repeat(100)),
+        priority: 0,
+        callback_url: None,
+        idempotency_key: None,
+    };
+    let tasks: Vec<_> = (0..20).map(|i| task(&i.to_string())).collect();
+
+    let res = client
+        .post(format!("{url}/add_tasks"))
+        .json(&tasks)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(state.api.queue.len_pending(), 20);
+
+    let mut oversized = task("big");
+    oversized.exploit_key = Some("x".repeat(2 * 1024));
+    let res = client
+        .post(format!("{url}/add_task"))
+        .json(&oversized)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let tasks: Vec<_> = (0..100).map(|i| task(&i.to_string())).collect();
+    let res = client
+        .post(format!("{url}/add_tasks"))
+        .json(&tasks)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(state.api.queue.len_pending(), 20);
+}
+
+// Upstream that never answers in time, neither exploit fetches nor completions
+async fn spawn_stalled_upstream() -> String {
+    let stall = async || tokio::time::sleep(Duration::from_secs(30)).await;
+    let app = Router::new()
+        .route("/get_exploit/{key}", get(stall))
+        .route("/submit", post(stall));
+    serve(app).await
+}
+
+#[tokio::test]
+async fn stalled_requests_time_out_except_long_polls() {
+    let upstream = spawn_stalled_upstream().await;
+    let state = AppState {
+        api: state(Duration::from_secs(10)),
+        cache: Arc::new(CacheState {
+            exploits: Cache::new(GetterStub::new(reqwest::Client::new(), &upstream)),
+        }),
+    };
+    let limit = Duration::from_millis(100);
+    let app = Router::new()
+        .nest("/queue", routes_with_limits(BodyLimits::default(), limit))
+        .nest("/cache", cache_routes_with_timeout(limit))
+        .with_state(state.clone());
+    let url = serve(app).await;
+    let client = reqwest::Client::new();
+
+    let started = Instant::now();
+    let res = client
+        .post(format!("{url}/cache/warm"))
+        .json(&["a"])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body: ApiErrorBody = res.json().await.unwrap();
+    assert_eq!(body.error, "Request timed out");
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // NOTE: get_task outlives the limit while it waits for a task
+    let poll = tokio::spawn(
+        client
+            .get(format!("{url}/queue/get_task"))
+            .query(&[("include_exploit", "false")])
+            .send(),
+    );
+    tokio::time::sleep(limit * 3).await;
+    let mut task = add_task("a").0;
+    task.callback_url = Some(format!("{upstream}/submit"));
+    let res = client
+        .post(format!("{url}/queue/add_task"))
+        .json(&task)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = poll.await.unwrap().unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let task: QueueTask = res.json().await.unwrap();
+
+    let completed = QueueCompletedTask {
+        id: task.id,
+        info: "done".to_owned(),
+        request_id: None,
+    };
+    let started = Instant::now();
+    let res = client
+        .post(format!("{url}/queue/submit_completed"))
+        .json(&completed)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    // NOTE: the dropped completion went back to the queue
+    assert_eq!(state.api.queue.len_pending(), 1);
+}
+
+#[tokio::test]
+async fn api_errors_map_to_status_and_json_body() {
+    let cases = [
+        (
+            ApiError::Upstream("down".into()),
+            StatusCode::BAD_GATEWAY,
+            "down",
+        ),
+        (
+            ApiError::QueueFull,
+            StatusCode::TOO_MANY_REQUESTS,
+            "Queue is full",
+        ),
+        (
+            ApiError::BadRequest("bad id".into()),
+            StatusCode::BAD_REQUEST,
+            "bad id",
+        ),
+        (
+            ApiError::NotFound("missing".into()),
+            StatusCode::NOT_FOUND,
+            "missing",
+        ),
+        (
+            ApiError::Internal("oops".into()),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "oops",
+        ),
+        (
+            ApiError::Conflict("twice".into()),
+            StatusCode::CONFLICT,
+            "twice",
+        ),
+        (ApiError::Gone("late".into()), StatusCode::GONE, "late"),
+        (
+            ApiError::Timeout("slow".into()),
+            StatusCode::GATEWAY_TIMEOUT,
+            "slow",
+        ),
+    ];
+    for (err, status, message) in cases {
+        assert_eq!(err.status(), status);
+        let res = err.into_response();
+        assert_eq!(res.status(), status);
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/json");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: ApiErrorBody = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body.error, message);
+    }
+}
+
+#[tokio::test]
+async fn handler_errors_carry_a_json_body() {
+    let state = state(Duration::from_secs(10));
+    let res = queue_heartbeat(
+        State(state.clone()),
+        Json(QueueTaskRef {
+            id: TaskId::from([0; 16]),
+        }),
+    )
+    .await
+    .into_response();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let body: ApiErrorBody = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body.error, "Task is not being processed");
+
+    let mut task = add_task("a").0;
+    task.submission_id = "x".repeat(17);
+    let res = queue_add_task(State(state), RequestId::generate(), Json(task)).await;
+    assert_eq!(res.unwrap_err().status(), StatusCode::BAD_REQUEST);
+}
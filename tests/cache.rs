@@ -0,0 +1,958 @@
+use std::{
+    cell::Cell,
+    convert::Infallible,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use proptest::prelude::*;
+use queues_demo::cache::{
+    Cache, CacheError, CacheStats, DataGetter, EvictionCandidate, EvictionPolicy, ExpireKind,
+    TimeExpiry,
+};
+
+#[derive(Debug, Default)]
+struct UnreachableGetter;
+
+impl DataGetter for UnreachableGetter {
+    type Key = String;
+    type BorrowedKey = str;
+    type Value = u32;
+    type Error = Infallible;
+    async fn get(&self, _key: &str) -> Result<u32, Infallible> {
+        unreachable!("get_or_insert_with must not fall back to the getter")
+    }
+}
+
+type TestCache = Cache<UnreachableGetter, 30_000, 600_000>;
+
+#[test]
+fn get_or_insert_with_runs_closure_on_miss() {
+    let cache = TestCache::default();
+    let calls = Cell::new(0);
+    let value = cache.get_or_insert_with("a", || {
+        calls.set(calls.get() + 1);
+        1
+    });
+    let value = value.unwrap();
+    assert_eq!(value, 1);
+    assert_eq!(calls.get(), 1);
+}
+
+#[test]
+fn get_or_insert_with_skips_closure_on_hit() {
+    let cache = TestCache::default();
+    cache.get_or_insert_with("a", || 1).unwrap();
+    let value = cache
+        .get_or_insert_with("a", || panic!("closure ran on a hit"))
+        .unwrap();
+    assert_eq!(value, 1);
+}
+
+#[test]
+fn get_or_insert_with_keeps_existing_value() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    assert_eq!(cache.get_or_insert_with("a", || 2).unwrap(), 1);
+}
+
+#[test]
+fn usage_count_follows_add_and_remove_usage() {
+    let cache = TestCache::default();
+    assert_eq!(cache.usage_count("a"), None);
+    cache.set("a".to_owned(), 1).unwrap();
+    assert_eq!(cache.usage_count("a"), Some(0));
+    cache.add_usage("a").unwrap();
+    cache.add_usage("a").unwrap();
+    assert_eq!(cache.usage_count("a"), Some(2));
+    cache.remove_usage("a").unwrap();
+    assert_eq!(cache.usage_count("a"), Some(1));
+    cache.remove_usage("a").unwrap();
+    assert_eq!(cache.usage_count("a"), Some(0));
+}
+
+#[tokio::test]
+async fn leaked_usages_reports_usages_older_than_threshold() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.set("b".to_owned(), 2).unwrap();
+    cache.add_usage("a").unwrap();
+    assert!(cache.leaked_usages(Duration::from_millis(50)).is_empty());
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    cache.add_usage("a").unwrap();
+    cache.add_usage("b").unwrap();
+    assert_eq!(
+        cache.leaked_usages(Duration::from_millis(50)),
+        [("a".to_owned(), 2)]
+    );
+
+    cache.remove_usage("a").unwrap();
+    cache.remove_usage("a").unwrap();
+    assert!(cache.leaked_usages(Duration::from_millis(50)).is_empty());
+}
+
+#[test]
+fn usages_move_entries_between_idle_and_used() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.add_usage("a").unwrap();
+    cache.add_usage("a").unwrap();
+    assert!(cache.expire_now(false).is_empty());
+    assert_eq!(cache.leaked_usages(Duration::ZERO), [("a".to_owned(), 2)]);
+
+    cache.remove_usage("a").unwrap();
+    assert!(cache.expire_now(false).is_empty());
+
+    cache.remove_usage("a").unwrap();
+    assert!(cache.leaked_usages(Duration::ZERO).is_empty());
+    let expires = cache.expire_now(false);
+    assert_eq!(expires.len(), 1);
+    assert_eq!(expires[0].key, "a");
+    assert_eq!(expires[0].kind, ExpireKind::Idle);
+}
+
+#[test]
+fn remove_usage_underflows_on_idle_entry() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    assert!(matches!(
+        cache.remove_usage("a"),
+        Err(CacheError::UsageUnderflow)
+    ));
+    assert!(matches!(cache.add_usage("b"), Err(CacheError::KeyNotFound)));
+}
+
+#[test]
+fn expire_now_keeps_used_entries_unless_included() {
+    let cache = TestCache::default();
+    for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+        cache.set(key.to_owned(), value).unwrap();
+    }
+    cache.add_usage("c").unwrap();
+    cache.add_usage("c").unwrap();
+
+    let mut expires = cache.expire_now(false);
+    expires.sort_by(|a, b| a.key.cmp(&b.key));
+    let expired: Vec<_> = expires.iter().map(|e| (e.key.as_str(), e.kind)).collect();
+    assert_eq!(expired, [("a", ExpireKind::Idle), ("b", ExpireKind::Idle)]);
+    assert_eq!(cache.keys(), ["c"]);
+    assert_eq!(cache.usage_count("c"), Some(2));
+    assert_eq!(cache.check_invariant(), Ok(()));
+
+    let expires = cache.expire_now(true);
+    assert_eq!(expires.len(), 1);
+    assert_eq!((expires[0].key.as_str(), expires[0].usages), ("c", 2));
+    assert_eq!(expires[0].kind, ExpireKind::Used);
+    assert!(cache.is_empty());
+    assert_eq!(cache.check_invariant(), Ok(()));
+}
+
+#[tokio::test]
+async fn sweep_reports_idle_and_used_expirations_by_kind() {
+    let cache =
+        TestCache::default().with_expiry(Duration::from_millis(10), Duration::from_millis(10));
+    cache.set("idle".to_owned(), 1).unwrap();
+    cache.set("used".to_owned(), 2).unwrap();
+    cache.add_usage("used").unwrap();
+    assert!(cache.evict_expired().is_empty());
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let mut expires = cache.evict_expired();
+    expires.sort_by(|a, b| a.key.cmp(&b.key));
+    let expired: Vec<_> = expires
+        .iter()
+        .map(|e| (e.key.as_str(), e.usages, e.kind))
+        .collect();
+    assert_eq!(
+        expired,
+        [("idle", 0, ExpireKind::Idle), ("used", 1, ExpireKind::Used)]
+    );
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn every_subscriber_receives_swept_expirations() {
+    let cache = TestCache::default().with_expiry(Duration::ZERO, Duration::ZERO);
+    let mut logger = cache.subscribe_expirations();
+    let mut metrics = cache.subscribe_expirations();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.set("b".to_owned(), 2).unwrap();
+    cache.add_usage("b").unwrap();
+    assert!(logger.try_recv().is_err());
+
+    let returned = cache.evict_expired();
+    assert_eq!(returned.len(), 2);
+    for receiver in [&mut logger, &mut metrics] {
+        for expected in &returned {
+            let expire = receiver.try_recv().unwrap();
+            assert_eq!(
+                (&expire.key, expire.usages),
+                (&expected.key, expected.usages)
+            );
+            assert_eq!(expire.kind, expected.kind);
+        }
+        assert!(receiver.try_recv().is_err());
+    }
+}
+
+#[derive(Debug, Default)]
+struct LenGetter;
+
+impl DataGetter for LenGetter {
+    type Key = String;
+    type BorrowedKey = str;
+    type Value = usize;
+    type Error = Infallible;
+    async fn get(&self, key: &str) -> Result<usize, Infallible> {
+        Ok(key.len())
+    }
+}
+
+#[tokio::test]
+async fn get_with_meta_reports_fetch_then_hit() {
+    let cache = Cache::<LenGetter, 30_000, 600_000>::default();
+    let (value, meta) = cache.get_with_meta("abc").await.unwrap();
+    assert_eq!(value, 3);
+    assert!(!meta.hit);
+    assert_eq!(meta.age, Duration::ZERO);
+    assert_eq!(meta.usages, 0);
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    cache.add_usage("abc").unwrap();
+    let (value, meta) = cache.get_with_meta("abc").await.unwrap();
+    assert_eq!(value, 3);
+    assert!(meta.hit);
+    assert!(meta.age < Duration::from_millis(20));
+    assert_eq!(meta.usages, 1);
+}
+
+#[tokio::test]
+async fn get_with_meta_renews_idle_entries() {
+    let cache = Cache::<LenGetter, 30_000, 600_000>::default();
+    cache.get("abc").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let (_, meta) = cache.get_with_meta("abc").await.unwrap();
+    assert!(meta.age >= Duration::from_millis(20));
+    let (_, meta) = cache.get_with_meta("abc").await.unwrap();
+    assert!(meta.age < Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn operations_after_close_are_rejected() {
+    let cache = Cache::<LenGetter, 30_000, 600_000>::default();
+    cache.get("abc").await.unwrap();
+    cache.add_usage("abc").unwrap();
+    cache.close();
+    assert!(cache.is_closed());
+
+    assert!(matches!(cache.get("abc").await, Err(CacheError::Closed)));
+    assert!(matches!(cache.get("new").await, Err(CacheError::Closed)));
+    assert!(matches!(
+        cache.get_with_meta("abc").await,
+        Err(CacheError::Closed)
+    ));
+    assert!(matches!(
+        cache.set("new".to_owned(), 3),
+        Err(CacheError::Closed)
+    ));
+    assert!(matches!(cache.add_usage("abc"), Err(CacheError::Closed)));
+    assert!(matches!(cache.remove_usage("abc"), Err(CacheError::Closed)));
+    assert!(matches!(
+        cache.get_or_insert_with("new", || 3),
+        Err(CacheError::Closed)
+    ));
+}
+
+#[derive(Debug, Default)]
+struct SlowGetter;
+
+impl DataGetter for SlowGetter {
+    type Key = String;
+    type BorrowedKey = str;
+    type Value = u32;
+    type Error = Infallible;
+    async fn get(&self, _key: &str) -> Result<u32, Infallible> {
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        Ok(0)
+    }
+}
+
+#[tokio::test]
+async fn fetch_latency_is_recorded_in_its_bucket() {
+    let cache = Cache::<SlowGetter, 30_000, 600_000>::default();
+    cache.get("a").await.unwrap();
+    cache.get("a").await.unwrap();
+
+    let histogram = cache.fetch_latency_histogram();
+    assert_eq!(histogram.last().unwrap().le, None);
+    let recorded: Vec<_> = histogram.iter().filter(|bucket| bucket.count > 0).collect();
+    // NOTE: the hit is not a fetch, 30ms sleeps land in the (25ms, 50ms] bucket unless the
+    // runtime stalls badly
+    assert_eq!(recorded.len(), 1);
+    assert_eq!(recorded[0].count, 1);
+    assert_eq!(recorded[0].le, Some(Duration::from_millis(50)));
+}
+
+// Each fetch waits for the other one, so both are misses, then returns its own value
+#[derive(Debug)]
+struct RacingGetter {
+    barrier: tokio::sync::Barrier,
+    calls: AtomicUsize,
+}
+
+impl DataGetter for RacingGetter {
+    type Key = String;
+    type BorrowedKey = str;
+    type Value = usize;
+    type Error = Infallible;
+    async fn get(&self, _key: &str) -> Result<usize, Infallible> {
+        let value = self.calls.fetch_add(1, Ordering::Relaxed);
+        self.barrier.wait().await;
+        Ok(value)
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn concurrent_misses_converge_on_one_value() {
+    let cache = Arc::new(Cache::<_, 30_000, 600_000>::new(RacingGetter {
+        barrier: tokio::sync::Barrier::new(2),
+        calls: AtomicUsize::new(0),
+    }));
+    let racers: Vec<_> = (0..2)
+        .map(|_| {
+            let cache = cache.clone();
+            tokio::spawn(async move { cache.get("a").await.unwrap() })
+        })
+        .collect();
+    let mut values = vec![];
+    for racer in racers {
+        values.push(racer.await.unwrap());
+    }
+
+    assert_eq!(cache.stats().misses, 2);
+    assert_eq!(values[0], values[1]);
+    assert_eq!(cache.get("a").await.unwrap(), values[0]);
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn upsert_replaces_present_value_in_place() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.set("b".to_owned(), 2).unwrap();
+    cache.set("c".to_owned(), 3).unwrap();
+    cache.add_usage("c").unwrap();
+
+    cache.upsert("a".to_owned(), 10).unwrap();
+    cache.upsert("c".to_owned(), 30).unwrap();
+    assert_eq!(cache.usage_count("c"), Some(1));
+    assert_eq!(
+        cache.get_or_insert_with("c", || unreachable!()).unwrap(),
+        30
+    );
+
+    // NOTE: "a" keeps its place in front of "b" instead of being renewed
+    let expires = cache.expire_now(false);
+    let keys: Vec<_> = expires.iter().map(|expire| expire.key.as_str()).collect();
+    assert_eq!(keys, ["a", "b"]);
+}
+
+#[test]
+fn upsert_inserts_absent_key() {
+    let cache = TestCache::default();
+    cache.upsert("a".to_owned(), 1).unwrap();
+    assert_eq!(cache.usage_count("a"), Some(0));
+    assert_eq!(cache.get_or_insert_with("a", || unreachable!()).unwrap(), 1);
+}
+
+#[test]
+fn replace_returns_the_displaced_value() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.set("b".to_owned(), 2).unwrap();
+    cache.add_usage("a").unwrap();
+
+    assert_eq!(cache.replace("a".to_owned(), 10).unwrap(), Some(1));
+    assert_eq!(cache.usage_count("a"), Some(1));
+    assert_eq!(
+        cache.get_or_insert_with("a", || unreachable!()).unwrap(),
+        10
+    );
+    cache.remove_usage("a").unwrap();
+    // NOTE: "b" stays behind "a", the replace didn't renew it
+    assert_eq!(cache.replace("b".to_owned(), 20).unwrap(), Some(2));
+    let expires = cache.expire_now(false);
+    let keys: Vec<_> = expires.iter().map(|expire| expire.key.as_str()).collect();
+    assert_eq!(keys, ["b", "a"]);
+}
+
+#[test]
+fn replace_inserts_absent_key() {
+    let cache = TestCache::default();
+    assert_eq!(cache.replace("a".to_owned(), 1).unwrap(), None);
+    assert_eq!(cache.usage_count("a"), Some(0));
+    assert_eq!(cache.get_or_insert_with("a", || unreachable!()).unwrap(), 1);
+}
+
+#[derive(Debug, Default)]
+struct CountingGetter {
+    calls: AtomicUsize,
+}
+
+impl DataGetter for CountingGetter {
+    type Key = String;
+    type BorrowedKey = str;
+    type Value = usize;
+    type Error = Infallible;
+    async fn get(&self, key: &str) -> Result<usize, Infallible> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        Ok(key.len())
+    }
+}
+
+#[test]
+fn set_at_capacity_evicts_the_oldest_idle_entry() {
+    let cache = TestCache::default().with_max_entries(2);
+    let mut expirations = cache.subscribe_expirations();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.set("b".to_owned(), 2).unwrap();
+    // NOTE: the lookup moves "a" behind "b" in the idle list
+    assert_eq!(cache.get_or_insert_with("a", || 0).unwrap(), 1);
+    cache.set("c".to_owned(), 3).unwrap();
+
+    let expire = expirations.try_recv().unwrap();
+    assert_eq!(expire.key, "b");
+    assert_eq!(expire.kind, ExpireKind::Evicted);
+    assert!(expirations.try_recv().is_err());
+    let mut keys = cache.keys();
+    keys.sort();
+    assert_eq!(keys, ["a", "c"]);
+    assert!(matches!(
+        cache.set("a".to_owned(), 10),
+        Err(CacheError::KeyExists)
+    ));
+    assert_eq!(cache.len(), 2);
+}
+
+#[tokio::test]
+async fn set_at_capacity_fails_when_every_entry_is_in_use() {
+    let getter = Arc::new(CountingGetter::default());
+    let cache = Cache::<_, 30_000, 600_000>::new(getter.clone()).with_max_entries(2);
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.set("b".to_owned(), 2).unwrap();
+    cache.add_usage("a").unwrap();
+    cache.add_usage("b").unwrap();
+
+    assert!(matches!(
+        cache.set("c".to_owned(), 3),
+        Err(CacheError::CapacityFull)
+    ));
+    assert_eq!(cache.usage_count("a"), Some(1));
+    assert_eq!(cache.usage_count("b"), Some(1));
+    // NOTE: a miss still answers, without caching the fetched value
+    assert_eq!(cache.get("abc").await.unwrap(), 3);
+    assert_eq!(cache.len(), 2);
+
+    cache.remove_usage("b").unwrap();
+    cache.set("c".to_owned(), 3).unwrap();
+    let mut keys = cache.keys();
+    keys.sort();
+    assert_eq!(keys, ["a", "c"]);
+}
+
+#[tokio::test]
+async fn caches_share_one_getter_through_arc() {
+    let getter = Arc::new(CountingGetter::default());
+    let short = Cache::<_, 1_000, 10_000>::new(getter.clone());
+    let long = Cache::<_, 30_000, 600_000>::new(getter.clone());
+    assert_eq!(Arc::strong_count(&getter), 3);
+
+    assert_eq!(short.get("ab").await.unwrap(), 2);
+    assert_eq!(long.get("abc").await.unwrap(), 3);
+    assert_eq!(long.get("abc").await.unwrap(), 3);
+    assert_eq!(short.get("abc").await.unwrap(), 3);
+    assert_eq!(getter.calls.load(Ordering::Relaxed), 3);
+}
+
+#[tokio::test]
+async fn stats_count_hits_and_misses() {
+    let cache = Cache::<LenGetter, 30_000, 600_000>::default();
+    cache.get("a").await.unwrap();
+    cache.get("a").await.unwrap();
+    cache.get_with_meta("bb").await.unwrap();
+    assert_eq!(
+        cache.stats(),
+        CacheStats {
+            hits: 1,
+            misses: 2,
+            len: 2
+        }
+    );
+    let mut keys = cache.keys();
+    keys.sort();
+    assert_eq!(keys, ["a", "bb"]);
+}
+
+#[test]
+fn invalidate_removes_idle_and_used_entries() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.set("b".to_owned(), 2).unwrap();
+    cache.add_usage("b").unwrap();
+
+    assert_eq!(cache.invalidate("a").unwrap(), 0);
+    assert_eq!(cache.invalidate("b").unwrap(), 1);
+    assert!(matches!(
+        cache.invalidate("a"),
+        Err(CacheError::KeyNotFound)
+    ));
+    assert!(cache.is_empty());
+    assert!(matches!(
+        cache.remove_usage("b"),
+        Err(CacheError::KeyNotFound)
+    ));
+    assert!(cache.expire_now(true).is_empty());
+
+    cache.set("a".to_owned(), 3).unwrap();
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn evict_expired_budget_drains_in_several_calls() {
+    let cache = Cache::<UnreachableGetter, 0, 0>::default();
+    for i in 0..10 {
+        cache.set(i.to_string(), i).unwrap();
+    }
+    for i in 0..3 {
+        cache.add_usage(&i.to_string()).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(5));
+
+    let mut calls = 0;
+    let mut evicted = vec![];
+    loop {
+        let (expires, more) = cache.evict_expired_budget(4);
+        calls += 1;
+        assert!(expires.len() <= 4);
+        evicted.extend(expires);
+        if !more {
+            break;
+        }
+        assert_eq!(evicted.len(), calls * 4);
+    }
+    assert_eq!(calls, 3);
+    assert_eq!(evicted.len(), 10);
+    let used = evicted
+        .iter()
+        .filter(|expire| expire.kind == ExpireKind::Used)
+        .count();
+    assert_eq!(used, 3);
+    assert!(cache.is_empty());
+    assert_eq!(cache.evict_expired_budget(4).0.len(), 0);
+}
+
+#[test]
+fn with_expiry_overrides_const_parameters() {
+    let cache = TestCache::default().with_expiry(Duration::ZERO, Duration::from_secs(600));
+    cache.set("idle".to_owned(), 1).unwrap();
+    cache.set("used".to_owned(), 2).unwrap();
+    cache.add_usage("used").unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+
+    let expired = cache.evict_expired();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].key, "idle");
+    assert_eq!(expired[0].kind, ExpireKind::Idle);
+    assert_eq!(cache.len(), 1);
+}
+
+#[test]
+fn try_remove_usage_releases_uncontended_usage() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.add_usage("a").unwrap();
+    cache.add_usage("a").unwrap();
+
+    cache.try_remove_usage("a").unwrap();
+    assert_eq!(cache.usage_count("a"), Some(1));
+    cache.try_remove_usage("a").unwrap();
+    assert_eq!(cache.usage_count("a"), Some(0));
+    assert!(matches!(
+        cache.try_remove_usage("a"),
+        Err(CacheError::UsageUnderflow)
+    ));
+    assert!(matches!(
+        cache.try_remove_usage("b"),
+        Err(CacheError::KeyNotFound)
+    ));
+}
+
+#[test]
+fn try_remove_usage_would_block_on_busy_lists() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    cache.set("b".to_owned(), 2).unwrap();
+    let stop = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        // NOTE: the last usage of "b" comes and goes, each time under both list locks
+        scope.spawn(|| {
+            while !stop.load(Ordering::Relaxed) {
+                cache.add_usage("b").unwrap();
+                cache.remove_usage("b").unwrap();
+            }
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let result = loop {
+            if Instant::now() > deadline {
+                break Ok(());
+            }
+            cache.add_usage("a").unwrap();
+            match cache.try_remove_usage("a") {
+                Ok(()) => {}
+                err => break err,
+            }
+        };
+        stop.store(true, Ordering::Relaxed);
+        assert!(matches!(result, Err(CacheError::WouldBlock)), "{result:?}");
+    });
+    // NOTE: a blocked attempt leaves the usage in place for a retry
+    assert_eq!(cache.usage_count("a"), Some(1));
+    cache.remove_usage("a").unwrap();
+    assert_eq!(cache.usage_count("a"), Some(0));
+}
+
+#[test]
+fn usage_guard_removes_usage_on_drop() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    {
+        let guard = cache.usage_guard("a").unwrap();
+        assert_eq!(guard.key(), "a");
+        let _second = cache.usage_guard("a").unwrap();
+        assert_eq!(cache.usage_count("a"), Some(2));
+    }
+    assert_eq!(cache.usage_count("a"), Some(0));
+    assert!(cache.leaked_usages(Duration::ZERO).is_empty());
+
+    let guard = cache.usage_guard("a").unwrap();
+    cache.invalidate("a").unwrap();
+    assert!(matches!(guard.release(), Err(CacheError::KeyNotFound)));
+    assert!(matches!(
+        cache.usage_guard("b"),
+        Err(CacheError::KeyNotFound)
+    ));
+}
+
+#[test]
+fn usage_guard_removes_usage_on_panic() {
+    let cache = TestCache::default();
+    cache.set("a".to_owned(), 1).unwrap();
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let _guard = cache.usage_guard("a").unwrap();
+        panic!("worker failed");
+    }));
+    assert!(res.is_err());
+    assert_eq!(cache.usage_count("a"), Some(0));
+}
+
+#[tokio::test]
+async fn get_refetches_entry_past_idle_expiry_before_the_sweep() {
+    let getter = Arc::new(CountingGetter::default());
+    let cache = Cache::<_, 30_000, 600_000>::new(getter.clone())
+        .with_expiry(Duration::from_millis(20), Duration::from_secs(600));
+    let mut expirations = cache.subscribe_expirations();
+    assert_eq!(cache.get("ab").await.unwrap(), 2);
+    assert_eq!(cache.get("ab").await.unwrap(), 2);
+    assert_eq!(getter.calls.load(Ordering::Relaxed), 1);
+
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    assert_eq!(cache.get("ab").await.unwrap(), 2);
+    assert_eq!(getter.calls.load(Ordering::Relaxed), 2);
+    let expire = expirations.try_recv().unwrap();
+    assert_eq!(expire.key, "ab");
+    assert_eq!(expire.kind, ExpireKind::Idle);
+    assert_eq!(cache.stats().misses, 2);
+
+    // NOTE: entries in use only expire through the sweep
+    cache.add_usage("ab").unwrap();
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    let (_, meta) = cache.get_with_meta("ab").await.unwrap();
+    assert!(meta.hit);
+    cache.remove_usage("ab").unwrap();
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    let (_, meta) = cache.get_with_meta("ab").await.unwrap();
+    assert!(!meta.hit);
+    assert_eq!(getter.calls.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn invalidate_prefix_drops_only_matching_keys() {
+    let cache = TestCache::default();
+    for key in ["batch1-a", "batch1-b", "batch12-c", "batch2-a", "other"] {
+        cache.set(key.to_owned(), 1).unwrap();
+    }
+    cache.add_usage("batch1-b").unwrap();
+
+    assert_eq!(cache.invalidate_prefix("batch1-").unwrap(), 2);
+    let mut keys = cache.keys();
+    keys.sort();
+    assert_eq!(keys, ["batch12-c", "batch2-a", "other"]);
+    assert!(matches!(
+        cache.remove_usage("batch1-b"),
+        Err(CacheError::KeyNotFound)
+    ));
+    assert_eq!(cache.invalidate_prefix("missing").unwrap(), 0);
+    // NOTE: the lists are left consistent, the rest still expires normally
+    assert_eq!(cache.expire_now(false).len(), 3);
+    assert!(cache.is_empty());
+}
+
+// Keeps at most `capacity` entries, evicting the least used ones first
+#[derive(Debug)]
+struct LeastUsed {
+    capacity: usize,
+}
+
+impl EvictionPolicy<String> for LeastUsed {
+    fn select(&self, candidates: &[EvictionCandidate<String>]) -> Vec<usize> {
+        let mut positions: Vec<_> = (0..candidates.len()).collect();
+        positions.sort_by_key(|&position| candidates[position].usages);
+        positions.truncate(candidates.len().saturating_sub(self.capacity));
+        positions
+    }
+}
+
+#[test]
+fn eviction_policy_evicts_least_used_first() {
+    let cache = TestCache::default().with_eviction_policy(LeastUsed { capacity: 2 });
+    for (key, usages) in [("a", 2), ("b", 0), ("c", 3), ("d", 1)] {
+        cache.set(key.to_owned(), 1).unwrap();
+        for _ in 0..usages {
+            cache.add_usage(key).unwrap();
+        }
+    }
+
+    let (expires, more) = cache.evict_expired_budget(1);
+    assert!(more);
+    assert_eq!(expires.len(), 1);
+    assert_eq!(expires[0].key, "b");
+    assert_eq!(expires[0].kind, ExpireKind::Idle);
+
+    let expires = cache.evict_expired();
+    assert_eq!(expires.len(), 1);
+    assert_eq!(expires[0].key, "d");
+    assert_eq!(expires[0].usages, 1);
+    assert_eq!(expires[0].kind, ExpireKind::Used);
+    let mut keys = cache.keys();
+    keys.sort();
+    assert_eq!(keys, ["a", "c"]);
+    // NOTE: the evicted entry's node left the used list, releasing the rest still works
+    cache.remove_usage("a").unwrap();
+    assert!(cache.evict_expired().is_empty());
+}
+
+#[test]
+fn time_expiry_policy_matches_default() {
+    let policy = TimeExpiry {
+        idle_expire: Duration::ZERO,
+        used_expire: Duration::from_secs(600),
+    };
+    let cache = TestCache::default().with_eviction_policy(policy);
+    cache.set("idle".to_owned(), 1).unwrap();
+    cache.set("used".to_owned(), 2).unwrap();
+    cache.add_usage("used").unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+
+    let expires = cache.evict_expired();
+    assert_eq!(expires.len(), 1);
+    assert_eq!(expires[0].key, "idle");
+    assert_eq!(cache.keys(), ["used"]);
+}
+
+#[tokio::test]
+async fn handle_is_taken_from_a_borrowed_cache() {
+    // NOTE: like `CacheState`, the owner only lends the cache out
+    let owner = Arc::new(Cache::<LenGetter, 30_000, 600_000>::new(LenGetter));
+    let handle = owner.handle();
+    handle.set("a".to_owned(), 10).unwrap();
+    assert_eq!(owner.get("a").await.unwrap(), 10);
+    assert_eq!(owner.get("abc").await.unwrap(), 3);
+    assert_eq!(handle.stats().misses, 1);
+
+    drop(owner);
+    assert_eq!(handle.get("a").await.unwrap(), 10);
+    assert_eq!(handle.len(), 2);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn cloned_handles_share_the_cache() {
+    let cache = Cache::<LenGetter, 30_000, 600_000>::new(LenGetter).into_handle();
+    let tasks: Vec<_> = (0..4)
+        .map(|i| {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                for j in 0..50 {
+                    cache.set(format!("set-{i}-{j}"), j).unwrap();
+                    assert_eq!(cache.get("shared").await.unwrap(), 6);
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    assert_eq!(cache.len(), 4 * 50 + 1);
+    assert_eq!(cache.get("set-3-49").await.unwrap(), 49);
+    let stats = cache.stats();
+    assert_eq!(stats.hits + stats.misses, 4 * 50 + 1);
+}
+
+#[test]
+fn evict_expired_runs_alongside_lookups_and_usages() {
+    // NOTE: a zero expiry makes every sweep find expired entries, idle and used
+    let cache =
+        Cache::<LenGetter, 30_000, 600_000>::default().with_expiry(Duration::ZERO, Duration::ZERO);
+    let keys: Vec<String> = (0..32).map(|i| format!("key-{i}")).collect();
+    let done = AtomicBool::new(false);
+
+    let longest_sweep = std::thread::scope(|scope| {
+        let sweeper = scope.spawn(|| {
+            let mut longest = Duration::ZERO;
+            while !done.load(Ordering::Relaxed) {
+                let started = Instant::now();
+                drop(cache.evict_expired());
+                longest = longest.max(started.elapsed());
+            }
+            longest
+        });
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let (cache, keys) = (&cache, &keys);
+                scope.spawn(move || {
+                    for round in 0..2_000 {
+                        let key = &keys[(worker + round) % keys.len()];
+                        let value = futures::executor::block_on(cache.get(key)).unwrap();
+                        assert_eq!(value, key.len());
+                        // NOTE: the sweep may drop the entry in between, errors are expected
+                        if cache.add_usage(key).is_ok() {
+                            drop(cache.remove_usage(key));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        sweeper.join().unwrap()
+    });
+
+    assert!(
+        longest_sweep < Duration::from_secs(1),
+        "a sweep took {longest_sweep:?}"
+    );
+    assert_eq!(cache.check_invariant(), Ok(()));
+}
+
+#[derive(Debug, Clone)]
+enum CacheOp {
+    Get(&'static str),
+    Set(&'static str),
+    Upsert(&'static str),
+    AddUsage(&'static str),
+    RemoveUsage(&'static str),
+    Invalidate(&'static str),
+    ExpireNow(bool),
+    EvictExpired,
+}
+
+fn cache_op() -> impl Strategy<Value = CacheOp> {
+    let key = prop::sample::select(vec!["a", "b", "c", "d"]);
+    prop_oneof![
+        key.clone().prop_map(CacheOp::Get),
+        key.clone().prop_map(CacheOp::Set),
+        key.clone().prop_map(CacheOp::Upsert),
+        key.clone().prop_map(CacheOp::AddUsage),
+        key.clone().prop_map(CacheOp::RemoveUsage),
+        key.prop_map(CacheOp::Invalidate),
+        any::<bool>().prop_map(CacheOp::ExpireNow),
+        Just(CacheOp::EvictExpired),
+    ]
+}
+
+// NOTE: a zero expiry makes every lookup and sweep expire entries, a long one keeps them
+fn expiry() -> impl Strategy<Value = Duration> {
+    prop_oneof![Just(Duration::ZERO), Just(Duration::from_secs(60))]
+}
+
+// NOTE: below the number of keys, so sets run into the cap
+fn max_entries() -> impl Strategy<Value = Option<usize>> {
+    prop::option::of(1usize..4)
+}
+
+fn prop_cache(
+    idle_expire: Duration,
+    used_expire: Duration,
+    max_entries: Option<usize>,
+) -> Cache<LenGetter, 30_000, 600_000> {
+    let cache = Cache::default().with_expiry(idle_expire, used_expire);
+    match max_entries {
+        Some(max_entries) => cache.with_max_entries(max_entries),
+        None => cache,
+    }
+}
+
+// Errors are expected, the ops don't track which keys are cached or in use
+fn apply(cache: &Cache<LenGetter, 30_000, 600_000>, op: &CacheOp) {
+    match *op {
+        CacheOp::Get(key) => drop(futures::executor::block_on(cache.get(key))),
+        CacheOp::Set(key) => drop(cache.set(key.to_owned(), key.len())),
+        CacheOp::Upsert(key) => drop(cache.upsert(key.to_owned(), key.len())),
+        CacheOp::AddUsage(key) => drop(cache.add_usage(key)),
+        CacheOp::RemoveUsage(key) => drop(cache.remove_usage(key)),
+        CacheOp::Invalidate(key) => drop(cache.invalidate(key)),
+        CacheOp::ExpireNow(include_used) => drop(cache.expire_now(include_used)),
+        CacheOp::EvictExpired => drop(cache.evict_expired()),
+    }
+}
+
+proptest! {
+    #[test]
+    fn lists_match_entries_after_every_op(
+        ops in prop::collection::vec(cache_op(), 1..100),
+        idle_expire in expiry(),
+        used_expire in expiry(),
+        max_entries in max_entries(),
+    ) {
+        let cache = prop_cache(idle_expire, used_expire, max_entries);
+        for op in &ops {
+            apply(&cache, op);
+            prop_assert_eq!(cache.check_invariant(), Ok(()), "after {:?}", op);
+            prop_assert!(max_entries.is_none_or(|max| cache.len() <= max), "after {:?}", op);
+        }
+    }
+
+    #[test]
+    fn lists_match_entries_after_concurrent_ops(
+        threads in prop::collection::vec(prop::collection::vec(cache_op(), 1..200), 2..5),
+        idle_expire in expiry(),
+        used_expire in expiry(),
+        max_entries in max_entries(),
+    ) {
+        let cache = prop_cache(idle_expire, used_expire, max_entries);
+        std::thread::scope(|scope| {
+            for ops in &threads {
+                let cache = &cache;
+                scope.spawn(move || ops.iter().for_each(|op| apply(cache, op)));
+            }
+        });
+        prop_assert_eq!(cache.check_invariant(), Ok(()));
+        prop_assert!(max_entries.is_none_or(|max| cache.len() <= max));
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{Json, Router, routing::post};
+use queues_demo::api::QueueAddTask;
+use tokio::process::Command;
+
+mod common;
+
+use common::serve;
+
+fn client() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_client"));
+    command.env("RUST_LOG", "off");
+    command
+}
+
+fn submission_ids(stdout: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<QueueAddTask>(line)
+                .unwrap()
+                .submission_id
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn count_stops_after_that_many_submissions() {
+    let submitted = Arc::new(Mutex::new(vec![]));
+    let app = Router::new().route(
+        "/queue/add_task",
+        post({
+            let submitted = submitted.clone();
+            async move |Json(task): Json<QueueAddTask>| {
+                submitted.lock().unwrap().push(task.submission_id);
+            }
+        }),
+    );
+    let url = serve(app).await;
+
+    let status = client()
+        .args(["--count", "3", "--interval", "0", "--server-url", &url])
+        .status()
+        .await
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(submitted.lock().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn dry_run_sends_nothing_and_seed_is_reproducible() {
+    // NOTE: nothing listens there, any request would fail the run
+    let args = [
+        "--count",
+        "5",
+        "--seed",
+        "42",
+        "--dry-run",
+        "--server-url",
+        "http://127.0.0.1:1",
+    ];
+    let first = client().args(args).output().await.unwrap();
+    assert!(first.status.success());
+    let ids = submission_ids(&first.stdout);
+    assert_eq!(ids.len(), 5);
+
+    let second = client().args(args).output().await.unwrap();
+    assert_eq!(submission_ids(&second.stdout), ids);
+}
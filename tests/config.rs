@@ -0,0 +1,48 @@
+use std::{path::Path, time::Duration};
+
+use clap::Parser;
+use queues_demo::{config::Config, utils::HttpPool};
+
+#[test]
+fn config_defaults_and_env_overrides() {
+    let config = Config::try_parse_from(["queue"]).unwrap();
+    assert_eq!(config.port, 3000);
+    assert_eq!(config.db_path, Path::new("queue.db"));
+    assert_eq!(config.collector_url, "http://localhost:3002/submit");
+    assert_eq!(config.exec_timeout_ms, 30_000);
+    assert_eq!(config.cache_idle_expire_ms, 30_000);
+    assert_eq!(config.cache_used_expire_ms, 600_000);
+    assert_eq!(config.max_attempts, None);
+    assert_eq!(config.max_body_bytes, 1024 * 1024);
+    assert_eq!(config.exploit_pool, HttpPool::default());
+
+    // NOTE: the only test in this binary, so nothing else reads the environment concurrently
+    unsafe {
+        std::env::set_var("QUEUE_PORT", "3100");
+        std::env::set_var("QUEUE_DB_PATH", "/tmp/other.db");
+        std::env::set_var("COLLECTOR_URL", "http://collector:3002/submit");
+        std::env::set_var("QUEUE_EXEC_TIMEOUT_MS", "5000");
+        std::env::set_var("CACHE_IDLE_EXPIRE_MS", "1000");
+        std::env::set_var("QUEUE_MAX_ATTEMPTS", "3");
+    }
+    let config = Config::try_parse_from(["queue"]).unwrap();
+    assert_eq!(config.port, 3100);
+    assert_eq!(config.db_path, Path::new("/tmp/other.db"));
+    assert_eq!(config.collector_url, "http://collector:3002/submit");
+    assert_eq!(
+        Duration::from_millis(config.exec_timeout_ms),
+        Duration::from_secs(5)
+    );
+    assert_eq!(config.cache_idle_expire_ms, 1000);
+    assert_eq!(config.cache_used_expire_ms, 600_000);
+    assert_eq!(config.max_attempts.map(|n| n.get()), Some(3));
+
+    // NOTE: flags still win over the environment
+    let config = Config::try_parse_from(["queue", "--port", "3200"]).unwrap();
+    assert_eq!(config.port, 3200);
+
+    unsafe {
+        std::env::set_var("QUEUE_PORT", "not a port");
+    }
+    assert!(Config::try_parse_from(["queue"]).is_err());
+}
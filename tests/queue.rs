@@ -0,0 +1,1367 @@
+use std::{
+    cell::Cell,
+    collections::{HashSet, VecDeque},
+    fs,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::StreamExt;
+use proptest::prelude::*;
+use queues_demo::{
+    queue::{
+        BackupCodec, Durability, GenericTaskQueue, GenericTaskQueueWithBackup,
+        QUEUE_FORMAT_VERSION, SubmitError, TaskEventKind, TaskId, TimeoutAction,
+    },
+    store::{InMemoryStore, StoreWrite, Table, TaskStore},
+    utils::open_db,
+};
+use tokio::time::Instant;
+
+type TestQueue = GenericTaskQueueWithBackup<String, 30_000>;
+
+fn temporary_db() -> sled::Db {
+    sled::Config::new().temporary(true).open().unwrap()
+}
+
+#[tokio::test]
+async fn drain_pending_takes_queued_tasks_and_their_keys() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone());
+    queue
+        .push_many(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()])
+        .await;
+    let (processed, _) = queue
+        .pop_with_timeout(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(*processed, "a");
+
+    let drained: Vec<String> = queue
+        .drain_pending()
+        .into_iter()
+        .map(|task| (*task).clone())
+        .collect();
+    assert_eq!(drained, ["b", "c"]);
+    assert_eq!(queue.len_pending(), 0);
+    assert_eq!(queue.len_processing(), 1);
+    // NOTE: only the processing task is left on disk
+    assert_eq!(db.len(), 1);
+}
+
+fn record(version: u8, task: &str) -> Vec<u8> {
+    let mut value = vec![version];
+    value.extend(bincode::serde::encode_to_vec(task, bincode::config::standard()).unwrap());
+    value
+}
+
+// A record as the queue writes it, the task followed by its attempts
+fn stored(task: &str, attempts: u32) -> Vec<u8> {
+    let mut value = record(QUEUE_FORMAT_VERSION, task);
+    value.extend(attempts.to_be_bytes());
+    value
+}
+
+fn records(db: &sled::Db) -> Vec<(u64, Vec<u8>)> {
+    db.iter()
+        .map(|item| {
+            let (key, value) = item.unwrap();
+            let key = u64::from_be_bytes((*key).try_into().unwrap());
+            (key, value.to_vec())
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn records_of_other_versions_are_skipped_without_migration() {
+    let db = temporary_db();
+    db.insert(1u64.to_be_bytes(), record(QUEUE_FORMAT_VERSION, "current"))
+        .unwrap();
+    db.insert(
+        2u64.to_be_bytes(),
+        record(QUEUE_FORMAT_VERSION + 1, "future"),
+    )
+    .unwrap();
+    db.insert(3u64.to_be_bytes(), &[QUEUE_FORMAT_VERSION, 0xff])
+        .unwrap();
+
+    let queue = TestQueue::new(db.clone());
+    let (task, _) = queue
+        .pop_with_timeout(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(*task, "current");
+    assert_eq!(queue.len_pending(), 0);
+    // NOTE: skipped records stay on disk
+    assert_eq!(db.len(), 3);
+}
+
+#[test]
+fn records_of_other_versions_are_migrated() {
+    let db = temporary_db();
+    db.insert(1u64.to_be_bytes(), record(QUEUE_FORMAT_VERSION - 1, "old"))
+        .unwrap();
+
+    let queue = TestQueue::new_with_migration(db.clone(), |version, payload| {
+        assert_eq!(version, QUEUE_FORMAT_VERSION - 1);
+        let (task, _): (String, _) =
+            bincode::serde::decode_from_slice(payload, bincode::config::standard()).ok()?;
+        Some(format!("{task} migrated"))
+    });
+    assert_eq!(queue.len_pending(), 1);
+    assert_eq!(records(&db), [(1, stored("old migrated", 0))]);
+}
+
+#[tokio::test]
+async fn json_records_are_readable_by_either_codec() {
+    let db = temporary_db();
+    let queue = TestQueue::new_with_codec(db.clone(), BackupCodec::Json);
+    queue.push_many(vec!["a".to_owned(), "b".to_owned()]).await;
+    queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    let values: Vec<serde_json::Value> = records(&db)
+        .iter()
+        .map(|(_, value)| serde_json::from_slice(value).unwrap())
+        .collect();
+    assert_eq!(
+        values,
+        [
+            serde_json::json!({ "task": "a", "attempts": 1 }),
+            serde_json::json!({ "task": "b", "attempts": 0 }),
+        ]
+    );
+    drop(queue);
+
+    // NOTE: the default codec still reads them, and writes new records in bincode
+    let queue = TestQueue::new(db.clone());
+    queue.push("c".to_owned()).await;
+    assert_eq!(records(&db)[2].1, stored("c", 0));
+    let (task, _, attempt) = queue.pop_with_attempt(Duration::ZERO).await.unwrap();
+    assert_eq!((task.as_str(), attempt), ("a", 2));
+    let (task, _, attempt) = queue.pop_with_attempt(Duration::ZERO).await.unwrap();
+    assert_eq!((task.as_str(), attempt), ("b", 1));
+}
+
+#[tokio::test]
+async fn records_keyed_by_task_are_moved_to_sequential_keys() {
+    let db = temporary_db();
+    let dead_letter = db.open_tree("dead_letter").unwrap();
+    db.insert(record(QUEUE_FORMAT_VERSION, "pending"), &[])
+        .unwrap();
+    dead_letter
+        .insert(record(QUEUE_FORMAT_VERSION, "dead"), &[])
+        .unwrap();
+
+    let queue = TestQueue::new(db.clone());
+    assert_eq!(queue.len_pending(), 1);
+    assert_eq!(queue.len_dead_letter(), 1);
+    let values: Vec<_> = records(&db).into_iter().map(|(_, value)| value).collect();
+    assert_eq!(values, [stored("pending", 0)]);
+    assert_eq!(dead_letter.len(), 1);
+
+    let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    queue.submit_completed(&id).unwrap();
+    assert_eq!(db.len(), 0);
+}
+
+#[tokio::test]
+async fn push_order_is_preserved_across_restart() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone());
+    // NOTE: picked so that the encoded tasks sort in the opposite order
+    queue.push("b".to_owned()).await;
+    queue.push("a".to_owned()).await;
+    queue
+        .push_many((0..20).rev().map(|i| format!("task{i:02}")).collect())
+        .await;
+    drop(queue);
+
+    let queue = TestQueue::new(db);
+    let mut popped = vec![];
+    while let Some((task, _)) = queue.pop_with_timeout(Duration::ZERO).await {
+        popped.push((*task).clone());
+    }
+    let mut expected = vec!["b".to_owned(), "a".to_owned()];
+    expected.extend((0..20).rev().map(|i| format!("task{i:02}")));
+    assert_eq!(popped, expected);
+}
+
+#[tokio::test]
+async fn push_many_lands_in_memory_and_on_disk_and_wakes_every_waiter() {
+    let db = temporary_db();
+    let queue = Arc::new(TestQueue::new(db.clone()));
+    let waiters: Vec<_> = (0..3)
+        .map(|_| {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.pop_with_timeout(Duration::from_secs(10)).await })
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+
+    let tasks: Vec<String> = (0..5).map(|i| i.to_string()).collect();
+    queue.push_many(tasks.clone()).await;
+    let mut popped = vec![];
+    for waiter in waiters {
+        let (task, _) = tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("Waiter was not woken")
+            .unwrap()
+            .unwrap();
+        popped.push((*task).clone());
+    }
+    popped.sort();
+    assert_eq!(popped, ["0", "1", "2"]);
+    assert_eq!(queue.len_pending(), 2);
+    assert_eq!(queue.len_processing(), 3);
+    let values: Vec<_> = records(&db).into_iter().map(|(_, value)| value).collect();
+    let attempts = [1, 1, 1, 0, 0];
+    let expected: Vec<_> = tasks
+        .iter()
+        .zip(attempts)
+        .map(|(task, attempts)| stored(task, attempts))
+        .collect();
+    assert_eq!(values, expected);
+}
+
+#[tokio::test]
+async fn equal_tasks_keep_separate_records() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone());
+    queue.push("a".to_owned()).await;
+    queue.push("a".to_owned()).await;
+    assert_eq!(db.len(), 2);
+
+    let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    queue.submit_completed(&id).unwrap();
+    drop(queue);
+    let queue = TestQueue::new(db);
+    assert_eq!(queue.len_pending(), 1);
+}
+
+fn temporary_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("queues-demo-test-{}", uuid::Uuid::new_v4()))
+}
+
+// Opens a copy of what is on disk right now, sled keeps unflushed writes in memory only
+fn open_disk_snapshot(path: &Path) -> sled::Db {
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &target);
+            } else {
+                fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+    let snapshot = temporary_dir();
+    copy_dir(path, &snapshot);
+    sled::Config::new()
+        .path(snapshot)
+        .temporary(true)
+        .open()
+        .unwrap()
+}
+
+fn open_without_background_flush(path: &Path) -> sled::Db {
+    sled::Config::new()
+        .path(path)
+        .temporary(true)
+        .flush_every_ms(None)
+        .open()
+        .unwrap()
+}
+
+// Keeps a copy of the stored records after every write, each one is what a crash right after that
+// write would leave behind
+#[derive(Debug, Clone, Default)]
+struct CrashPoints {
+    store: InMemoryStore,
+    snapshots: Arc<Mutex<Vec<InMemoryStore>>>,
+}
+
+impl CrashPoints {
+    fn crash_here(&self) {
+        self.snapshots.lock().unwrap().push(self.store.snapshot());
+    }
+}
+
+impl TaskStore for CrashPoints {
+    fn generate_id(&self) -> u64 {
+        self.store.generate_id()
+    }
+
+    fn records(&self, table: Table) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.store.records(table)
+    }
+
+    fn apply(&self, table: Table, writes: Vec<StoreWrite>) {
+        self.store.apply(table, writes);
+        self.crash_here();
+    }
+
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&[u8]) -> Vec<u8>) {
+        self.store.update(key, update);
+        self.crash_here();
+    }
+
+    fn move_to_dead_letter(&self, key: &[u8]) {
+        self.store.move_to_dead_letter(key);
+        self.crash_here();
+    }
+
+    fn clear(&self, table: Table) {
+        self.store.clear(table);
+        self.crash_here();
+    }
+
+    async fn flush(&self) -> usize {
+        self.store.flush().await
+    }
+}
+
+#[tokio::test]
+async fn no_task_is_lost_or_duplicated_by_a_crash() {
+    let store = CrashPoints::default();
+    let queue = GenericTaskQueueWithBackup::<String, 30_000, _>::from_store(store.clone());
+    for task in ["a", "b", "c"] {
+        queue.push(task.to_owned()).await;
+    }
+    let (_, a) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    let (_, b) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    queue
+        .submit_completed_with_inspect(&a, async |task| {
+            // NOTE: the task already left processing, its record must still be there
+            assert_eq!(queue.len_processing(), 1);
+            assert_eq!(*task.unwrap(), "a");
+            store.crash_here();
+        })
+        .await;
+    queue.submit_completed(&b).unwrap();
+
+    let mut restored: Vec<Vec<String>> = vec![];
+    for snapshot in store.snapshots.lock().unwrap().drain(..) {
+        let reloaded = GenericTaskQueueWithBackup::<String, 30_000, _>::from_store(snapshot);
+        let tasks: Vec<String> = reloaded
+            .drain_pending()
+            .iter()
+            .map(|task| task.to_string())
+            .collect();
+        if restored.last() != Some(&tasks) {
+            restored.push(tasks);
+        }
+    }
+    // NOTE: pushed tasks are there from the write on, completed ones only leave after it
+    assert_eq!(
+        restored,
+        [
+            vec!["a"],
+            vec!["a", "b"],
+            vec!["a", "b", "c"],
+            vec!["b", "c"],
+            vec!["c"],
+        ]
+    );
+}
+
+#[tokio::test]
+async fn strict_push_is_on_disk_immediately() {
+    let path = temporary_dir();
+    let queue =
+        TestQueue::new(open_without_background_flush(&path)).with_durability(Durability::Strict);
+    queue.push("a".to_owned()).await;
+
+    let snapshot = TestQueue::new(open_disk_snapshot(&path));
+    assert_eq!(snapshot.len_pending(), 1);
+}
+
+#[tokio::test]
+async fn relaxed_push_is_on_disk_after_flush() {
+    let path = temporary_dir();
+    let queue =
+        TestQueue::new(open_without_background_flush(&path)).with_durability(Durability::Relaxed);
+    queue.push("a".to_owned()).await;
+    assert_eq!(TestQueue::new(open_disk_snapshot(&path)).len_pending(), 0);
+
+    queue.flush().await;
+    assert_eq!(TestQueue::new(open_disk_snapshot(&path)).len_pending(), 1);
+}
+
+#[tokio::test]
+async fn timeout_scan_reclaims_shorter_deadlines_behind_longer_ones() {
+    let queue = GenericTaskQueue::<String, 60_000>::default();
+    queue.push("long".to_owned());
+    queue.push("short".to_owned());
+    let (_, long_id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    let (_, short_id) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(10))
+        .await
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    let reclaimed = Mutex::new(vec![]);
+    queue.process_timeouts_with_inspect(|id, _| {
+        reclaimed.lock().unwrap().push(id);
+        TimeoutAction::Requeue
+    });
+    assert_eq!(reclaimed.into_inner().unwrap(), [short_id]);
+    assert_eq!(queue.len_processing(), 1);
+    assert_eq!(queue.len_pending(), 1);
+    assert_eq!(
+        queue.submit_completed(&long_id).map(|task| (*task).clone()),
+        Ok("long".to_owned())
+    );
+}
+
+#[tokio::test]
+async fn timeout_batches_reclaim_soonest_deadlines_first() {
+    let queue = GenericTaskQueue::<String, 60_000>::default();
+    let mut ids = vec![];
+    for (task, timeout_ms) in [("a", 40), ("b", 10), ("c", 25), ("d", 3_600_000)] {
+        queue.push(task.to_owned());
+        let (_, id) = queue
+            .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(timeout_ms))
+            .await
+            .unwrap();
+        ids.push(id);
+    }
+
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    let reclaimed = Mutex::new(vec![]);
+    let inspect = |_, task: &String| {
+        reclaimed.lock().unwrap().push(task.clone());
+        TimeoutAction::Requeue
+    };
+    assert!(queue.process_timeouts_batch_with_inspect(2, inspect));
+    assert_eq!(*reclaimed.lock().unwrap(), ["b", "c"]);
+    assert!(!queue.process_timeouts_batch_with_inspect(2, inspect));
+    assert_eq!(*reclaimed.lock().unwrap(), ["b", "c", "a"]);
+    assert_eq!(queue.len_processing(), 1);
+    assert!(queue.submit_completed(&ids[3]).is_ok());
+}
+
+#[tokio::test]
+async fn large_timed_out_set_is_drained_in_bounded_passes() {
+    const TASKS: usize = 1_000;
+    const BATCH: usize = 64;
+    let queue = GenericTaskQueue::<usize, 60_000>::default();
+    queue.push_many((0..TASKS).collect());
+    for _ in 0..TASKS {
+        queue
+            .pop_with_execution_timeout(Duration::ZERO, Duration::ZERO)
+            .await
+            .unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let mut passes = vec![];
+    loop {
+        let reclaimed = Cell::new(0);
+        let more = queue.process_timeouts_batch_with_inspect(BATCH, |_, _| {
+            reclaimed.set(reclaimed.get() + 1);
+            TimeoutAction::Requeue
+        });
+        passes.push(reclaimed.get());
+        if !more {
+            break;
+        }
+    }
+    assert_eq!(passes.len(), TASKS.div_ceil(BATCH));
+    assert!(passes.iter().all(|&reclaimed| reclaimed <= BATCH));
+    assert_eq!(passes.iter().sum::<usize>(), TASKS);
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.len_pending(), TASKS);
+}
+
+#[tokio::test]
+async fn dropped_timeouts_leave_pending_and_processing() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone());
+    queue.push_many(vec!["a".to_owned(), "b".to_owned()]).await;
+    let (_, dropped) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::ZERO)
+        .await
+        .unwrap();
+    let (_, kept) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    queue.process_timeouts_with_inspect(|id, task| {
+        assert_eq!((id, task.as_str()), (dropped, "a"));
+        TimeoutAction::Drop
+    });
+    assert_eq!(queue.len_pending(), 0);
+    assert_eq!(queue.len_processing(), 1);
+    assert_eq!(queue.len_dead_letter(), 0);
+    assert_eq!(queue.submit_completed(&dropped), Err(SubmitError::TimedOut));
+    assert_eq!(*queue.submit_completed(&kept).unwrap(), "b");
+    assert_eq!(db.len(), 0);
+}
+
+#[tokio::test]
+async fn next_timeout_deadline_follows_the_soonest_task() {
+    let queue =
+        GenericTaskQueue::<String, 60_000>::default().with_max_lease(Duration::from_secs(100));
+    assert_eq!(queue.next_timeout_deadline(), None);
+    queue.push_many(vec!["a".to_owned(), "b".to_owned()]);
+
+    let popped_at = Instant::now();
+    let (_, a) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_secs(10))
+        .await
+        .unwrap();
+    let (_, b) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_secs(1_000))
+        .await
+        .unwrap();
+    let deadline_in = || {
+        queue
+            .next_timeout_deadline()
+            .map(|at| (at - popped_at).as_secs())
+    };
+    assert_eq!(deadline_in(), Some(10));
+    queue.submit_completed(&a).unwrap();
+    // NOTE: capped by the lease
+    assert_eq!(deadline_in(), Some(100));
+    queue.submit_completed(&b).unwrap();
+    assert_eq!(deadline_in(), None);
+}
+
+#[tokio::test]
+async fn timeout_loop_reclaims_right_after_the_deadline() {
+    let queue = Arc::new(GenericTaskQueue::<String, 60_000>::default());
+    let timeouts = tokio::spawn({
+        let queue = queue.clone();
+        async move {
+            loop {
+                queue.wait_next_timeout().await;
+                queue.process_timeouts();
+            }
+        }
+    });
+    // NOTE: the loop is parked on an empty queue, the pop has to wake it
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    queue.push("a".to_owned());
+    let popped_at = Instant::now();
+    queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(50))
+        .await
+        .unwrap();
+
+    while queue.len_pending() == 0 {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let reclaimed_after = popped_at.elapsed();
+    assert!(reclaimed_after >= Duration::from_millis(50));
+    assert!(
+        reclaimed_after < Duration::from_millis(250),
+        "{reclaimed_after:?}"
+    );
+    timeouts.abort();
+}
+
+#[tokio::test]
+async fn heartbeat_postpones_timeout() {
+    let queue = GenericTaskQueue::<String, 60_000>::default();
+    queue.push("a".to_owned());
+    let (_, id) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(100))
+        .await
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(70)).await;
+    queue.heartbeat(&id).unwrap();
+    tokio::time::sleep(Duration::from_millis(70)).await;
+    queue.process_timeouts();
+    assert_eq!(queue.len_processing(), 1);
+
+    tokio::time::sleep(Duration::from_millis(70)).await;
+    queue.process_timeouts();
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.heartbeat(&id), Err(SubmitError::TimedOut));
+}
+
+#[tokio::test]
+async fn timed_out_task_becomes_visible_again() {
+    let queue = GenericTaskQueue::<String, 60_000>::default();
+    queue.push("a".to_owned());
+    let (_, first_id) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(20))
+        .await
+        .unwrap();
+    assert!(queue.pop_with_timeout(Duration::ZERO).await.is_none());
+
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    queue.process_timeouts();
+    let (task, second_id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(*task, "a");
+    assert_ne!(first_id, second_id);
+}
+
+#[tokio::test]
+async fn task_is_dead_lettered_after_max_attempts() {
+    let queue = GenericTaskQueue::<String, 60_000>::default()
+        .with_max_attempts(NonZeroU32::new(2).unwrap());
+    queue.push("a".to_owned());
+    for _ in 0..2 {
+        queue
+            .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(20))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        queue.process_timeouts();
+    }
+    assert_eq!(queue.len_pending(), 0);
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.len_dead_letter(), 1);
+}
+
+#[tokio::test]
+async fn heartbeat_cannot_extend_past_max_lease() {
+    let queue =
+        GenericTaskQueue::<String, 60_000>::default().with_max_lease(Duration::from_millis(100));
+    queue.push("a".to_owned());
+    let (_, id) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(60))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    queue.heartbeat(&id).unwrap();
+    tokio::time::sleep(Duration::from_millis(70)).await;
+    assert_eq!(queue.heartbeat(&id), Err(SubmitError::TimedOut));
+    queue.process_timeouts();
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.len_pending(), 1);
+}
+
+#[tokio::test]
+async fn completion_of_an_old_entry_is_rejected() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone()).with_max_completion_age(Duration::from_millis(50));
+    queue.push_many(vec!["a".to_owned(), "b".to_owned()]).await;
+    let (_, old) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(80)).await;
+    let (_, fresh) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+
+    assert_eq!(queue.submit_completed(&old), Err(SubmitError::Stale));
+    assert_eq!(*queue.submit_completed(&fresh).unwrap(), "b");
+    // NOTE: the stale task keeps its record and is left for the timeout scan
+    assert_eq!(queue.len_processing(), 1);
+    assert_eq!(records(&db).len(), 1);
+    queue.heartbeat(&old).unwrap();
+    assert_eq!(*queue.submit_completed(&old).unwrap(), "a");
+}
+
+#[tokio::test]
+async fn task_ids_do_not_collide_across_reloads() {
+    let db = temporary_db();
+    let tasks: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+    let mut seen = HashSet::new();
+    let mut old_ids = vec![];
+    for _ in 0..3 {
+        let queue = TestQueue::new(db.clone());
+        if queue.len_pending() == 0 {
+            queue.push_many(tasks.clone()).await;
+        }
+        for _ in 0..tasks.len() {
+            let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+            assert!(seen.insert(id), "id {id:?} was issued twice");
+            old_ids.push(id);
+        }
+        // NOTE: ids of an earlier instance don't alias the tasks restored in this one
+        for id in &old_ids[..old_ids.len() - tasks.len()] {
+            assert_eq!(queue.submit_completed(id), Err(SubmitError::NotFound));
+        }
+        assert_eq!(queue.len_processing(), tasks.len());
+    }
+    assert_eq!(db.len(), tasks.len());
+}
+
+#[tokio::test]
+async fn completion_targets_only_the_given_task() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone());
+    queue
+        .push_many(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()])
+        .await;
+    let mut ids = vec![];
+    for _ in 0..3 {
+        ids.push(queue.pop_with_timeout(Duration::ZERO).await.unwrap().1);
+    }
+
+    assert_eq!(*queue.submit_completed(&ids[1]).unwrap(), "b");
+    assert_eq!(queue.len_processing(), 2);
+    assert_eq!(
+        records(&db)
+            .iter()
+            .map(|(_, value)| value.clone())
+            .collect::<Vec<_>>(),
+        [stored("a", 1), stored("c", 1)]
+    );
+    queue.push("d".to_owned()).await;
+    let (_, reused) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert!(!ids.contains(&reused));
+    assert_eq!(
+        queue.submit_completed(&ids[1]),
+        Err(SubmitError::AlreadyCompleted)
+    );
+    assert_eq!(*queue.submit_completed(&ids[2]).unwrap(), "c");
+    assert_eq!(*queue.submit_completed(&ids[0]).unwrap(), "a");
+    assert_eq!(*queue.submit_completed(&reused).unwrap(), "d");
+    assert_eq!(db.len(), 0);
+}
+
+#[tokio::test]
+async fn completion_errors_tell_unknown_and_completed_ids_apart() {
+    let queue = TestQueue::new(temporary_db());
+    queue.push("a".to_owned()).await;
+    let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+
+    assert_eq!(*queue.submit_completed(&id).unwrap(), "a");
+    assert_eq!(
+        queue.submit_completed(&id),
+        Err(SubmitError::AlreadyCompleted)
+    );
+    let never_issued = TaskId::from([0x5a; 16]);
+    assert_eq!(
+        queue.submit_completed(&never_issued),
+        Err(SubmitError::NotFound)
+    );
+}
+
+#[tokio::test]
+async fn backup_moves_exhausted_task_to_dead_letter_tree() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone()).with_max_attempts(NonZeroU32::new(1).unwrap());
+    queue.push("a".to_owned()).await;
+    queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(20))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    queue.process_timeouts();
+    assert_eq!(queue.len_dead_letter(), 1);
+    assert_eq!(db.len(), 0);
+    assert_eq!(db.open_tree("dead_letter").unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn take_dead_letter_keeps_records_of_tasks_it_did_not_take() {
+    let store = InMemoryStore::default();
+    let queue = GenericTaskQueueWithBackup::<String, 30_000, _>::from_store(store.clone())
+        .with_max_attempts(NonZeroU32::new(1).unwrap());
+    queue.push("taken".to_owned()).await;
+    let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    queue.fail(&id, TimeoutAction::DeadLetter).unwrap();
+    // NOTE: a task dead lettered concurrently, its record is moved before it shows in memory
+    let late = (u64::MAX.to_be_bytes().to_vec(), stored("late", 1));
+    store.apply(
+        Table::DeadLetter,
+        vec![StoreWrite::Insert(late.0.clone(), late.1.clone())],
+    );
+
+    let taken: Vec<_> = queue
+        .take_dead_letter()
+        .iter()
+        .map(|task| task.to_string())
+        .collect();
+    assert_eq!(taken, ["taken"]);
+    assert_eq!(store.records(Table::DeadLetter), [late]);
+    drop(queue);
+    let restarted = GenericTaskQueueWithBackup::<String, 30_000, _>::from_store(store);
+    assert_eq!(restarted.len_dead_letter(), 1);
+}
+
+#[tokio::test]
+async fn stream_yields_pushed_tasks_and_parks_when_empty() {
+    let queue = GenericTaskQueue::<u32, 60_000>::default();
+    for i in 0..5 {
+        queue.push(i);
+    }
+    let mut stream = pin!(queue.stream());
+    let tasks: Vec<u32> = stream
+        .as_mut()
+        .take(5)
+        .map(|(task, _)| *task)
+        .collect()
+        .await;
+    assert_eq!(tasks, [0, 1, 2, 3, 4]);
+    assert_eq!(queue.len_processing(), 5);
+
+    let parked = tokio::time::timeout(Duration::from_millis(50), stream.next()).await;
+    assert!(parked.is_err());
+
+    queue.push(5);
+    let (task, _) = stream.next().await.unwrap();
+    assert_eq!(*task, 5);
+}
+
+#[tokio::test]
+async fn shrink_processing_releases_burst_capacity() {
+    let queue = GenericTaskQueue::<u32, 60_000>::default();
+    let mut ids = vec![];
+    for i in 0..1000 {
+        queue.push(i);
+        ids.push(queue.pop_with_timeout(Duration::ZERO).await.unwrap().1);
+    }
+    let (kept, rest) = ids.split_first().unwrap();
+    for id in rest {
+        queue.submit_completed(id).unwrap();
+    }
+    assert!(queue.capacity_processing() >= 1000);
+
+    queue.shrink_processing();
+    assert!(queue.capacity_processing() < 1000);
+    assert_eq!(queue.len_processing(), 1);
+    // NOTE: list indices are rewritten by the shrink, the remaining task must still resolve
+    queue.heartbeat(kept).unwrap();
+    assert_eq!(*queue.submit_completed(kept).unwrap(), 0);
+}
+
+#[tokio::test]
+async fn panicking_completion_requeues_task_and_keeps_record() {
+    let db = temporary_db();
+    let queue = Arc::new(TestQueue::new(db.clone()));
+    queue.push("a".to_owned()).await;
+    let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+
+    let completion = tokio::spawn({
+        let queue = queue.clone();
+        async move {
+            queue
+                .submit_completed_with_inspect(&id, async |_| panic!("inspect failed"))
+                .await
+        }
+    });
+    assert!(completion.await.unwrap_err().is_panic());
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.len_pending(), 1);
+    assert_eq!(db.len(), 1);
+
+    let (task, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(*task, "a");
+    queue.submit_completed_with_inspect(&id, async |_| ()).await;
+    assert_eq!(db.len(), 0);
+}
+
+#[tokio::test]
+async fn completions_that_keep_panicking_end_in_dead_letter() {
+    let db = temporary_db();
+    let queue = Arc::new(TestQueue::new(db.clone()).with_max_attempts(NonZeroU32::new(2).unwrap()));
+    queue.push("a".to_owned()).await;
+
+    for attempt in 1..=2 {
+        let (task, id, popped_attempt) = queue.pop_with_attempt(Duration::ZERO).await.unwrap();
+        assert_eq!((task.as_str(), popped_attempt), ("a", attempt));
+        let completion = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                queue
+                    .submit_completed_with_inspect(&id, async |_| panic!("inspect failed"))
+                    .await
+            }
+        });
+        assert!(completion.await.unwrap_err().is_panic());
+    }
+    assert_eq!(queue.len_pending(), 0);
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.len_dead_letter(), 1);
+    // NOTE: the record moved along with the task
+    assert_eq!(db.len(), 0);
+    assert_eq!(db.open_tree("dead_letter").unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn db_opens_at_custom_path() {
+    let path = temporary_dir().join("nested").join("queue.db");
+    {
+        let db = open_db(&path).unwrap();
+        let queue = TestQueue::new(db);
+        queue.push("a".to_owned()).await;
+        queue.flush().await;
+    }
+    let queue = TestQueue::new(open_db(&path).unwrap());
+    assert_eq!(queue.len_pending(), 1);
+}
+
+#[test]
+fn locked_db_reports_running_instance() {
+    let path = temporary_dir();
+    let _db = open_db(&path).unwrap();
+    let err = open_db(&path).unwrap_err().to_string();
+    assert!(
+        err.contains("another instance is probably running"),
+        "{err}"
+    );
+    assert!(err.contains(&path.display().to_string()), "{err}");
+}
+
+#[tokio::test]
+async fn reclaim_all_processing_requeues_every_task() {
+    let queue = GenericTaskQueue::<String, 60_000>::default();
+    queue.push_many(vec!["a".to_owned(), "b".to_owned(), "c".to_owned()]);
+    let mut ids = vec![];
+    for _ in 0..3 {
+        let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+        ids.push(id);
+    }
+    assert_eq!(queue.reclaim_all_processing(), 3);
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.len_pending(), 3);
+    assert_eq!(queue.submit_completed(&ids[0]), Err(SubmitError::Requeued));
+
+    let mut repopped = vec![];
+    for _ in 0..3 {
+        let (task, _) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+        repopped.push((*task).clone());
+    }
+    assert_eq!(repopped, ["a", "b", "c"]);
+    assert_eq!(queue.reclaim_all_processing(), 3);
+    assert_eq!(queue.reclaim_all_processing(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn processing_time_stats_follow_completions() {
+    let queue = GenericTaskQueue::<String, 60_000>::default();
+    assert_eq!(queue.processing_time_stats(), None);
+    queue.push_many(vec!["a".to_owned(), "b".to_owned()]);
+    for sleep_ms in [20, 60] {
+        let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(sleep_ms)).await;
+        queue.submit_completed(&id).unwrap();
+    }
+    let stats = queue.processing_time_stats().unwrap();
+    assert_eq!(stats.count, 2);
+    assert_eq!(stats.min, Duration::from_millis(20));
+    assert_eq!(stats.max, Duration::from_millis(60));
+    assert_eq!(stats.avg, Duration::from_millis(40));
+}
+
+#[tokio::test]
+async fn lifecycle_events_are_logged_in_order_and_capped() {
+    let queue = GenericTaskQueue::<String, 60_000>::default().with_event_log_capacity(3);
+    queue.push("a".to_owned());
+    let (_, id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    queue.submit_completed(&id).unwrap();
+
+    let events = queue.events();
+    let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            TaskEventKind::Pushed,
+            TaskEventKind::Popped,
+            TaskEventKind::Completed
+        ]
+    );
+    assert_eq!(events[0].id, None);
+    assert_eq!(events[1].id, Some(id));
+    assert_eq!(events[2].id, Some(id));
+    assert!(events.iter().all(|event| *event.task == "a"));
+    assert!(events[0].at <= events[2].at);
+
+    queue.push("b".to_owned());
+    let kinds: Vec<_> = queue.events().iter().map(|event| event.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            TaskEventKind::Popped,
+            TaskEventKind::Completed,
+            TaskEventKind::Pushed
+        ]
+    );
+}
+
+#[test]
+fn event_log_is_disabled_by_default() {
+    let queue = GenericTaskQueue::<String, 60_000>::default();
+    queue.push("a".to_owned());
+    assert!(queue.events().is_empty());
+}
+
+#[tokio::test]
+async fn stale_completion_of_requeued_task_is_rejected() {
+    let queue = GenericTaskQueue::<String, 60_000>::default();
+    queue.push("x".to_owned());
+    let (_, stale_id) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::from_millis(20))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    queue.process_timeouts();
+    let (task, live_id) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(*task, "x");
+
+    assert_eq!(
+        queue.submit_completed(&stale_id),
+        Err(SubmitError::TimedOut)
+    );
+    assert_eq!(queue.len_processing(), 1);
+    assert_eq!(*queue.submit_completed(&live_id).unwrap(), "x");
+    assert_eq!(
+        queue.submit_completed(&stale_id),
+        Err(SubmitError::TimedOut)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_pushes_are_popped_in_order_per_pusher() {
+    const PUSHERS: usize = 4;
+    const PER_PUSHER: usize = 500;
+    let queue = Arc::new(GenericTaskQueue::<(usize, usize), 60_000>::default());
+    let pushers: Vec<_> = (0..PUSHERS)
+        .map(|pusher| {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                for seq in 0..PER_PUSHER {
+                    queue.push((pusher, seq));
+                    if rand::random::<u8>() < 32 {
+                        tokio::task::yield_now().await;
+                    }
+                }
+            })
+        })
+        .collect();
+    let consumers: Vec<_> = (0..PUSHERS)
+        .map(|_| {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                let mut popped = vec![];
+                while let Some((task, _)) = queue.pop_with_timeout(Duration::from_millis(200)).await
+                {
+                    popped.push(*task);
+                }
+                popped
+            })
+        })
+        .collect();
+    for pusher in pushers {
+        pusher.await.unwrap();
+    }
+
+    let mut delivered = 0;
+    for consumer in consumers {
+        let popped = consumer.await.unwrap();
+        delivered += popped.len();
+        // NOTE: pops are FIFO, so every consumer sees each pusher's tasks in increasing order
+        let mut last_seen = [None; PUSHERS];
+        for (pusher, seq) in popped {
+            assert!(last_seen[pusher].is_none_or(|last| last < seq));
+            last_seen[pusher] = Some(seq);
+        }
+    }
+    assert_eq!(delivered, PUSHERS * PER_PUSHER);
+}
+
+#[tokio::test]
+async fn with_execution_timeout_overrides_const_parameter() {
+    let queue = GenericTaskQueue::<String, 60_000>::default()
+        .with_execution_timeout(Duration::from_millis(10));
+    queue.push("a".to_owned());
+    queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    queue.process_timeouts();
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.len_pending(), 1);
+}
+
+#[tokio::test]
+async fn task_pushed_first_is_popped_first_after_restart() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone());
+    queue.push("B-first".to_owned()).await;
+    queue.push("A-second".to_owned()).await;
+    queue.push("C-third".to_owned()).await;
+    // NOTE: a task in flight at the crash goes back to its place, ahead of later pushes
+    let (task, _) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(*task, "B-first");
+    drop(queue);
+
+    let queue = TestQueue::new(db);
+    for expected in ["B-first", "A-second", "C-third"] {
+        let (task, _) = queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+        assert_eq!(*task, expected);
+    }
+}
+
+#[tokio::test]
+async fn attempts_of_a_task_in_flight_survive_restart() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone()).with_max_attempts(NonZeroU32::new(2).unwrap());
+    queue.push("a".to_owned()).await;
+    queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(records(&db)[0].1, stored("a", 1));
+    drop(queue);
+
+    let queue = TestQueue::new(db.clone()).with_max_attempts(NonZeroU32::new(2).unwrap());
+    let (_, id) = queue
+        .pop_with_execution_timeout(Duration::ZERO, Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!(records(&db)[0].1, stored("a", 2));
+    // NOTE: the pop before the restart counts, so this timeout is the last allowed one
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let reclaimed = Mutex::new(vec![]);
+    queue.process_timeouts_with_inspect(|id, _| {
+        reclaimed.lock().unwrap().push(id);
+        TimeoutAction::Requeue
+    });
+    assert_eq!(reclaimed.into_inner().unwrap(), [id]);
+    assert_eq!(queue.len_pending(), 0);
+    assert_eq!(queue.len_dead_letter(), 1);
+}
+
+#[tokio::test]
+async fn records_without_attempts_are_restored_with_zero_attempts() {
+    let db = temporary_db();
+    db.insert(1u64.to_be_bytes(), record(QUEUE_FORMAT_VERSION, "a"))
+        .unwrap();
+    let queue = TestQueue::new(db.clone());
+    assert_eq!(records(&db), [(1, stored("a", 0))]);
+    queue.pop_with_timeout(Duration::ZERO).await.unwrap();
+    assert_eq!(records(&db), [(1, stored("a", 1))]);
+}
+
+#[tokio::test]
+async fn requeued_task_is_delivered_with_next_attempt() {
+    let db = temporary_db();
+    let queue = TestQueue::new(db.clone());
+    queue.push("a".to_owned()).await;
+
+    let (task, id, attempt) = queue
+        .pop_with_attempt(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(*task, "a");
+    assert_eq!(attempt, 1);
+    queue.requeue_processing(&id).unwrap();
+
+    let (task, id, attempt) = queue
+        .pop_with_attempt(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!(*task, "a");
+    assert_eq!(attempt, 2);
+    queue.submit_completed(&id).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn lengths_match_contents_after_mixed_workload() {
+    let queue = Arc::new(
+        GenericTaskQueue::<u32, 30_000>::default().with_max_attempts(NonZeroU32::new(3).unwrap()),
+    );
+    let workers: Vec<_> = (0..4)
+        .map(|worker| {
+            let queue = queue.clone();
+            tokio::spawn(async move {
+                let mut held = vec![];
+                for i in 0..500 {
+                    match i % 5 {
+                        0 | 1 => queue.push(worker * 1_000 + i),
+                        2 => {
+                            if let Some((_, id)) = queue.pop_with_timeout(Duration::ZERO).await {
+                                held.push(id);
+                            }
+                        }
+                        3 => {
+                            if let Some(id) = held.pop() {
+                                queue.submit_completed(&id).unwrap();
+                            }
+                        }
+                        _ => {
+                            if let Some(id) = held.pop() {
+                                queue.fail(&id, TimeoutAction::Requeue).unwrap();
+                            }
+                        }
+                    }
+                }
+                held.len()
+            })
+        })
+        .collect();
+    let mut held = 0;
+    for worker in workers {
+        held += worker.await.unwrap();
+    }
+
+    assert_eq!(queue.len_processing(), held);
+    let pending = queue.len_pending();
+    assert_eq!(queue.drain_pending().len(), pending);
+    // NOTE: counted from the locked processing map
+    assert_eq!(queue.reclaim_all_processing(), held);
+    assert_eq!(queue.len_processing(), 0);
+    assert_eq!(queue.len_pending(), held);
+}
+
+#[derive(Debug, Clone)]
+enum QueueOp {
+    Push,
+    // Pops with a timeout the next `ProcessTimeouts` passes, or one it never reaches
+    Pop { expires: bool },
+    // Completes one of the processing tasks
+    Complete(prop::sample::Index),
+    // Completes a completed or timed out task again, which has to be rejected
+    CompleteStale(prop::sample::Index),
+    // Advances the paused clock past the short timeout, then reclaims
+    ProcessTimeouts,
+}
+
+const SHORT_TIMEOUT: Duration = Duration::from_secs(1);
+// NOTE: 200 ops never advance the clock this far
+const LONG_TIMEOUT: Duration = Duration::from_secs(3600);
+
+fn queue_op() -> impl Strategy<Value = QueueOp> {
+    prop_oneof![
+        3 => Just(QueueOp::Push),
+        3 => any::<bool>().prop_map(|expires| QueueOp::Pop { expires }),
+        2 => any::<prop::sample::Index>().prop_map(QueueOp::Complete),
+        1 => any::<prop::sample::Index>().prop_map(QueueOp::CompleteStale),
+        1 => Just(QueueOp::ProcessTimeouts),
+    ]
+}
+
+type PropQueue = GenericTaskQueue<u32, 30_000>;
+
+// What the queue should hold, tasks are numbered by push
+#[derive(Debug, Default)]
+struct QueueModel {
+    pushed: u32,
+    pending: VecDeque<u32>,
+    // NOTE: in pop order, the order timeouts reclaim them in
+    processing: Vec<(TaskId<u32>, u32, bool)>,
+    // Ids no longer processing, with the error a late completion gets
+    retired: Vec<(TaskId<u32>, SubmitError)>,
+    completed: HashSet<u32>,
+}
+
+impl QueueModel {
+    async fn apply(&mut self, queue: &PropQueue, op: &QueueOp) -> Result<(), TestCaseError> {
+        match op {
+            QueueOp::Push => {
+                queue.push(self.pushed);
+                self.pending.push_back(self.pushed);
+                self.pushed += 1;
+            }
+            QueueOp::Pop { expires } => {
+                let timeout = if *expires {
+                    SHORT_TIMEOUT
+                } else {
+                    LONG_TIMEOUT
+                };
+                // NOTE: equal deadlines reclaim in id order, a tick apart they follow pop order
+                tokio::time::advance(Duration::from_millis(1)).await;
+                let popped = queue
+                    .pop_with_execution_timeout(Duration::ZERO, timeout)
+                    .await;
+                match (popped, self.pending.pop_front()) {
+                    (Some((task, id)), Some(expected)) => {
+                        prop_assert_eq!(*task, expected, "FIFO order");
+                        prop_assert!(!self.completed.contains(&task), "completed task popped");
+                        self.processing.push((id, expected, *expires));
+                    }
+                    (None, None) => {}
+                    (popped, expected) => {
+                        let popped = popped.map(|(task, _)| *task);
+                        prop_assert!(false, "popped {popped:?}, expected {expected:?}");
+                    }
+                }
+            }
+            QueueOp::Complete(index) => {
+                if !self.processing.is_empty() {
+                    self.complete(queue, index.index(self.processing.len()))?;
+                }
+            }
+            QueueOp::CompleteStale(index) => {
+                if self.retired.is_empty() {
+                    return Ok(());
+                }
+                let (id, reason) = self.retired[index.index(self.retired.len())];
+                prop_assert_eq!(queue.submit_completed(&id), Err(reason));
+            }
+            QueueOp::ProcessTimeouts => {
+                tokio::time::advance(SHORT_TIMEOUT * 2).await;
+                queue.process_timeouts();
+                let (expired, kept) = self
+                    .processing
+                    .drain(..)
+                    .partition(|(_, _, expires)| *expires);
+                self.processing = kept;
+                for (id, task, _) in expired {
+                    self.pending.push_back(task);
+                    self.retired.push((id, SubmitError::TimedOut));
+                }
+            }
+        }
+        self.check_len(queue)
+    }
+
+    fn complete(&mut self, queue: &PropQueue, index: usize) -> Result<(), TestCaseError> {
+        let (id, task, _) = self.processing.remove(index);
+        prop_assert_eq!(queue.submit_completed(&id).map(|task| *task), Ok(task));
+        self.completed.insert(task);
+        self.retired.push((id, SubmitError::AlreadyCompleted));
+        self.check_len(queue)
+    }
+
+    fn check_len(&self, queue: &PropQueue) -> Result<(), TestCaseError> {
+        let outstanding = self.pushed as usize - self.completed.len();
+        prop_assert_eq!(queue.len_pending() + queue.len_processing(), outstanding);
+        Ok(())
+    }
+}
+
+#[test]
+fn short_task_ids_are_stable() {
+    let id = TaskId::<u32>::from([0xab; 16]);
+    assert_eq!(id.display_short(), id.display_short());
+    assert_eq!(id.display_short(), "5Dw8mHX2wYZfADG6WaEB0l");
+    assert_eq!(TaskId::<u32>::from([0; 16]).display_short(), "0".repeat(22));
+    assert_eq!(
+        TaskId::<u32>::from([0xff; 16]).display_short(),
+        "7n42DGM5Tflk9n8mt7Fhc7"
+    );
+    assert_eq!(TaskId::<u32>::parse_short("0"), None);
+    assert_eq!(TaskId::<u32>::parse_short(&"-".repeat(22)), None);
+    // NOTE: above u128::MAX
+    assert_eq!(TaskId::<u32>::parse_short(&"z".repeat(22)), None);
+}
+
+proptest! {
+    #[test]
+    fn short_task_ids_round_trip(bytes in any::<[u8; 16]>()) {
+        let id = TaskId::<u32>::from(bytes);
+        let short = id.display_short();
+        prop_assert_eq!(short.len(), 22);
+        prop_assert_eq!(TaskId::parse_short(&short), Some(id));
+    }
+
+    #[test]
+    fn queue_delivers_in_order_and_at_least_once(ops in prop::collection::vec(queue_op(), 1..200)) {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let queue = PropQueue::default();
+            let mut model = QueueModel::default();
+            for op in &ops {
+                model.apply(&queue, op).await?;
+            }
+            // NOTE: every task not completed yet, timed out ones included, is still delivered
+            model.apply(&queue, &QueueOp::ProcessTimeouts).await?;
+            while !model.pending.is_empty() || !model.processing.is_empty() {
+                if model.processing.is_empty() {
+                    model.apply(&queue, &QueueOp::Pop { expires: false }).await?;
+                }
+                model.complete(&queue, 0)?;
+            }
+            prop_assert_eq!(model.completed.len(), model.pushed as usize);
+            Ok(())
+        })?;
+    }
+}
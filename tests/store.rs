@@ -0,0 +1,205 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::NonZeroU32,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use queues_demo::{
+    queue::{GenericTaskQueueWithBackup, TimeoutAction},
+    store::{InMemoryStore, SledStore, StoreWrite, Table, TaskStore},
+};
+
+type Records = BTreeMap<Vec<u8>, Vec<u8>>;
+
+// Custom store, clones share the records like the clones of a sled db
+#[derive(Debug, Clone, Default)]
+struct MapStore {
+    tables: Arc<Mutex<HashMap<Table, Records>>>,
+    ids: Arc<AtomicU64>,
+}
+
+impl TaskStore for MapStore {
+    fn generate_id(&self) -> u64 {
+        self.ids.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn records(&self, table: Table) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let tables = self.tables.lock().unwrap();
+        tables
+            .get(&table)
+            .map(|records| records.clone().into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    fn apply(&self, table: Table, writes: Vec<StoreWrite>) {
+        let mut tables = self.tables.lock().unwrap();
+        let records = tables.entry(table).or_default();
+        for write in writes {
+            match write {
+                StoreWrite::Insert(key, value) => records.insert(key, value),
+                StoreWrite::Remove(key) => records.remove(&key),
+            };
+        }
+    }
+
+    fn update(&self, key: &[u8], update: &mut dyn FnMut(&[u8]) -> Vec<u8>) {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(value) = tables.entry(Table::Tasks).or_default().get_mut(key) {
+            *value = update(value);
+        }
+    }
+
+    fn move_to_dead_letter(&self, key: &[u8]) {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(value) = tables.entry(Table::Tasks).or_default().remove(key) {
+            tables
+                .entry(Table::DeadLetter)
+                .or_default()
+                .insert(key.to_vec(), value);
+        }
+    }
+
+    fn clear(&self, table: Table) {
+        self.tables.lock().unwrap().remove(&table);
+    }
+
+    async fn flush(&self) -> usize {
+        0
+    }
+}
+
+async fn lifecycle_survives_reload<S: TaskStore + Clone>(store: S) {
+    let queue = GenericTaskQueueWithBackup::<String, 30_000, S>::from_store(store.clone())
+        .with_max_attempts(NonZeroU32::new(1).unwrap());
+    queue
+        .push_many(["a", "b", "c", "d"].map(str::to_owned).to_vec())
+        .await;
+    let pop = async || {
+        queue
+            .pop_with_attempt(Duration::from_secs(1))
+            .await
+            .unwrap()
+    };
+    let (task, id, _) = pop().await;
+    assert_eq!(*task, "a");
+    queue.submit_completed(&id).unwrap();
+    let (task, id, _) = pop().await;
+    assert_eq!(*task, "b");
+    assert_eq!(
+        queue.fail(&id, TimeoutAction::Requeue),
+        Ok(TimeoutAction::DeadLetter)
+    );
+    let (task, _, attempt) = pop().await;
+    assert_eq!((task.as_str(), attempt), ("c", 1));
+    drop(queue);
+
+    let queue = GenericTaskQueueWithBackup::<String, 30_000, S>::from_store(store);
+    assert_eq!(queue.len_pending(), 2);
+    assert_eq!(queue.len_dead_letter(), 1);
+    let (task, _, attempt) = queue
+        .pop_with_attempt(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!((task.as_str(), attempt), ("c", 2));
+    let (task, _, attempt) = queue
+        .pop_with_attempt(Duration::from_secs(1))
+        .await
+        .unwrap();
+    assert_eq!((task.as_str(), attempt), ("d", 1));
+    let dead: Vec<String> = queue
+        .take_dead_letter()
+        .into_iter()
+        .map(|task| (*task).clone())
+        .collect();
+    assert_eq!(dead, ["b"]);
+}
+
+#[tokio::test]
+async fn sled_store_keeps_tasks_across_reload() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    lifecycle_survives_reload(SledStore::new(db)).await;
+}
+
+#[tokio::test]
+async fn in_memory_store_keeps_tasks_across_reload() {
+    lifecycle_survives_reload(InMemoryStore::default()).await;
+}
+
+#[tokio::test]
+async fn custom_store_keeps_tasks_across_reload() {
+    lifecycle_survives_reload(MapStore::default()).await;
+}
+
+// Pushes, completes, fails and pops over a fresh queue, then returns both tables
+async fn run_workload<S: TaskStore>(store: S) -> [Vec<(Vec<u8>, Vec<u8>)>; 2] {
+    let queue = GenericTaskQueueWithBackup::<String, 30_000, S>::from_store(store)
+        .with_max_attempts(NonZeroU32::new(2).unwrap());
+    queue.push("a".to_owned()).await;
+    queue
+        .push_many(["b", "c", "d"].map(str::to_owned).to_vec())
+        .await;
+    let pop = async || {
+        queue
+            .pop_with_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+    };
+    let (_, id) = pop().await;
+    queue.submit_completed(&id).unwrap();
+    let (_, id) = pop().await;
+    queue.fail(&id, TimeoutAction::Requeue).unwrap();
+    let (_, id) = pop().await;
+    queue.fail(&id, TimeoutAction::DeadLetter).unwrap();
+    pop().await;
+    queue.flush().await;
+    [Table::Tasks, Table::DeadLetter].map(|table| queue.store().records(table))
+}
+
+#[tokio::test]
+async fn in_memory_store_matches_sled() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let sled = run_workload(SledStore::new(db)).await;
+    let in_memory = run_workload(InMemoryStore::default()).await;
+    // NOTE: keys are ids of different generators, only their order has to match
+    let values = |tables: &[Vec<(Vec<u8>, Vec<u8>)>; 2]| {
+        tables.each_ref().map(|records| {
+            records
+                .iter()
+                .map(|(_, value)| value.clone())
+                .collect::<Vec<_>>()
+        })
+    };
+    assert_eq!(values(&sled), values(&in_memory));
+    assert_eq!(sled[0].len(), 2);
+    assert_eq!(sled[1].len(), 1);
+}
+
+#[tokio::test]
+async fn queue_reloads_from_a_snapshot() {
+    let store = InMemoryStore::default();
+    let queue = GenericTaskQueueWithBackup::<String, 30_000, _>::from_store(store.clone());
+    queue
+        .push_many(["a", "b"].map(str::to_owned).to_vec())
+        .await;
+    let snapshot = store.snapshot();
+    let (_, id) = queue
+        .pop_with_timeout(Duration::from_secs(1))
+        .await
+        .unwrap();
+    queue.submit_completed(&id).unwrap();
+    queue.push("c".to_owned()).await;
+
+    let reloaded = GenericTaskQueueWithBackup::<String, 30_000, _>::from_store(snapshot);
+    let pending: Vec<String> = reloaded
+        .drain_pending()
+        .into_iter()
+        .map(|task| (*task).clone())
+        .collect();
+    assert_eq!(pending, ["a", "b"]);
+    // NOTE: the drain removed the records from the snapshot only
+    assert_eq!(store.records(Table::Tasks).len(), 2);
+}
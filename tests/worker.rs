@@ -0,0 +1,189 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{Json, Router, http::StatusCode, response::IntoResponse, routing::get};
+use queues_demo::{
+    utils::or_shutdown,
+    worker::{Backoff, ProcessedExploits, poll_task, process_exploit},
+};
+use tokio::{sync::watch, time::sleep};
+
+mod common;
+
+use common::serve;
+
+#[tokio::test]
+async fn requested_shutdown_skips_the_poll() {
+    let (tx, mut rx) = watch::channel(false);
+    tx.send(true).unwrap();
+    let res: Option<()> =
+        or_shutdown(&mut rx, async { unreachable!("polled after shutdown") }).await;
+    assert!(res.is_none());
+}
+
+#[tokio::test]
+async fn poll_completes_without_shutdown() {
+    let (tx, mut rx) = watch::channel(false);
+    assert_eq!(or_shutdown(&mut rx, async { 1 }).await, Some(1));
+    drop(tx);
+    assert_eq!(or_shutdown(&mut rx, async { 2 }).await, Some(2));
+}
+
+#[tokio::test]
+async fn loop_finishes_current_task_before_exiting() {
+    let (tx, mut rx) = watch::channel(false);
+    let done = Arc::new(AtomicU32::new(0));
+    let worker = tokio::spawn({
+        let done = done.clone();
+        async move {
+            // Same shape as the worker loop: an interruptible poll, then uninterruptible work
+            while or_shutdown(&mut rx, sleep(Duration::from_millis(10)))
+                .await
+                .is_some()
+            {
+                sleep(Duration::from_millis(100)).await;
+                done.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+
+    sleep(Duration::from_millis(50)).await;
+    tx.send(true).unwrap();
+    assert_eq!(done.load(Ordering::Relaxed), 0);
+    worker.await.unwrap();
+    assert_eq!(done.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn repeated_submission_ids_reuse_the_processed_exploit() {
+    let processed = ProcessedExploits::new(2);
+    let calls = AtomicU32::new(0);
+    let process = |exploit: &str| {
+        calls.fetch_add(1, Ordering::Relaxed);
+        process_exploit(exploit)
+    };
+    assert_eq!(
+        processed.get_or_process("a", "exploit a", process),
+        "exploit a"
+    );
+    assert_eq!(
+        processed.get_or_process("a", "exploit a", process),
+        "exploit a"
+    );
+    assert_eq!(
+        processed.get_or_process("b", "exploit b", process),
+        "exploit b"
+    );
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+    assert_eq!(processed.stats().hits, 1);
+
+    // NOTE: "b" is the least recently used one and makes room for "c"
+    processed.get_or_process("a", "exploit a", process);
+    processed.get_or_process("c", "exploit c", process);
+    processed.get_or_process("b", "exploit b", process);
+    assert_eq!(calls.load(Ordering::Relaxed), 4);
+}
+
+#[test]
+fn zero_entries_process_every_time() {
+    let processed = ProcessedExploits::new(0);
+    let calls = AtomicU32::new(0);
+    for _ in 0..3 {
+        let info = processed.get_or_process("a", "exploit", |exploit| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            process_exploit(exploit)
+        });
+        assert_eq!(info, "exploit");
+    }
+    assert_eq!(calls.load(Ordering::Relaxed), 3);
+}
+
+const BACKOFF: Backoff = Backoff {
+    base: Duration::from_millis(10),
+    max: Duration::from_millis(40),
+};
+
+#[test]
+fn backoff_doubles_up_to_the_cap_with_jitter() {
+    for (attempt, capped) in [(0, 10), (1, 20), (2, 40), (3, 40), (100, 40)] {
+        let capped = Duration::from_millis(capped);
+        let delay = BACKOFF.delay(attempt);
+        assert!(
+            capped / 2 <= delay && delay <= capped,
+            "{attempt}: {delay:?}"
+        );
+    }
+}
+
+// Answers get_task with `failures` server errors before the task, or with `status` throughout
+async fn spawn_flaky_server(failures: u32, status: StatusCode) -> (String, Arc<AtomicU32>) {
+    let polls = Arc::new(AtomicU32::new(0));
+    let app = Router::new().route(
+        "/queue/get_task",
+        get({
+            let polls = polls.clone();
+            async move || {
+                if polls.fetch_add(1, Ordering::Relaxed) < failures {
+                    return status.into_response();
+                }
+                Json(serde_json::json!({
+                    "id": "00".repeat(16),
+                    "submission_id": "a",
+                    "exploit_key": "a",
+                    "priority": 0,
+                    "request_id": "trace",
+                }))
+                .into_response()
+            }
+        }),
+    );
+    let url = serve(app).await;
+    (url, polls)
+}
+
+#[tokio::test]
+async fn poll_retries_server_errors_with_backoff() {
+    let (url, polls) = spawn_flaky_server(3, StatusCode::SERVICE_UNAVAILABLE).await;
+    let client = reqwest::Client::new();
+    let started = Instant::now();
+    let task = poll_task(&client, &url, Duration::from_secs(5), BACKOFF)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(task.submission_id, "a");
+    assert_eq!(polls.load(Ordering::Relaxed), 4);
+    // NOTE: at least half of 10, 20 and 40 ms
+    assert!(started.elapsed() >= Duration::from_millis(35));
+}
+
+#[tokio::test]
+async fn poll_fails_on_client_errors_right_away() {
+    let (url, polls) = spawn_flaky_server(u32::MAX, StatusCode::BAD_REQUEST).await;
+    let client = reqwest::Client::new();
+    let res = poll_task(&client, &url, Duration::from_secs(5), BACKOFF).await;
+    assert!(res.is_err());
+    assert_eq!(polls.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn poll_waits_for_the_server_to_come_back() {
+    // NOTE: nothing listens on the port until the server starts
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(100)).await;
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let app = Router::new().route("/queue/get_task", get(StatusCode::NO_CONTENT));
+        axum::serve(listener, app).await.unwrap();
+    });
+    let client = reqwest::Client::new();
+    let url = format!("http://{addr}");
+    let res = poll_task(&client, &url, Duration::from_secs(5), BACKOFF).await;
+    assert!(res.unwrap().is_none());
+}